@@ -0,0 +1,139 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::product::{OutputAvailability, ProductId};
+
+/// Published fields an alert threshold can watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertField {
+    Free,
+    Buildable,
+}
+
+impl AlertField {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Free => "free_immediately",
+            Self::Buildable => "buildable",
+        }
+    }
+
+    fn value(self, availability: &OutputAvailability) -> Decimal {
+        match self {
+            Self::Free => availability.free_immediately,
+            Self::Buildable => availability.buildable,
+        }
+    }
+}
+
+/// Which way a value crossed its threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From at or above the threshold to below it
+    Below,
+    /// From below the threshold back to at or above it
+    Above,
+}
+
+/// A product whose published value crossed a threshold since the previous run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub product_id: i32,
+    pub field: &'static str,
+    pub direction: Direction,
+    pub threshold: Decimal,
+    pub previous: Decimal,
+    pub current: Decimal,
+}
+
+/// The body POSTed to `--alert-webhook`: the alerts of one warehouse or location.
+#[derive(Debug, Serialize)]
+pub struct AlertPayload<'a> {
+    pub run_id: &'a str,
+    pub warehouse_id: i32,
+    pub warehouse_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location_id: Option<i32>,
+    pub alerts: &'a [Alert],
+}
+
+/// The thresholds crossing which raises an alert.
+#[derive(Debug, Clone, Default)]
+pub struct AlertThresholds(pub Vec<(AlertField, Decimal)>);
+
+impl AlertThresholds {
+    /// Alerts for every threshold crossed between the `previous` and `current` row of `product`.
+    pub fn crossings(
+        &self,
+        product: ProductId,
+        previous: &OutputAvailability,
+        current: &OutputAvailability,
+    ) -> Vec<Alert> {
+        self.0
+            .iter()
+            .filter_map(|(field, threshold)| {
+                let (previous, current) = (field.value(previous), field.value(current));
+                let direction = match (previous < *threshold, current < *threshold) {
+                    (false, true) => Direction::Below,
+                    (true, false) => Direction::Above,
+                    _ => return None,
+                };
+                Some(Alert {
+                    product_id: product.0,
+                    field: field.as_str(),
+                    direction,
+                    threshold: *threshold,
+                    previous,
+                    current,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::{AlertField, AlertThresholds, Direction};
+    use crate::product::{OutputAvailability, ProductId};
+
+    fn availability(free: i64, buildable: i64) -> OutputAvailability {
+        OutputAvailability {
+            quantity: Decimal::from(free),
+            reserved: Decimal::ZERO,
+            incoming: Decimal::ZERO,
+            outgoing: Decimal::ZERO,
+            buildable: Decimal::from(buildable),
+            free_immediately: Decimal::from(free),
+            virtual_available: Decimal::from(free),
+            net_on_order: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn alerts_only_when_a_threshold_is_crossed() {
+        let thresholds = AlertThresholds(vec![
+            (AlertField::Free, Decimal::ONE),
+            (AlertField::Buildable, Decimal::from(10)),
+        ]);
+
+        let alerts = thresholds.crossings(ProductId(7), &availability(3, 12), &availability(0, 11));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].field, "free_immediately");
+        assert_eq!(alerts[0].direction, Direction::Below);
+        assert_eq!(alerts[0].current, Decimal::ZERO);
+
+        let alerts = thresholds.crossings(ProductId(7), &availability(0, 9), &availability(1, 4));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].field, "free_immediately");
+        assert_eq!(alerts[0].direction, Direction::Above);
+
+        assert!(
+            thresholds
+                .crossings(ProductId(7), &availability(5, 9), &availability(2, 3))
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use serde::Serialize;
+
+/// An anomaly looked for by `audit` in the tables this tool reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditCheck {
+    /// quants with a negative quantity in the warehouse
+    NegativeQuant,
+    /// quants reserving more than their (non-negative) quantity in the warehouse
+    ReservedAboveQuantity,
+    /// open moves whose source or destination location does not exist
+    MoveMissingLocation,
+    /// active storable products whose unit of measure is missing or has no positive rounding
+    MissingUomRounding,
+    /// locations holding quants without a `parent_path`, invisible to every warehouse
+    LocationWithoutParentPath,
+    /// active BoMs whose unit of measure is in another category than the product's
+    BomUomCategory,
+    /// lines of active BoMs whose unit of measure is in another category than the component's
+    BomLineUomCategory,
+}
+
+impl AuditCheck {
+    pub const ALL: [Self; 7] = [
+        Self::NegativeQuant,
+        Self::ReservedAboveQuantity,
+        Self::MoveMissingLocation,
+        Self::MissingUomRounding,
+        Self::LocationWithoutParentPath,
+        Self::BomUomCategory,
+        Self::BomLineUomCategory,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NegativeQuant => "negative_quant",
+            Self::ReservedAboveQuantity => "reserved_above_quantity",
+            Self::MoveMissingLocation => "move_missing_location",
+            Self::MissingUomRounding => "missing_uom_rounding",
+            Self::LocationWithoutParentPath => "location_without_parent_path",
+            Self::BomUomCategory => "bom_uom_category",
+            Self::BomLineUomCategory => "bom_line_uom_category",
+        }
+    }
+
+    /// The Odoo model of the records reported by this check.
+    pub fn model(self) -> &'static str {
+        match self {
+            Self::NegativeQuant | Self::ReservedAboveQuantity => "stock.quant",
+            Self::MoveMissingLocation => "stock.move",
+            Self::MissingUomRounding => "product.product",
+            Self::LocationWithoutParentPath => "stock.location",
+            Self::BomUomCategory => "mrp.bom",
+            Self::BomLineUomCategory => "mrp.bom.line",
+        }
+    }
+}
+
+/// One offending record, emitted by `audit` as a JSON line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Anomaly {
+    pub check: &'static str,
+    pub model: &'static str,
+    pub record_id: i32,
+    pub product_id: Option<i32>,
+    pub location_id: Option<i32>,
+
+    /// the offending values, e.g. `quantity=-2`
+    pub detail: String,
+}
+
+impl Anomaly {
+    pub fn new(
+        check: AuditCheck,
+        (record_id, product_id, location_id, detail): (i32, Option<i32>, Option<i32>, String),
+    ) -> Self {
+        Self {
+            check: check.as_str(),
+            model: check.model(),
+            record_id,
+            product_id,
+            location_id,
+            detail,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Anomaly, AuditCheck};
+
+    #[test]
+    fn anomalies_serialize_with_their_check_and_model() {
+        let anomaly = Anomaly::new(
+            AuditCheck::NegativeQuant,
+            (12, Some(3), Some(8), "quantity=-2".to_string()),
+        );
+
+        assert_eq!(
+            serde_json::to_string(&anomaly).expect("anomaly must serialize"),
+            r#"{"check":"negative_quant","model":"stock.quant","record_id":12,"product_id":3,"location_id":8,"detail":"quantity=-2"}"#
+        );
+    }
+}
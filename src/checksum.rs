@@ -0,0 +1,109 @@
+use sqlx::PgPool;
+
+/// Tables whose rows availability is computed from. The optional sources (sale and purchase
+/// orders, valuation layers, the dropship exclusion's operation types and routes) are added by
+/// the flags reading them.
+const TABLES: &[&str] = &[
+    "decimal_precision",
+    "mrp_bom",
+    "mrp_bom_line",
+    "mrp_bom_line_product_template_attribute_value_rel",
+    "product_product",
+    "product_template",
+    "product_template_attribute_value",
+    "product_variant_combination",
+    "stock_location",
+    "stock_move",
+    "stock_move_line",
+    "stock_quant",
+    "stock_warehouse",
+    "uom_uom",
+];
+
+/// Identify the state of the source tables read by a run from their data: the row count of each
+/// and, for the Odoo models, the latest `write_date`. Unlike the statistics counters this is read
+/// from the tables themselves, so it holds on a hot standby and survives statistics resets.
+pub async fn source_fingerprint(
+    pool: &PgPool,
+    extra_tables: &[&str],
+) -> Result<String, sqlx::Error> {
+    let tables: Vec<&str> = TABLES.iter().chain(extra_tables).copied().collect();
+    // Optional modules' tables may be missing, and relation tables have no write_date
+    let existing: Vec<(String, bool)> = sqlx::query_as(
+        "
+        SELECT
+            pg_class.relname::text,
+            EXISTS (
+                SELECT 1 FROM pg_attribute
+                WHERE pg_attribute.attrelid = pg_class.oid
+                AND pg_attribute.attname = 'write_date'
+                AND NOT pg_attribute.attisdropped
+            )
+        FROM unnest($1::text[]) AS name
+        INNER JOIN pg_class ON pg_class.oid = to_regclass(name)
+        ORDER BY pg_class.relname
+    ",
+    )
+    .bind(tables)
+    .fetch_all(pool)
+    .await?;
+
+    let mut states = Vec::with_capacity(existing.len());
+    for (table, has_write_date) in existing {
+        let latest = if has_write_date {
+            "max(write_date)::text"
+        } else {
+            "NULL::text"
+        };
+        let (rows, latest): (i64, Option<String>) =
+            sqlx::query_as(&format!("SELECT count(*), {latest} FROM \"{table}\""))
+                .fetch_one(pool)
+                .await?;
+        states.push(match latest {
+            Some(latest) => format!("{table}={rows}@{latest}"),
+            None => format!("{table}={rows}"),
+        });
+    }
+
+    Ok(states.join(","))
+}
+
+/// Checksum of the inputs of one stock scope's rows: the source database and the state of its
+/// tables, the scope and the options affecting the rows, as 16 hex digits.
+pub fn input_checksum(source: &str, scope: &str, options: &str) -> String {
+    // 64-bit FNV-1a, stable across builds unlike the std hasher
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in [source, scope, options] {
+        for byte in part.bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::input_checksum;
+
+    #[test]
+    fn checksums_change_with_every_input() {
+        let checksum = input_checksum("odoo@db:5432|stock_quant=3", "warehouse/1", "--stdout");
+        assert_eq!(checksum.len(), 16);
+        assert_eq!(
+            checksum,
+            input_checksum("odoo@db:5432|stock_quant=3", "warehouse/1", "--stdout")
+        );
+        assert_ne!(
+            checksum,
+            input_checksum("odoo@db:5432|stock_quant=4", "warehouse/1", "--stdout")
+        );
+        assert_ne!(
+            checksum,
+            input_checksum("odoo@db:5432|stock_quant=3", "warehouse/2", "--stdout")
+        );
+        // Parts are separated, so moving bytes between them changes the checksum
+        assert_ne!(input_checksum("a", "bc", ""), input_checksum("ab", "c", ""));
+        assert_eq!(input_checksum("", "", ""), "d94d12186c0f2fb7");
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, fmt};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::product::{OutputAvailability, ProductId};
+
+/// A product field whose value differs between the two databases by more than the tolerance.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Difference {
+    pub product_id: i32,
+    /// `None` when the product is only computed on one side
+    pub field: Option<&'static str>,
+    pub left: Option<Decimal>,
+    pub right: Option<Decimal>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.field, self.left, self.right) {
+            (Some(field), Some(left), Some(right)) => write!(
+                f,
+                "Product {}: {field} {left} -> {right} ({:+})",
+                self.product_id,
+                right - left
+            ),
+            (_, _, None) => write!(f, "Product {}: only in left", self.product_id),
+            (_, None, _) => write!(f, "Product {}: only in right", self.product_id),
+            (None, Some(_), Some(_)) => write!(f, "Product {}: differs", self.product_id),
+        }
+    }
+}
+
+/// How far apart two values may be and still count as equal: up to `absolute`, or up to
+/// `percent` of the larger magnitude, so UoM rounding noise is not reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Tolerance {
+    pub absolute: Decimal,
+    pub percent: Option<Decimal>,
+}
+
+impl Tolerance {
+    /// Whether `left` and `right` differ by more than both tolerances.
+    pub fn exceeded(&self, left: Decimal, right: Decimal) -> bool {
+        let difference = (right - left).abs();
+        difference > self.absolute
+            && self.percent.is_none_or(|percent| {
+                difference * Decimal::ONE_HUNDRED > percent * left.abs().max(right.abs())
+            })
+    }
+}
+
+impl fmt::Display for Tolerance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.percent {
+            Some(percent) => write!(f, "{} or {percent}%", self.absolute),
+            None => write!(f, "{}", self.absolute),
+        }
+    }
+}
+
+/// Per-product differences between two runs, in product order. Fields are reported when they
+/// exceed `tolerance`; products computed on one side only are always reported.
+pub fn differences(
+    left: &BTreeMap<ProductId, OutputAvailability>,
+    right: &BTreeMap<ProductId, OutputAvailability>,
+    tolerance: Tolerance,
+) -> Vec<Difference> {
+    let mut products: Vec<ProductId> = left.keys().chain(right.keys()).copied().collect();
+    products.sort();
+    products.dedup();
+
+    let mut differences = Vec::new();
+    for product in products {
+        match (left.get(&product), right.get(&product)) {
+            (Some(left), Some(right)) => {
+                for ((field, left), (_, right)) in left.fields().into_iter().zip(right.fields()) {
+                    if tolerance.exceeded(left, right) {
+                        differences.push(Difference {
+                            product_id: product.0,
+                            field: Some(field),
+                            left: Some(left),
+                            right: Some(right),
+                        });
+                    }
+                }
+            }
+            (left, right) => differences.push(Difference {
+                product_id: product.0,
+                field: None,
+                left: left.map(|availability| availability.free_immediately),
+                right: right.map(|availability| availability.free_immediately),
+            }),
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rust_decimal::Decimal;
+
+    use super::{Difference, Tolerance, differences};
+    use crate::product::{OutputAvailability, ProductId};
+
+    fn availability(quantity: i64, reserved: i64) -> OutputAvailability {
+        OutputAvailability {
+            quantity: Decimal::from(quantity),
+            reserved: Decimal::from(reserved),
+            incoming: Decimal::ZERO,
+            outgoing: Decimal::ZERO,
+            buildable: Decimal::ZERO,
+            free_immediately: Decimal::from(quantity - reserved),
+            virtual_available: Decimal::from(quantity),
+            net_on_order: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn reports_fields_beyond_tolerance_and_one_sided_products() {
+        let left = BTreeMap::from([
+            (ProductId(1), availability(10, 2)),
+            (ProductId(2), availability(5, 0)),
+            (ProductId(3), availability(7, 0)),
+        ]);
+        let right = BTreeMap::from([
+            (ProductId(1), availability(10, 2)),
+            (ProductId(2), availability(8, 0)),
+            (ProductId(4), availability(1, 0)),
+        ]);
+
+        let absolute = |absolute: i64| Tolerance {
+            absolute: Decimal::from(absolute),
+            percent: None,
+        };
+        let found = differences(&left, &right, absolute(1));
+        let fields: Vec<(i32, Option<&str>)> = found
+            .iter()
+            .map(|difference| (difference.product_id, difference.field))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (2, Some("free")),
+                (2, Some("quantity")),
+                (2, Some("virtual_available")),
+                (3, None),
+                (4, None),
+            ]
+        );
+        assert_eq!(found[0].to_string(), "Product 2: free 5 -> 8 (+3)");
+        assert_eq!(found[3].to_string(), "Product 3: only in left");
+        assert_eq!(
+            found[4],
+            Difference {
+                product_id: 4,
+                field: None,
+                left: None,
+                right: Some(Decimal::ONE),
+            }
+        );
+
+        assert!(differences(&left, &left, absolute(0)).is_empty());
+        assert_eq!(differences(&left, &right, absolute(3)).len(), 2);
+    }
+
+    #[test]
+    fn percent_tolerance_scales_with_the_larger_value() {
+        let tolerance = Tolerance {
+            absolute: Decimal::ZERO,
+            percent: Some(Decimal::new(1, 1)),
+        };
+        // 0.1% of 1000 is 1
+        assert!(!tolerance.exceeded(Decimal::from(1000), Decimal::from(999)));
+        assert!(tolerance.exceeded(Decimal::from(1000), Decimal::new(9989, 1)));
+        assert!(tolerance.exceeded(Decimal::ZERO, Decimal::new(1, 3)));
+        assert_eq!(tolerance.to_string(), "0 or 0.1%");
+
+        // Either tolerance is enough to ignore a difference
+        let tolerance = Tolerance {
+            absolute: Decimal::new(1, 2),
+            ..tolerance
+        };
+        assert!(!tolerance.exceeded(Decimal::ZERO, Decimal::new(1, 2)));
+        assert!(!tolerance.exceeded(Decimal::from(1000), Decimal::new(9995, 1)));
+        assert!(tolerance.exceeded(Decimal::from(10), Decimal::new(1002, 2)));
+    }
+}
@@ -1,13 +1,24 @@
-use std::{collections::HashMap, error::Error, fmt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use futures::future::BoxFuture;
 use petgraph::graphmap::DiGraphMap;
 use rust_decimal::Decimal;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 
 use crate::{
+    audit::{Anomaly, AuditCheck},
+    locations::Location,
     odoo::OdooVersion,
-    product::{Product, ProductId, Quant},
+    product::{
+        AlternativeBom, Dimensions, IncomingBreakdown, MoveDetail, Product, ProductId, Quant,
+        ReservedBreakdown,
+    },
     warehouse::Warehouse,
 };
 
@@ -21,38 +32,618 @@ pub fn dp_from_rounding(rounding: Decimal) -> u32 {
     }
 }
 
+/// Whether a source error is worth retrying after reconnecting: lost connections, server
+/// shutdowns/restarts and replica recovery conflicts.
+pub fn is_transient(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(db_err) => db_err.code().is_some_and(|code| {
+            code.starts_with("08") || matches!(code.as_ref(), "57P01" | "57P02" | "57P03" | "40001")
+        }),
+        _ => false,
+    }
+}
+
+/// `stock.move` states an adapter may count towards incoming/outgoing.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum MoveState {
+    Draft,
+    Waiting,
+    Confirmed,
+    PartiallyAvailable,
+    Assigned,
+}
+
+impl MoveState {
+    /// States counted when `--move-states` is not given.
+    pub const DEFAULT: [Self; 4] = [
+        Self::Waiting,
+        Self::Confirmed,
+        Self::Assigned,
+        Self::PartiallyAvailable,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Draft => "draft",
+            Self::Waiting => "waiting",
+            Self::Confirmed => "confirmed",
+            Self::PartiallyAvailable => "partially_available",
+            Self::Assigned => "assigned",
+        }
+    }
+}
+
+impl std::str::FromStr for MoveState {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.trim() {
+            "draft" => Ok(Self::Draft),
+            "waiting" => Ok(Self::Waiting),
+            "confirmed" => Ok(Self::Confirmed),
+            "partially_available" => Ok(Self::PartiallyAvailable),
+            "assigned" => Ok(Self::Assigned),
+            other => Err(format!(
+                "unknown move state '{other}' (expected draft, waiting, confirmed, partially_available or assigned)"
+            )),
+        }
+    }
+}
+
+/// A `Attribute=Value` variant filter, matched against attribute and value names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeValue {
+    pub attribute: String,
+    pub value: String,
+}
+
+impl AttributeValue {
+    /// Group filters by attribute: values of the same attribute are alternatives, while every
+    /// attribute must match.
+    pub fn grouped(filters: &[Self]) -> BTreeMap<&str, Vec<&str>> {
+        let mut grouped: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for filter in filters {
+            grouped
+                .entry(filter.attribute.as_str())
+                .or_default()
+                .push(filter.value.as_str());
+        }
+        grouped
+    }
+}
+
+impl std::str::FromStr for AttributeValue {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (attribute, value) = input.split_once('=').ok_or_else(|| {
+            format!("invalid attribute value '{input}' (expected ATTRIBUTE=VALUE)")
+        })?;
+        let (attribute, value) = (attribute.trim(), value.trim());
+        if attribute.is_empty() || value.is_empty() {
+            return Err(format!(
+                "invalid attribute value '{input}' (expected ATTRIBUTE=VALUE)"
+            ));
+        }
+
+        Ok(Self {
+            attribute: attribute.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// A user-supplied SQL predicate over `product_product` and `product_template`, selecting the
+/// products to compute. It is spliced into the query, so only a single expression is accepted:
+/// no statement separators, comments or dollar quoting, with balanced quotes and parentheses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProductWhere(pub String);
+
+impl std::str::FromStr for ProductWhere {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| Err(format!("invalid --product-where '{input}': {reason}"));
+        if input.trim().is_empty() {
+            return invalid("empty predicate");
+        }
+
+        let mut depth = 0usize;
+        let mut quote = None;
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            if let Some(open) = quote {
+                if c == open {
+                    // A doubled quote is an escaped one
+                    if chars.peek() == Some(&open) {
+                        let _ = chars.next();
+                    } else {
+                        quote = None;
+                    }
+                }
+                continue;
+            }
+            match c {
+                '\'' | '"' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => match depth.checked_sub(1) {
+                    Some(outer) => depth = outer,
+                    None => return invalid("unbalanced parentheses"),
+                },
+                ';' => return invalid("statement separators are not allowed"),
+                '$' => return invalid("dollar quoting and parameters are not allowed"),
+                '-' if chars.peek() == Some(&'-') => return invalid("comments are not allowed"),
+                '/' if chars.peek() == Some(&'*') => return invalid("comments are not allowed"),
+                _ => {}
+            }
+        }
+        if quote.is_some() {
+            return invalid("unterminated quote");
+        }
+        if depth != 0 {
+            return invalid("unbalanced parentheses");
+        }
+
+        Ok(Self(input.to_string()))
+    }
+}
+
+/// Locations whose stock is held back (quarantine, quality hold, ...) and so neither on hand nor
+/// free. A location matches on its usage, its full name (SQL `ILIKE` pattern) or its id; children
+/// of a matched id match too.
+#[derive(Debug, Clone, Default)]
+pub struct HoldLocations {
+    pub usages: Vec<String>,
+    pub name_patterns: Vec<String>,
+    pub ids: Vec<i32>,
+}
+
+impl HoldLocations {
+    pub fn is_empty(&self) -> bool {
+        self.usages.is_empty() && self.name_patterns.is_empty() && self.ids.is_empty()
+    }
+}
+
+/// A line of an active BoM that is left out of the product graph because its component is
+/// archived or not storable, making the kit compute as if the component were not needed.
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct DroppedBomLine {
+    pub bom_id: i32,
+    pub product_id: ProductId,
+
+    /// `[default_code] name` of the kit
+    pub product: String,
+    pub component_id: ProductId,
+
+    /// `[default_code] name` of the component
+    pub component: String,
+    pub component_active: bool,
+
+    /// product type of the component (`product` for storable)
+    pub component_type: String,
+}
+
+impl fmt::Display for DroppedBomLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BoM {} of Product {} {}: component Product {} {} is ",
+            self.bom_id, self.product_id.0, self.product, self.component_id.0, self.component
+        )?;
+        let storable = self.component_type == "product";
+        match (self.component_active, storable) {
+            (false, false) => write!(f, "archived and not storable ({})", self.component_type),
+            (false, true) => f.write_str("archived"),
+            (true, _) => write!(f, "not storable ({})", self.component_type),
+        }
+    }
+}
+
+/// Knobs shared by every adapter's quant and move aggregation.
+#[derive(Debug, Clone)]
+pub struct QuantOptions {
+    /// move states counted towards incoming/outgoing
+    pub move_states: Vec<MoveState>,
+
+    /// count every open move arriving from an inter-company transit location as incoming,
+    /// whatever its state
+    pub include_transit: bool,
+
+    /// count dropship moves (of a dropship operation type or route) towards incoming/outgoing
+    pub include_dropship: bool,
+
+    /// stock in these locations is left out of the on-hand and reserved quantities
+    pub hold_locations: HoldLocations,
+
+    /// mirror the domains of Odoo's forecast (`_compute_quantities`): moves within the
+    /// warehouse are neither incoming nor outgoing
+    pub odoo_forecast: bool,
+
+    /// only count quants in, and moves into or out of, locations of these usages; any usage
+    /// when empty
+    pub location_usages: Vec<String>,
+
+    /// count negative quants as zero instead of subtracting them from the on-hand quantity
+    pub ignore_negative_quants: bool,
+}
+
+impl QuantOptions {
+    pub fn move_state_names(&self) -> Vec<&'static str> {
+        self.move_states
+            .iter()
+            .map(|state| state.as_str())
+            .collect()
+    }
+}
+
+impl Default for QuantOptions {
+    fn default() -> Self {
+        Self {
+            move_states: MoveState::DEFAULT.to_vec(),
+            include_transit: false,
+            include_dropship: false,
+            hold_locations: HoldLocations::default(),
+            odoo_forecast: false,
+            location_usages: Vec::new(),
+            ignore_negative_quants: false,
+        }
+    }
+}
+
 #[async_trait]
 pub trait OdooAdapter: Send + Sync {
     fn major(&self) -> OdooVersion;
 
+    /// Products and their BoM classification, with the BoM each manufactured product is computed
+    /// from in `first_boms`. With `company_id`, only BoMs shared between companies or owned by
+    /// that company are considered.
     async fn products(
         &self,
-        pool: &PgPool,
+        conn: &mut PgConnection,
+        company_id: Option<i32>,
         catalogue: &mut HashMap<ProductId, Product>,
+        first_boms: &mut HashMap<ProductId, i32>,
         graph: &mut DiGraphMap<ProductId, Decimal>,
     ) -> Result<(), sqlx::Error>;
 
+    /// BoM and commingled edges, scoped to `company_id` like [`OdooAdapter::products`]. Edges
+    /// come from the BoM of each product in `first_boms`; with `alternatives`, the others are
+    /// collected there, their components linked by edges of quantity zero. A BoM with a line in a
+    /// UoM that cannot be converted to its component's is left out whole: the first BoM of a
+    /// product is then recorded in `invalid_boms` without edges, the others are dropped.
     async fn relations(
         &self,
-        pool: &PgPool,
+        conn: &mut PgConnection,
+        company_id: Option<i32>,
         graph: &mut DiGraphMap<ProductId, Decimal>,
+        first_boms: &HashMap<ProductId, i32>,
+        alternatives: Option<&mut HashMap<ProductId, Vec<AlternativeBom>>>,
+        invalid_boms: &mut HashMap<ProductId, i32>,
     ) -> Result<(), sqlx::Error>;
 
     async fn quants(
         &self,
-        pool: &PgPool,
+        conn: &mut PgConnection,
         warehouse_location_path: &str,
         scoped_products: Option<&[i32]>,
         decimal_precision: u32,
+        options: &QuantOptions,
         raw_quants: &mut HashMap<ProductId, Quant>,
     ) -> Result<(), sqlx::Error>;
 
-    async fn warehouse(&self, pool: &PgPool, id: i32) -> Result<Warehouse, sqlx::Error>;
+    /// Average unit cost per product from the stock valuation layers, when the valuation tables
+    /// exist. With `company_id`, only that company's layers are averaged.
+    async fn unit_costs(
+        &self,
+        conn: &mut PgConnection,
+        company_id: Option<i32>,
+        scoped_products: Option<&[i32]>,
+        unit_costs: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Unit weight and volume per product.
+    async fn dimensions(
+        &self,
+        conn: &mut PgConnection,
+        scoped_products: Option<&[i32]>,
+        dimensions: &mut HashMap<ProductId, Dimensions>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Quantity per product moved out of the warehouse by done moves within the lookback window.
+    async fn outbound_history(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        window: Duration,
+        scoped_products: Option<&[i32]>,
+        outbound: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// On-hand quantity per product in the warehouse's hold locations.
+    async fn held_quants(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        hold_locations: &HoldLocations,
+        scoped_products: Option<&[i32]>,
+        held: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Free quantity (on hand less reserved, outside hold locations) per product in the
+    /// warehouses configured to resupply `warehouse_id`, and the longest delay in days of the
+    /// rules on its resupply routes, if any.
+    async fn resupply(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_id: i32,
+        hold_locations: &HoldLocations,
+        scoped_products: Option<&[i32]>,
+        resuppliable: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<Option<i32>, sqlx::Error>;
+
+    /// Free quantity (on hand less reserved, outside hold locations) per product under the stock
+    /// locations of `warehouse_ids`, summed across them.
+    async fn warehouses_free(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_ids: &[i32],
+        hold_locations: &HoldLocations,
+        scoped_products: Option<&[i32]>,
+        free: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Quantity per manufactured product the work centers of the operations of its BoM in
+    /// `first_boms` can make over `horizon`, the least of them when there are several. Products
+    /// without timed operations are left out.
+    async fn work_center_capacity(
+        &self,
+        conn: &mut PgConnection,
+        first_boms: &HashMap<ProductId, i32>,
+        horizon: Duration,
+        scoped_products: Option<&[i32]>,
+        capacity: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Quantity per product reserved in the warehouse (outside hold locations, in locations of
+    /// the counted usages), split by the operation type of the move it is reserved for.
+    async fn reserved_breakdown(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        options: &QuantOptions,
+        scoped_products: Option<&[i32]>,
+        breakdown: &mut HashMap<ProductId, ReservedBreakdown>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Whole days per product since its oldest open reservation in the warehouse (outside hold
+    /// locations, in locations of the counted usages) was made. Products without open
+    /// reservations are left out.
+    async fn oldest_reservations(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        options: &QuantOptions,
+        scoped_products: Option<&[i32]>,
+        oldest: &mut HashMap<ProductId, i32>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// The moves counted as `incoming`, split by the operation type bringing them in.
+    async fn incoming_breakdown(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        scoped_products: Option<&[i32]>,
+        options: &QuantOptions,
+        breakdown: &mut HashMap<ProductId, IncomingBreakdown>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// The individual moves counted as `incoming` and `outgoing` per product, oldest first.
+    async fn open_moves(
+        &self,
+        pool: &PgPool,
+        warehouse_location_path: &str,
+        products: &[ProductId],
+        options: &QuantOptions,
+    ) -> Result<HashMap<ProductId, Vec<MoveDetail>>, sqlx::Error>;
+
+    /// Quantity per product on draft/sent sale orders of the warehouse, in the product's UoM.
+    async fn quotations(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_id: i32,
+        scoped_products: Option<&[i32]>,
+        quoted: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Quantity per product on draft/sent purchase orders delivering to the warehouse, in the
+    /// product's UoM.
+    async fn draft_purchases(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_id: i32,
+        scoped_products: Option<&[i32]>,
+        unconfirmed: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// The warehouse and its stock location path. `lang` selects the translation used for
+    /// translated (jsonb) names from Odoo 16 onwards; earlier adapters store plain names and
+    /// ignore it.
+    async fn warehouse(
+        &self,
+        pool: &PgPool,
+        id: i32,
+        lang: Option<&str>,
+    ) -> Result<Warehouse, sqlx::Error>;
+
+    /// A location of a warehouse as a scope: its own path, with its warehouse's id, name and
+    /// company. `lang` is handled as in `warehouse`.
+    async fn location_scope(
+        &self,
+        pool: &PgPool,
+        location_id: i32,
+        lang: Option<&str>,
+    ) -> Result<Warehouse, sqlx::Error>;
+
+    /// Full category name (`All / Saleable / ...`) per product.
+    async fn product_categories(
+        &self,
+        pool: &PgPool,
+        products: &[ProductId],
+    ) -> Result<HashMap<ProductId, String>, sqlx::Error>;
+
+    /// Display name (`[default_code] name`) per product. `lang` is handled as in `warehouse`.
+    async fn product_references(
+        &self,
+        pool: &PgPool,
+        products: &[ProductId],
+        lang: Option<&str>,
+    ) -> Result<HashMap<ProductId, String>, sqlx::Error>;
+
+    /// Lines of the BoMs `relations` reads whose component is archived or not storable, and so
+    /// left out of the graph. `company_id` scopes BoMs as in `relations`.
+    async fn dropped_bom_lines(
+        &self,
+        pool: &PgPool,
+        company_id: Option<i32>,
+    ) -> Result<Vec<DroppedBomLine>, sqlx::Error>;
+
+    /// Active locations under the warehouse's stock location with their quant totals, parents
+    /// first.
+    async fn locations(
+        &self,
+        pool: &PgPool,
+        warehouse_location_path: &str,
+    ) -> Result<Vec<Location>, sqlx::Error>;
+
+    /// Records failing an `audit` check; quant checks are limited to the warehouse.
+    async fn audit(
+        &self,
+        pool: &PgPool,
+        check: AuditCheck,
+        warehouse_location_path: &str,
+    ) -> Result<Vec<Anomaly>, sqlx::Error>;
+
+    /// Active variants matching a `--product-where` predicate, evaluated in a read-only
+    /// transaction.
+    async fn products_where(
+        &self,
+        pool: &PgPool,
+        predicate: &ProductWhere,
+    ) -> Result<Vec<ProductId>, sqlx::Error>;
+
+    /// Active variants carrying every filtered attribute with one of its filtered values.
+    async fn products_with_attribute_values(
+        &self,
+        pool: &PgPool,
+        filters: &[AttributeValue],
+    ) -> Result<Vec<ProductId>, sqlx::Error>;
+}
+
+/// Builds an adapter for a source database, e.g. by probing which optional tables exist.
+pub type AdapterFactory =
+    for<'a> fn(&'a PgPool) -> BoxFuture<'a, Result<Box<dyn OdooAdapter>, BuildAdapterError>>;
+
+#[derive(Debug, Clone)]
+struct RegisteredAdapter {
+    name: String,
+    majors: Vec<OdooVersion>,
+    factory: AdapterFactory,
+}
+
+/// The adapters a run can pick from: by the detected Odoo major, or by name with `--dialect`.
+/// Forks storing stock in renamed tables can register their own `OdooAdapter` next to the
+/// built-in ones; later registrations win for the majors they share.
+#[derive(Debug, Clone)]
+pub struct AdapterRegistry {
+    adapters: Vec<RegisteredAdapter>,
+}
+
+impl AdapterRegistry {
+    /// The adapters shipped with this crate.
+    pub fn builtin() -> Self {
+        let mut registry = Self {
+            adapters: Vec::new(),
+        };
+        registry.register("v15", vec![OdooVersion::V15], |pool| {
+            Box::pin(async move {
+                let adapter: Box<dyn OdooAdapter> = Box::new(v15::Adapter::new(pool).await?);
+                Ok(adapter)
+            })
+        });
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, majors: Vec<OdooVersion>, factory: AdapterFactory) {
+        self.adapters.retain(|adapter| adapter.name != name);
+        self.adapters.push(RegisteredAdapter {
+            name: name.to_string(),
+            majors,
+            factory,
+        });
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.adapters
+            .iter()
+            .map(|adapter| adapter.name.as_str())
+            .collect()
+    }
+
+    /// The registered adapters' names, with the Odoo majors each handles.
+    pub fn majors(&self) -> Vec<(&str, Vec<u16>)> {
+        self.adapters
+            .iter()
+            .map(|adapter| {
+                let majors = adapter.majors.iter().map(|major| major.as_u16()).collect();
+                (adapter.name.as_str(), majors)
+            })
+            .collect()
+    }
+
+    /// Fail unless an adapter is registered under `name`.
+    pub fn check_name(&self, name: &str) -> Result<(), BuildAdapterError> {
+        self.named(name).map(|_| ())
+    }
+
+    fn named(&self, name: &str) -> Result<&RegisteredAdapter, BuildAdapterError> {
+        self.adapters
+            .iter()
+            .find(|adapter| adapter.name == name)
+            .ok_or_else(|| BuildAdapterError::UnknownDialect {
+                name: name.to_string(),
+                known: self.names().join(", "),
+            })
+    }
+
+    /// The adapter registered under `name`, whatever the database's version.
+    pub async fn by_name(
+        &self,
+        pool: &PgPool,
+        name: &str,
+    ) -> Result<Box<dyn OdooAdapter>, BuildAdapterError> {
+        (self.named(name)?.factory)(pool).await
+    }
+
+    /// The most recently registered adapter handling `major`.
+    pub async fn by_major(
+        &self,
+        pool: &PgPool,
+        major: OdooVersion,
+    ) -> Result<Box<dyn OdooAdapter>, BuildAdapterError> {
+        let adapter = self
+            .adapters
+            .iter()
+            .rfind(|adapter| adapter.majors.contains(&major))
+            .ok_or(BuildAdapterError::UnsupportedMajor(major.as_u16()))?;
+        (adapter.factory)(pool).await
+    }
 }
 
 #[derive(Debug)]
 pub enum BuildAdapterError {
     UnsupportedMajor(u16),
+    UnknownDialect { name: String, known: String },
     Sql(sqlx::Error),
 }
 
@@ -64,6 +655,9 @@ impl fmt::Display for BuildAdapterError {
                 "unsupported Odoo major version {} (only 15 is currently implemented)",
                 major
             ),
+            Self::UnknownDialect { name, known } => {
+                write!(f, "unknown --dialect '{name}' (registered: {known})")
+            }
             Self::Sql(err) => write!(f, "failed to initialize Odoo adapter: {err}"),
         }
     }
@@ -72,7 +666,7 @@ impl fmt::Display for BuildAdapterError {
 impl Error for BuildAdapterError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            Self::UnsupportedMajor(_) => None,
+            Self::UnsupportedMajor(_) | Self::UnknownDialect { .. } => None,
             Self::Sql(err) => Some(err),
         }
     }
@@ -102,3 +696,125 @@ async fn table_exists(pool: &PgPool, table_name: &str) -> Result<bool, sqlx::Err
 
     Ok(exists.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AdapterRegistry, AttributeValue, BuildAdapterError, DroppedBomLine, MoveState,
+        ProductWhere, QuantOptions, dp_from_rounding, is_transient,
+    };
+    use crate::{odoo::OdooVersion, product::ProductId};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn dp_from_rounding_uses_scale_below_one() {
+        assert_eq!(dp_from_rounding(Decimal::new(1, 2)), 2);
+        assert_eq!(dp_from_rounding(Decimal::ONE), 0);
+    }
+
+    #[test]
+    fn product_where_accepts_single_expressions_only() {
+        for predicate in [
+            "product_template.categ_id IN (5,6) AND product_product.default_code LIKE 'AB-%'",
+            "product_template.name = 'It''s; fine -- really'",
+        ] {
+            assert!(
+                predicate.parse::<ProductWhere>().is_ok(),
+                "{predicate} should be accepted"
+            );
+        }
+        for predicate in [
+            "",
+            "true; DROP TABLE stock_quant",
+            "true -- comment",
+            "true /* comment */",
+            "(product_template.categ_id = 5",
+            "product_template.categ_id = 5)",
+            "product_template.name = 'open",
+            "product_template.name = $$x$$",
+        ] {
+            assert!(
+                predicate.parse::<ProductWhere>().is_err(),
+                "{predicate} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn registry_knows_adapters_by_name() {
+        let mut registry = AdapterRegistry::builtin();
+        registry.register("fork", vec![OdooVersion::V15], |_| {
+            Box::pin(async { Err(BuildAdapterError::UnsupportedMajor(15)) })
+        });
+        registry.register("fork", vec![OdooVersion::V16], |_| {
+            Box::pin(async { Err(BuildAdapterError::UnsupportedMajor(16)) })
+        });
+
+        assert_eq!(registry.names(), vec!["v15", "fork"]);
+        assert!(registry.check_name("fork").is_ok());
+        let err = registry
+            .check_name("v14")
+            .expect_err("unregistered dialects must be rejected");
+        assert_eq!(
+            err.to_string(),
+            "unknown --dialect 'v14' (registered: v15, fork)"
+        );
+    }
+
+    #[test]
+    fn move_states_parse_odoo_names() {
+        assert_eq!(
+            "partially_available".parse::<MoveState>(),
+            Ok(MoveState::PartiallyAvailable)
+        );
+        assert!("done".parse::<MoveState>().is_err());
+        assert_eq!(
+            QuantOptions::default().move_state_names(),
+            vec!["waiting", "confirmed", "assigned", "partially_available"]
+        );
+    }
+
+    #[test]
+    fn attribute_values_parse_and_group_by_attribute() {
+        let filters = ["Colour=Red", " Colour = Blue ", "Size=XL"]
+            .map(|input| input.parse::<AttributeValue>().expect("filter must parse"));
+
+        let grouped = AttributeValue::grouped(&filters);
+
+        assert_eq!(grouped.get("Colour"), Some(&vec!["Red", "Blue"]));
+        assert_eq!(grouped.get("Size"), Some(&vec!["XL"]));
+        assert!("Colour".parse::<AttributeValue>().is_err());
+        assert!("=Red".parse::<AttributeValue>().is_err());
+    }
+
+    #[test]
+    fn dropped_bom_lines_name_the_reason() {
+        let mut line = DroppedBomLine {
+            bom_id: 3,
+            product_id: ProductId(10),
+            product: "[KIT-A] Kit A".to_string(),
+            component_id: ProductId(33),
+            component: "[MAN] Manual".to_string(),
+            component_active: true,
+            component_type: "consu".to_string(),
+        };
+        assert_eq!(
+            line.to_string(),
+            "BoM 3 of Product 10 [KIT-A] Kit A: component Product 33 [MAN] Manual is not storable (consu)"
+        );
+
+        line.component_active = false;
+        line.component_type = "product".to_string();
+        assert!(line.to_string().ends_with("is archived"));
+    }
+
+    #[test]
+    fn connection_errors_are_transient() {
+        let io = sqlx::Error::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+
+        assert!(is_transient(&io));
+        assert!(is_transient(&sqlx::Error::PoolTimedOut));
+        assert!(!is_transient(&sqlx::Error::RowNotFound));
+        assert!(!is_transient(&sqlx::Error::Protocol("bad message".into())));
+    }
+}
@@ -1,21 +1,197 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use futures::TryStreamExt;
 use petgraph::graphmap::DiGraphMap;
 use rust_decimal::{Decimal, RoundingStrategy};
-use sqlx::{PgPool, QueryBuilder};
+use sqlx::{PgConnection, PgPool, QueryBuilder};
 
 use crate::{
-    dialect::{OdooAdapter, dp_from_rounding},
+    audit::{Anomaly, AuditCheck},
+    dialect::{
+        AttributeValue, DroppedBomLine, HoldLocations, OdooAdapter, ProductWhere, QuantOptions,
+        dp_from_rounding,
+    },
+    locations::Location,
     odoo::OdooVersion,
-    product::{Product, ProductId, Quant},
+    product::{
+        AlternativeBom, Dimensions, IncomingBreakdown, MoveDetail, MoveDirection, Product,
+        ProductId, Quant, ReservedBreakdown, capacity_units,
+    },
     warehouse::Warehouse,
 };
 
+/// Dropship moves never move stock the warehouse holds, even when their locations are the
+/// warehouse's: they are recognised by their operation type (sequence code `DS`) or by the rule
+/// of the dropship route that created them.
+const DROPSHIP_EXCLUSION: &str = "
+    AND NOT EXISTS (
+        SELECT 1
+        FROM stock_picking_type AS dropship_picking_type
+        WHERE dropship_picking_type.id = stock_move.picking_type_id
+            AND dropship_picking_type.sequence_code = 'DS'
+    )
+    AND NOT EXISTS (
+        SELECT 1
+        FROM stock_rule AS dropship_rule
+        INNER JOIN ir_model_data AS dropship_route
+            ON dropship_route.res_id = dropship_rule.route_id
+            AND dropship_route.model = 'stock.location.route'
+        WHERE dropship_rule.id = stock_move.rule_id
+            AND dropship_route.module = 'stock_dropshipping'
+            AND dropship_route.name = 'route_drop_shipping'
+    )";
+
+/// The BoMs `products` builds a product from, given `mrp_bom`, its UoM as `mrp_uom` and the
+/// product's UoM as `product_uom`. A quantity in another UoM category cannot be converted, so such
+/// BoMs are not used; products without any other BoM are then simple.
+const USABLE_BOM: &str = "
+    mrp_bom.active is true
+    AND mrp_bom.type in ('normal', 'phantom')
+    AND mrp_bom.product_qty > 0
+    AND mrp_uom.category_id = product_uom.category_id";
+
+/// Whether `stock_location` is one of the hold locations (or below one matched by id).
+fn push_hold_predicate<'a>(
+    query: &mut QueryBuilder<'a, sqlx::Postgres>,
+    hold_locations: &'a HoldLocations,
+) {
+    let _ = query.push(" (stock_location.usage = ANY(");
+    let _ = query.push_bind(&hold_locations.usages);
+    let _ = query.push(") OR stock_location.complete_name ILIKE ANY(");
+    let _ = query.push_bind(&hold_locations.name_patterns);
+    let _ = query.push(
+        ") OR stock_location.parent_path LIKE ANY(
+            SELECT hold_location.parent_path || '%'
+            FROM stock_location AS hold_location
+            WHERE hold_location.id = ANY(",
+    );
+    let _ = query.push_bind(&hold_locations.ids);
+    let _ = query.push(")))");
+}
+
+/// Restrict `column` to locations of one of `usages`, unless there are none.
+fn push_usage_filter<'a>(
+    query: &mut QueryBuilder<'a, sqlx::Postgres>,
+    column: &str,
+    usages: &'a [String],
+) {
+    if usages.is_empty() {
+        return;
+    }
+    let _ = query.push(format!(" AND {column} = ANY("));
+    let _ = query.push_bind(usages);
+    let _ = query.push(")");
+}
+
+/// Moves towards the warehouse: their destination as `stock_location`, with their source.
+const MOVES_IN_FROM: &str = "
+            FROM stock_move
+            INNER JOIN stock_location ON stock_location.id = stock_move.location_dest_id
+            INNER JOIN stock_location AS source_location ON source_location.id = stock_move.location_id";
+
+/// The `WHERE` clause selecting the moves counted as `incoming`.
+fn push_moves_in_filters<'a>(
+    query: &mut QueryBuilder<'a, sqlx::Postgres>,
+    move_states: &'a Vec<&'static str>,
+    warehouse_location_path: &'a str,
+    options: &'a QuantOptions,
+) {
+    let _ = query.push(" WHERE (stock_move.state = ANY(");
+    let _ = query.push_bind(move_states);
+    let _ = query.push(")");
+    if options.include_transit {
+        // Inter-company transit locations are company-less transit locations; stock
+        // shipped into them only reaches us through these moves.
+        let _ = query.push(
+            " OR (
+                source_location.usage = 'transit'
+                AND source_location.company_id IS NULL
+                AND stock_move.state NOT IN ('done', 'cancel')
+            )",
+        );
+    }
+    let _ = query.push(") AND stock_location.parent_path like ");
+    let _ = query.push_bind(warehouse_location_path);
+    push_usage_filter(query, "stock_location.usage", &options.location_usages);
+    if options.odoo_forecast {
+        // Odoo leaves moves within the warehouse out of both incoming and outgoing
+        let _ = query.push(" AND source_location.parent_path NOT LIKE ");
+        let _ = query.push_bind(warehouse_location_path);
+    }
+
+    if !options.include_dropship {
+        let _ = query.push(DROPSHIP_EXCLUSION);
+    }
+}
+
+/// Moves away from the warehouse: their source as `stock_location`, with their destination.
+const MOVES_OUT_FROM: &str = "
+            FROM stock_move
+            INNER JOIN stock_location ON stock_location.id = stock_move.location_id
+            INNER JOIN stock_location AS dest_location ON dest_location.id = stock_move.location_dest_id";
+
+/// The `WHERE` clause selecting the moves counted as `outgoing`.
+fn push_moves_out_filters<'a>(
+    query: &mut QueryBuilder<'a, sqlx::Postgres>,
+    move_states: &'a Vec<&'static str>,
+    warehouse_location_path: &'a str,
+    options: &'a QuantOptions,
+) {
+    let _ = query.push(" WHERE stock_move.state = ANY(");
+    let _ = query.push_bind(move_states);
+    let _ = query.push(") AND stock_location.parent_path like ");
+    let _ = query.push_bind(warehouse_location_path);
+    push_usage_filter(query, "stock_location.usage", &options.location_usages);
+    if options.odoo_forecast {
+        let _ = query.push(" AND dest_location.parent_path NOT LIKE ");
+        let _ = query.push_bind(warehouse_location_path);
+    }
+
+    if !options.include_dropship {
+        let _ = query.push(DROPSHIP_EXCLUSION);
+    }
+}
+
+/// Reference, partner and date of the moves selected through `MOVES_IN_FROM` or
+/// `MOVES_OUT_FROM`, for `--detail moves`.
+const MOVE_DETAIL_SELECT: &str = "
+            SELECT
+                stock_move.product_id,
+                COALESCE(stock_move.reference, stock_move.name),
+                res_partner.name,
+                to_char(stock_move.date, 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'),
+                stock_move.product_qty";
+
+/// Joins for the partner in `MOVE_DETAIL_SELECT`, preferring the picking's partner.
+const MOVE_DETAIL_JOINS: &str = "
+            LEFT JOIN stock_picking ON stock_picking.id = stock_move.picking_id
+            LEFT JOIN res_partner ON res_partner.id = COALESCE(stock_picking.partner_id, stock_move.partner_id)";
+
+/// Restrict `column` to records shared between companies or owned by `company_id`, as Odoo's
+/// multi-company record rules do.
+fn push_company_scope(
+    query: &mut QueryBuilder<'_, sqlx::Postgres>,
+    column: &str,
+    company_id: Option<i32>,
+) {
+    if let Some(company_id) = company_id {
+        let _ = query.push(format!(" AND ({column} IS NULL OR {column} = "));
+        let _ = query.push_bind(company_id);
+        let _ = query.push(")");
+    }
+}
+
 pub struct Adapter {
     has_mrp_bom: bool,
     has_product_commingled: bool,
+    has_stock_valuation_layer: bool,
+    has_sale_order_line: bool,
+    has_purchase_order_line: bool,
+    has_mrp_routing_workcenter: bool,
 }
 
 impl Adapter {
@@ -23,6 +199,10 @@ impl Adapter {
         Ok(Self {
             has_mrp_bom: super::table_exists(pool, "mrp_bom").await?,
             has_product_commingled: super::table_exists(pool, "product_commingled").await?,
+            has_stock_valuation_layer: super::table_exists(pool, "stock_valuation_layer").await?,
+            has_sale_order_line: super::table_exists(pool, "sale_order_line").await?,
+            has_purchase_order_line: super::table_exists(pool, "purchase_order_line").await?,
+            has_mrp_routing_workcenter: super::table_exists(pool, "mrp_routing_workcenter").await?,
         })
     }
 }
@@ -35,18 +215,20 @@ impl OdooAdapter for Adapter {
 
     async fn products(
         &self,
-        pool: &PgPool,
+        conn: &mut PgConnection,
+        company_id: Option<i32>,
         catalogue: &mut HashMap<ProductId, Product>,
+        first_boms: &mut HashMap<ProductId, i32>,
         graph: &mut DiGraphMap<ProductId, Decimal>,
     ) -> Result<(), sqlx::Error> {
         let mut simple_query = QueryBuilder::new(
             "
             SELECT
                 product_product.id,
-                uom_uom.rounding
+                CASE WHEN product_template.tracking = 'serial' THEN 1 ELSE product_uom.rounding END
             FROM product_product
             INNER JOIN product_template ON product_product.product_tmpl_id = product_template.id
-            INNER JOIN uom_uom ON uom_uom.id = product_template.uom_id
+            INNER JOIN uom_uom AS product_uom ON product_uom.id = product_template.uom_id
             WHERE
                 product_product.active is true
                 AND product_template.type = 'product'
@@ -55,19 +237,24 @@ impl OdooAdapter for Adapter {
         );
 
         if self.has_mrp_bom {
+            // Products with a BoM `bom_query` would pick are not simple, and only those
             let _ = simple_query.push(
                 " AND NOT EXISTS (
                     SELECT 1
                     FROM mrp_bom
+                    INNER JOIN uom_uom AS mrp_uom ON mrp_uom.id = mrp_bom.product_uom_id
                     WHERE
-                        active is true
-                        AND (
+                        (
                             (
-                                product_tmpl_id = product_template.id and product_id IS NULL
-                            ) OR product_id = product_product.id
+                                mrp_bom.product_tmpl_id = product_template.id
+                                AND mrp_bom.product_id IS NULL
+                            ) OR mrp_bom.product_id = product_product.id
                         )
-                )",
+                        AND",
             );
+            let _ = simple_query.push(USABLE_BOM);
+            push_company_scope(&mut simple_query, "mrp_bom.company_id", company_id);
+            let _ = simple_query.push(")");
         }
 
         if self.has_product_commingled {
@@ -77,12 +264,13 @@ impl OdooAdapter for Adapter {
 
         let mut simple_stream = simple_query
             .build_query_as::<(ProductId, Decimal)>()
-            .fetch(pool);
+            .fetch(&mut *conn);
 
         while let Some((product_id, rounding)) = simple_stream.try_next().await? {
             let _ = catalogue.insert(product_id, Product::Simple(dp_from_rounding(rounding)));
             let _ = graph.add_node(product_id);
         }
+        drop(simple_stream);
 
         if self.has_product_commingled {
             tracing::debug!("Collecting commingled products");
@@ -90,7 +278,7 @@ impl OdooAdapter for Adapter {
                 "
                 SELECT
                     product_product.id,
-                    uom_uom.rounding
+                    CASE WHEN product_template.tracking = 'serial' THEN 1 ELSE uom_uom.rounding END
                 FROM product_product
                 INNER JOIN product_template ON product_product.product_tmpl_id = product_template.id
                 INNER JOIN uom_uom ON uom_uom.id = product_template.uom_id
@@ -103,7 +291,7 @@ impl OdooAdapter for Adapter {
 
             let mut stream = commingled_query
                 .build_query_as::<(ProductId, Decimal)>()
-                .fetch(pool);
+                .fetch(&mut *conn);
             while let Some((product_id, rounding)) = stream.try_next().await? {
                 let _ =
                     catalogue.insert(product_id, Product::Commingled(dp_from_rounding(rounding)));
@@ -118,9 +306,10 @@ impl OdooAdapter for Adapter {
                 SELECT
                     DISTINCT ON (product_product.id)
                     product_product.id,
+                    mrp_bom.id,
                     mrp_bom.type,
                     mrp_bom.product_qty / mrp_uom.factor * product_uom.factor AS product_qty,
-                    product_uom.rounding
+                    CASE WHEN product_template.tracking = 'serial' THEN 1 ELSE product_uom.rounding END
                 FROM product_product
                 INNER JOIN product_template ON product_product.product_tmpl_id = product_template.id
                 INNER JOIN uom_uom AS product_uom ON product_uom.id = product_template.uom_id
@@ -130,24 +319,30 @@ impl OdooAdapter for Adapter {
                     product_product.active is true
                     AND product_template.active is true
                     AND product_template.type = 'product'
-                    AND mrp_bom.active is true
-                    AND mrp_bom.type in ('normal', 'phantom')
-                    AND mrp_bom.product_qty > 0
+                    AND
             ",
             );
+            let _ = bom_query.push(USABLE_BOM);
 
             if self.has_product_commingled {
                 let _ =
                     bom_query.push(" AND COALESCE(product_product.commingled_ok, false) is false");
             }
 
-            let _ = bom_query.push(" ORDER BY product_product.id, mrp_bom.sequence ASC");
+            push_company_scope(&mut bom_query, "mrp_bom.company_id", company_id);
+
+            // Like Odoo's `_bom_find`, variant BoMs win over template BoMs of the same sequence
+            let _ = bom_query.push(
+                " ORDER BY product_product.id, mrp_bom.sequence ASC, mrp_bom.product_id ASC, mrp_bom.id ASC",
+            );
 
             let mut stream = bom_query
-                .build_query_as::<(ProductId, String, Decimal, Decimal)>()
-                .fetch(pool);
+                .build_query_as::<(ProductId, i32, String, Decimal, Decimal)>()
+                .fetch(&mut *conn);
 
-            while let Some((product_id, bom_type, quantity, rounding)) = stream.try_next().await? {
+            while let Some((product_id, bom_id, bom_type, quantity, rounding)) =
+                stream.try_next().await?
+            {
                 let dp = dp_from_rounding(rounding);
                 let quantity = quantity.round_dp_with_strategy(dp, RoundingStrategy::ToZero);
                 let product = match bom_type.as_str() {
@@ -157,6 +352,7 @@ impl OdooAdapter for Adapter {
                 };
 
                 let _ = catalogue.insert(product_id, product);
+                let _ = first_boms.insert(product_id, bom_id);
                 let _ = graph.add_node(product_id);
             }
         }
@@ -166,28 +362,39 @@ impl OdooAdapter for Adapter {
 
     async fn relations(
         &self,
-        pool: &PgPool,
+        conn: &mut PgConnection,
+        company_id: Option<i32>,
         graph: &mut DiGraphMap<ProductId, Decimal>,
+        first_boms: &HashMap<ProductId, i32>,
+        mut alternatives: Option<&mut HashMap<ProductId, Vec<AlternativeBom>>>,
+        invalid_boms: &mut HashMap<ProductId, i32>,
     ) -> Result<(), sqlx::Error> {
         tracing::debug!("Building graph edges");
+        invalid_boms.clear();
 
         if self.has_mrp_bom {
             tracing::debug!("Fetching MRP edges");
             let mut mrp_edges_query = QueryBuilder::new(
                 "
                 select
-                  mrp_bom.product_id as parent_product_id,
+                  product_product.id as parent_product_id,
                   mrp_bom_line.product_id as child_product_id,
                   COALESCE(mrp_bom_line.product_qty, 1) / line_uom.factor * line_product_uom.factor as child_qty,
-                  line_product_uom.rounding
+                  line_product_uom.rounding,
+                  line_uom.category_id = line_product_uom.category_id as same_uom_category,
+                  mrp_bom.id,
+                  mrp_bom.product_qty / bom_uom.factor * product_uom.factor as bom_qty
                 from mrp_bom_line
                 inner join mrp_bom on mrp_bom.id = mrp_bom_line.bom_id
                 inner join product_template on product_template.id = mrp_bom.product_tmpl_id
-                inner join product_product on product_product.id = mrp_bom.product_id
+                -- Template BoMs apply to every variant of the template
+                inner join product_product on (product_product.product_tmpl_id = product_template.id and mrp_bom.product_id is null) or product_product.id = mrp_bom.product_id
                 inner join product_product as line_product_product on line_product_product.id = mrp_bom_line.product_id
                 inner join product_template as line_product_template on line_product_template.id = line_product_product.product_tmpl_id
                 inner join uom_uom as line_uom on line_uom.id = mrp_bom_line.product_uom_id
                 inner join uom_uom as line_product_uom on line_product_uom.id = line_product_template.uom_id
+                inner join uom_uom as bom_uom on bom_uom.id = mrp_bom.product_uom_id
+                inner join uom_uom as product_uom on product_uom.id = product_template.uom_id
                 where
                   product_template.type = 'product'
                   AND
@@ -195,6 +402,10 @@ impl OdooAdapter for Adapter {
                   AND
                   mrp_bom.active is true
                   AND
+                  mrp_bom.type in ('normal', 'phantom')
+                  AND
+                  bom_uom.category_id = product_uom.category_id
+                  AND
                   line_product_product.active is true
                   AND
                   line_product_template.type = 'product'
@@ -202,22 +413,110 @@ impl OdooAdapter for Adapter {
                   mrp_bom.product_qty > 0
                   AND
                   mrp_bom_line.product_qty > 0
+                  -- Lines restricted to attribute values (Apply on Variants) are skipped for
+                  -- variants without one of the line's values of each restricted attribute
+                  AND
+                  NOT EXISTS (
+                    select 1
+                    from mrp_bom_line_product_template_attribute_value_rel as line_value
+                    inner join product_template_attribute_value as line_ptav on line_ptav.id = line_value.product_template_attribute_value_id
+                    left join product_variant_combination as variant_value
+                      on variant_value.product_template_attribute_value_id = line_ptav.id
+                      and variant_value.product_product_id = product_product.id
+                    where line_value.mrp_bom_line_id = mrp_bom_line.id
+                    group by line_ptav.attribute_id
+                    having count(variant_value.product_product_id) = 0
+                  )
             ",
             );
+            push_company_scope(&mut mrp_edges_query, "mrp_bom.company_id", company_id);
+            // Alternatives are collected BoM by BoM, in the order Odoo would pick them
+            let _ = mrp_edges_query.push(
+                " order by product_product.id, mrp_bom.sequence, mrp_bom.product_id, mrp_bom.id",
+            );
 
             let mut stream = mrp_edges_query
-                .build_query_as::<(ProductId, ProductId, Decimal, Decimal)>()
-                .fetch(pool);
+                .build_query_as::<(ProductId, ProductId, Decimal, Decimal, bool, i32, Decimal)>()
+                .fetch(&mut *conn);
 
-            while let Some((parent, child, child_qty, rounding)) = stream.try_next().await? {
-                if graph.contains_node(parent) && graph.contains_node(child) {
-                    let child_qty = child_qty.round_dp_with_strategy(
-                        dp_from_rounding(rounding),
-                        RoundingStrategy::ToZero,
-                    );
+            let mut invalid_alternatives: HashSet<(ProductId, i32)> = HashSet::new();
+            while let Some((
+                parent,
+                child,
+                child_qty,
+                rounding,
+                same_uom_category,
+                bom_id,
+                bom_qty,
+            )) = stream.try_next().await?
+            {
+                if !graph.contains_node(parent) || !graph.contains_node(child) {
+                    continue;
+                }
+                // Products `products` found no usable BoM for are not built from any
+                let Some(first_bom) = first_boms.get(&parent).map(|first| *first == bom_id) else {
+                    continue;
+                };
+                // A line that cannot be converted invalidates its whole BoM, as building from the
+                // other lines only would overstate what can be made
+                if !same_uom_category {
+                    if first_bom {
+                        let _ = invalid_boms.insert(parent, bom_id);
+                    } else {
+                        let _ = invalid_alternatives.insert((parent, bom_id));
+                    }
+                    continue;
+                }
+                let child_qty = child_qty
+                    .round_dp_with_strategy(dp_from_rounding(rounding), RoundingStrategy::ToZero);
+
+                if first_bom {
                     let _ = graph.add_edge(child, parent, child_qty);
+                } else if let Some(alternatives) = alternatives.as_deref_mut() {
+                    let boms = alternatives.entry(parent).or_default();
+                    match boms.last_mut() {
+                        Some(bom) if bom.bom_id == bom_id => {
+                            bom.components.push((child, child_qty));
+                        }
+                        _ => boms.push(AlternativeBom {
+                            bom_id,
+                            quantity: bom_qty,
+                            components: vec![(child, child_qty)],
+                        }),
+                    }
+                    if !graph.contains_edge(child, parent) {
+                        let _ = graph.add_edge(child, parent, Decimal::ZERO);
+                    }
+                }
+            }
+
+            drop(stream);
+
+            for parent in invalid_boms.keys() {
+                let components: Vec<ProductId> = graph
+                    .neighbors_directed(*parent, petgraph::Incoming)
+                    .collect();
+                for component in components {
+                    let _ = graph.remove_edge(component, *parent);
+                }
+                if let Some(alternatives) = alternatives.as_deref_mut() {
+                    let _ = alternatives.remove(parent);
                 }
             }
+            if let Some(alternatives) = alternatives {
+                for (parent, boms) in alternatives.iter_mut() {
+                    boms.retain(|bom| !invalid_alternatives.contains(&(*parent, bom.bom_id)));
+                }
+                alternatives.retain(|_, boms| !boms.is_empty());
+            }
+
+            if !invalid_boms.is_empty() || !invalid_alternatives.is_empty() {
+                tracing::warn!(
+                    products = invalid_boms.len(),
+                    alternatives = invalid_alternatives.len(),
+                    "Left out BoMs with lines whose UoM category differs from the component's; run audit to list them"
+                );
+            }
         }
 
         if self.has_product_commingled {
@@ -237,13 +536,23 @@ impl OdooAdapter for Adapter {
                   and product_template.type = 'product'
                   and child_product_product.active is true
                   and child_product_template.type = 'product'
-                  and child_product_template.active is true;
+                  and child_product_template.active is true
             ",
             );
+            push_company_scope(
+                &mut commingled_edges_query,
+                "product_template.company_id",
+                company_id,
+            );
+            push_company_scope(
+                &mut commingled_edges_query,
+                "child_product_template.company_id",
+                company_id,
+            );
 
             let mut stream = commingled_edges_query
                 .build_query_as::<(ProductId, ProductId)>()
-                .fetch(pool);
+                .fetch(&mut *conn);
 
             while let Some((parent, child)) = stream.try_next().await? {
                 if graph.contains_node(parent) && graph.contains_node(child) {
@@ -257,29 +566,39 @@ impl OdooAdapter for Adapter {
 
     async fn quants(
         &self,
-        pool: &PgPool,
+        conn: &mut PgConnection,
         warehouse_location_path: &str,
         scoped_products: Option<&[i32]>,
         decimal_precision: u32,
+        options: &QuantOptions,
         raw_quants: &mut HashMap<ProductId, Quant>,
     ) -> Result<(), sqlx::Error> {
         tracing::debug!("Collecting raw quants");
         raw_quants.clear();
+        let move_states = options.move_state_names();
 
-        let mut query = sqlx::QueryBuilder::new(
+        // Negative quants (stock taken out before it was received, e.g. by backdated operations)
+        // are optionally counted as empty rather than offsetting the other quants
+        let quantity = if options.ignore_negative_quants {
+            "GREATEST(COALESCE(stock_quant.quantity, 0), 0)"
+        } else {
+            "COALESCE(stock_quant.quantity, 0)"
+        };
+        let mut query = sqlx::QueryBuilder::new(format!(
             "
             SELECT
                 stock_quant.product_id,
-                SUM(COALESCE(stock_quant.quantity, 0)) as quantity,
+                SUM({quantity}) as quantity,
                 SUM(COALESCE(stock_quant.reserved_quantity, 0)) as reserved
             FROM stock_quant
             INNER JOIN stock_location ON stock_location.id = stock_quant.location_id
             WHERE
                 stock_location.parent_path like
-        ",
-        );
+        "
+        ));
 
         let _ = query.push_bind(warehouse_location_path);
+        push_usage_filter(&mut query, "stock_location.usage", &options.location_usages);
 
         if let Some(product_ids) = scoped_products {
             if product_ids.is_empty() {
@@ -291,11 +610,16 @@ impl OdooAdapter for Adapter {
             let _ = query.push(")");
         }
 
+        if !options.hold_locations.is_empty() {
+            let _ = query.push(" AND NOT");
+            push_hold_predicate(&mut query, &options.hold_locations);
+        }
+
         let _ = query.push(" GROUP BY stock_quant.product_id");
 
         let mut stream = query
             .build_query_as::<(ProductId, Decimal, Decimal)>()
-            .fetch(pool);
+            .fetch(&mut *conn);
 
         while let Some((product_id, quantity, reserved)) = stream.try_next().await? {
             let _ = raw_quants.insert(
@@ -309,20 +633,20 @@ impl OdooAdapter for Adapter {
                 },
             );
         }
+        drop(stream);
 
         let mut moves_in_query = QueryBuilder::new(
             "
             SELECT
-                product_id, SUM(product_qty)
-            FROM stock_move
-            INNER JOIN stock_location ON stock_location.id = stock_move.location_dest_id
-            WHERE
-                stock_move.state in ('waiting', 'confirmed', 'assigned', 'partially_available')
-                AND stock_location.parent_path like
-        ",
+                stock_move.product_id, SUM(stock_move.product_qty)",
+        );
+        let _ = moves_in_query.push(MOVES_IN_FROM);
+        push_moves_in_filters(
+            &mut moves_in_query,
+            &move_states,
+            warehouse_location_path,
+            options,
         );
-
-        let _ = moves_in_query.push_bind(warehouse_location_path);
 
         if let Some(product_ids) = scoped_products {
             if product_ids.is_empty() {
@@ -334,30 +658,30 @@ impl OdooAdapter for Adapter {
             let _ = moves_in_query.push(")");
         }
 
-        let _ = moves_in_query.push(" GROUP BY product_id");
+        let _ = moves_in_query.push(" GROUP BY stock_move.product_id");
 
         let mut stream = moves_in_query
             .build_query_as::<(ProductId, Decimal)>()
-            .fetch(pool);
+            .fetch(&mut *conn);
 
         while let Some((product_id, quantity)) = stream.try_next().await? {
             let entry = raw_quants.entry(product_id).or_default();
             entry.incoming = quantity;
         }
+        drop(stream);
 
         let mut moves_out_query = QueryBuilder::new(
             "
             SELECT
-                product_id, SUM(product_qty)
-            FROM stock_move
-            INNER JOIN stock_location ON stock_location.id = stock_move.location_id
-            WHERE
-                stock_move.state in ('waiting', 'confirmed', 'assigned', 'partially_available')
-                AND stock_location.parent_path like
-        ",
+                stock_move.product_id, SUM(stock_move.product_qty)",
+        );
+        let _ = moves_out_query.push(MOVES_OUT_FROM);
+        push_moves_out_filters(
+            &mut moves_out_query,
+            &move_states,
+            warehouse_location_path,
+            options,
         );
-
-        let _ = moves_out_query.push_bind(warehouse_location_path);
 
         if let Some(product_ids) = scoped_products {
             if product_ids.is_empty() {
@@ -369,11 +693,11 @@ impl OdooAdapter for Adapter {
             let _ = moves_out_query.push(")");
         }
 
-        let _ = moves_out_query.push(" GROUP BY product_id");
+        let _ = moves_out_query.push(" GROUP BY stock_move.product_id");
 
         let mut stream = moves_out_query
             .build_query_as::<(ProductId, Decimal)>()
-            .fetch(pool);
+            .fetch(&mut *conn);
 
         while let Some((product_id, quantity)) = stream.try_next().await? {
             let entry = raw_quants.entry(product_id).or_default();
@@ -383,27 +707,1284 @@ impl OdooAdapter for Adapter {
         Ok(())
     }
 
-    async fn warehouse(&self, pool: &PgPool, id: i32) -> Result<Warehouse, sqlx::Error> {
-        sqlx::query_as::<_, Warehouse>(
+    async fn unit_costs(
+        &self,
+        conn: &mut PgConnection,
+        company_id: Option<i32>,
+        scoped_products: Option<&[i32]>,
+        unit_costs: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error> {
+        unit_costs.clear();
+
+        if !self.has_stock_valuation_layer {
+            tracing::warn!("stock_valuation_layer does not exist, skipping valuation");
+            return Ok(());
+        }
+
+        tracing::debug!("Collecting unit costs");
+        let mut query = QueryBuilder::new(
             "
             SELECT
-                stock_warehouse.id,
-                stock_location.parent_path || '%' as location_path,
-                stock_warehouse.name
-            FROM stock_warehouse
-            INNER JOIN stock_location ON stock_location.id = stock_warehouse.lot_stock_id
+                product_id,
+                SUM(value) / SUM(quantity)
+            FROM stock_valuation_layer
+            WHERE true",
+        );
+        push_company_scope(&mut query, "company_id", company_id);
+
+        if let Some(product_ids) = scoped_products {
+            let _ = query.push(" AND product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY product_id HAVING SUM(quantity) > 0");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, unit_cost)) = stream.try_next().await? {
+            let _ = unit_costs.insert(
+                product_id,
+                unit_cost.round_dp_with_strategy(6, RoundingStrategy::MidpointAwayFromZero),
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn dimensions(
+        &self,
+        conn: &mut PgConnection,
+        scoped_products: Option<&[i32]>,
+        dimensions: &mut HashMap<ProductId, Dimensions>,
+    ) -> Result<(), sqlx::Error> {
+        tracing::debug!("Collecting product weights and volumes");
+        dimensions.clear();
+
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                product_product.id,
+                COALESCE(product_product.weight, product_template.weight, 0),
+                COALESCE(product_product.volume, product_template.volume, 0)
+            FROM product_product
+            INNER JOIN product_template ON product_product.product_tmpl_id = product_template.id
             WHERE
-                stock_warehouse.id = $1
-                AND
-                stock_warehouse.active is true
-                AND
-                stock_location.active is true
-                AND
-                stock_location.usage = 'internal'
+                product_product.active is true
         ",
-        )
-        .bind(id)
-        .fetch_one(pool)
-        .await
+        );
+
+        if let Some(product_ids) = scoped_products {
+            let _ = query.push(" AND product_product.id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, weight, volume)) = stream.try_next().await? {
+            let _ = dimensions.insert(product_id, Dimensions { weight, volume });
+        }
+
+        Ok(())
+    }
+
+    async fn outbound_history(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        window: Duration,
+        scoped_products: Option<&[i32]>,
+        outbound: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error> {
+        tracing::debug!("Collecting outbound move history");
+        outbound.clear();
+
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                stock_move.product_id, SUM(stock_move.product_qty)
+            FROM stock_move
+            INNER JOIN stock_location AS source_location ON source_location.id = stock_move.location_id
+            INNER JOIN stock_location AS dest_location ON dest_location.id = stock_move.location_dest_id
+            WHERE
+                stock_move.state = 'done'
+                AND stock_move.date >= now() - make_interval(secs => ",
+        );
+
+        let _ = query.push_bind(window.as_secs_f64());
+        let _ = query.push(") AND source_location.parent_path like ");
+        let _ = query.push_bind(warehouse_location_path);
+        let _ = query.push(" AND dest_location.parent_path not like ");
+        let _ = query.push_bind(warehouse_location_path);
+
+        if let Some(product_ids) = scoped_products {
+            let _ = query.push(" AND stock_move.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY stock_move.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, quantity)) = stream.try_next().await? {
+            let _ = outbound.insert(product_id, quantity);
+        }
+
+        Ok(())
+    }
+
+    async fn quotations(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_id: i32,
+        scoped_products: Option<&[i32]>,
+        quoted: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error> {
+        quoted.clear();
+
+        if !self.has_sale_order_line {
+            tracing::warn!("sale_order_line does not exist, skipping quotations");
+            return Ok(());
+        }
+
+        tracing::debug!("Collecting quotation quantities");
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                sale_order_line.product_id,
+                SUM(sale_order_line.product_uom_qty / line_uom.factor * product_uom.factor)
+            FROM sale_order_line
+            INNER JOIN sale_order ON sale_order.id = sale_order_line.order_id
+            INNER JOIN product_product ON product_product.id = sale_order_line.product_id
+            INNER JOIN product_template ON product_template.id = product_product.product_tmpl_id
+            INNER JOIN uom_uom AS line_uom ON line_uom.id = sale_order_line.product_uom
+            INNER JOIN uom_uom AS product_uom ON product_uom.id = product_template.uom_id
+            WHERE
+                sale_order.state in ('draft', 'sent')
+                AND sale_order_line.product_uom_qty > 0
+                AND sale_order.warehouse_id =
+        ",
+        );
+
+        let _ = query.push_bind(warehouse_id);
+
+        if let Some(product_ids) = scoped_products {
+            let _ = query.push(" AND sale_order_line.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY sale_order_line.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, quantity)) = stream.try_next().await? {
+            let _ = quoted.insert(product_id, quantity);
+        }
+
+        Ok(())
+    }
+
+    async fn draft_purchases(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_id: i32,
+        scoped_products: Option<&[i32]>,
+        unconfirmed: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error> {
+        unconfirmed.clear();
+
+        if !self.has_purchase_order_line {
+            tracing::warn!("purchase_order_line does not exist, skipping draft purchases");
+            return Ok(());
+        }
+
+        tracing::debug!("Collecting draft purchase quantities");
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                purchase_order_line.product_id,
+                SUM(purchase_order_line.product_qty / line_uom.factor * product_uom.factor)
+            FROM purchase_order_line
+            INNER JOIN purchase_order ON purchase_order.id = purchase_order_line.order_id
+            INNER JOIN stock_picking_type ON stock_picking_type.id = purchase_order.picking_type_id
+            INNER JOIN product_product ON product_product.id = purchase_order_line.product_id
+            INNER JOIN product_template ON product_template.id = product_product.product_tmpl_id
+            INNER JOIN uom_uom AS line_uom ON line_uom.id = purchase_order_line.product_uom
+            INNER JOIN uom_uom AS product_uom ON product_uom.id = product_template.uom_id
+            WHERE
+                purchase_order.state in ('draft', 'sent', 'to approve')
+                AND purchase_order_line.product_qty > 0
+                AND stock_picking_type.warehouse_id =
+        ",
+        );
+
+        let _ = query.push_bind(warehouse_id);
+
+        if let Some(product_ids) = scoped_products {
+            let _ = query.push(" AND purchase_order_line.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY purchase_order_line.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, quantity)) = stream.try_next().await? {
+            let _ = unconfirmed.insert(product_id, quantity);
+        }
+
+        Ok(())
+    }
+
+    async fn held_quants(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        hold_locations: &HoldLocations,
+        scoped_products: Option<&[i32]>,
+        held: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error> {
+        held.clear();
+        if hold_locations.is_empty() {
+            return Ok(());
+        }
+
+        let mut query = QueryBuilder::new(
+            "
+            SELECT stock_quant.product_id, SUM(COALESCE(stock_quant.quantity, 0))
+            FROM stock_quant
+            INNER JOIN stock_location ON stock_location.id = stock_quant.location_id
+            WHERE
+                stock_location.parent_path like ",
+        );
+        let _ = query.push_bind(warehouse_location_path);
+        let _ = query.push(" AND");
+        push_hold_predicate(&mut query, hold_locations);
+
+        if let Some(product_ids) = scoped_products {
+            if product_ids.is_empty() {
+                return Ok(());
+            }
+
+            let _ = query.push(" AND stock_quant.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY stock_quant.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, quantity)) = stream.try_next().await? {
+            let _ = held.insert(product_id, quantity);
+        }
+
+        Ok(())
+    }
+
+    async fn resupply(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_id: i32,
+        hold_locations: &HoldLocations,
+        scoped_products: Option<&[i32]>,
+        resuppliable: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        resuppliable.clear();
+
+        let lead_days = sqlx::query_scalar::<_, Option<i32>>(
+            "
+            SELECT MAX(stock_rule.delay)
+            FROM stock_location_route
+            INNER JOIN stock_rule ON stock_rule.route_id = stock_location_route.id
+            WHERE
+                stock_location_route.supplied_wh_id = $1
+                AND stock_location_route.active is true
+                AND stock_rule.active is true
+        ",
+        )
+        .bind(warehouse_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                stock_quant.product_id,
+                SUM(COALESCE(stock_quant.quantity, 0) - COALESCE(stock_quant.reserved_quantity, 0))
+            FROM stock_wh_resupply_table
+            INNER JOIN stock_warehouse ON stock_warehouse.id = stock_wh_resupply_table.supplier_wh_id
+            INNER JOIN stock_location AS hub_stock ON hub_stock.id = stock_warehouse.lot_stock_id
+            INNER JOIN stock_location ON stock_location.parent_path LIKE hub_stock.parent_path || '%'
+            INNER JOIN stock_quant ON stock_quant.location_id = stock_location.id
+            WHERE
+                stock_warehouse.active is true
+                AND stock_location.usage = 'internal'
+                AND stock_wh_resupply_table.supplied_wh_id = ",
+        );
+        let _ = query.push_bind(warehouse_id);
+
+        if let Some(product_ids) = scoped_products {
+            if product_ids.is_empty() {
+                return Ok(lead_days);
+            }
+
+            let _ = query.push(" AND stock_quant.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        if !hold_locations.is_empty() {
+            let _ = query.push(" AND NOT");
+            push_hold_predicate(&mut query, hold_locations);
+        }
+
+        let _ = query.push(" GROUP BY stock_quant.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, quantity)) = stream.try_next().await? {
+            let _ = resuppliable.insert(product_id, quantity);
+        }
+
+        Ok(lead_days)
+    }
+
+    async fn warehouses_free(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_ids: &[i32],
+        hold_locations: &HoldLocations,
+        scoped_products: Option<&[i32]>,
+        free: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error> {
+        free.clear();
+        if warehouse_ids.is_empty() || scoped_products.is_some_and(<[i32]>::is_empty) {
+            return Ok(());
+        }
+
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                stock_quant.product_id,
+                SUM(COALESCE(stock_quant.quantity, 0) - COALESCE(stock_quant.reserved_quantity, 0))
+            FROM stock_warehouse
+            INNER JOIN stock_location AS warehouse_stock ON warehouse_stock.id = stock_warehouse.lot_stock_id
+            INNER JOIN stock_location ON stock_location.parent_path LIKE warehouse_stock.parent_path || '%'
+            INNER JOIN stock_quant ON stock_quant.location_id = stock_location.id
+            WHERE
+                stock_location.usage = 'internal'
+                AND stock_warehouse.id = ANY(",
+        );
+        let _ = query.push_bind(warehouse_ids);
+        let _ = query.push(")");
+
+        if let Some(product_ids) = scoped_products {
+            let _ = query.push(" AND stock_quant.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        if !hold_locations.is_empty() {
+            let _ = query.push(" AND NOT");
+            push_hold_predicate(&mut query, hold_locations);
+        }
+
+        let _ = query.push(" GROUP BY stock_quant.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, quantity)) = stream.try_next().await? {
+            let _ = free.insert(product_id, quantity);
+        }
+
+        Ok(())
+    }
+
+    async fn work_center_capacity(
+        &self,
+        conn: &mut PgConnection,
+        first_boms: &HashMap<ProductId, i32>,
+        horizon: Duration,
+        scoped_products: Option<&[i32]>,
+        capacity: &mut HashMap<ProductId, Decimal>,
+    ) -> Result<(), sqlx::Error> {
+        capacity.clear();
+        if !self.has_mrp_routing_workcenter || scoped_products.is_some_and(<[i32]>::is_empty) {
+            return Ok(());
+        }
+
+        let scoped: Option<HashSet<i32>> =
+            scoped_products.map(|product_ids| product_ids.iter().copied().collect());
+        let (product_ids, bom_ids): (Vec<i32>, Vec<i32>) = first_boms
+            .iter()
+            .filter(|(product_id, _)| {
+                scoped
+                    .as_ref()
+                    .is_none_or(|scoped| scoped.contains(&product_id.0))
+            })
+            .map(|(product_id, bom_id)| (product_id.0, *bom_id))
+            .unzip();
+
+        // The BoMs picked by `products`; a cycle of an operation makes as many units as its
+        // work center's capacity and takes longer at a lower time efficiency, as in Odoo's
+        // expected durations (set-up and clean-up times are per order, so they are left out)
+        let mut query = QueryBuilder::new(
+            "
+            WITH first_bom AS (
+                SELECT
+                    first_bom.product_id,
+                    first_bom.bom_id,
+                    mrp_bom.type,
+                    CASE WHEN product_template.tracking = 'serial' THEN 1 ELSE product_uom.rounding END AS rounding
+                FROM unnest(",
+        );
+        let _ = query.push_bind(product_ids);
+        let _ = query.push("::int4[], ");
+        let _ = query.push_bind(bom_ids);
+        let _ = query.push(
+            "::int4[]) AS first_bom(product_id, bom_id)
+                INNER JOIN mrp_bom ON mrp_bom.id = first_bom.bom_id
+                INNER JOIN product_product ON product_product.id = first_bom.product_id
+                INNER JOIN product_template ON product_product.product_tmpl_id = product_template.id
+                INNER JOIN uom_uom AS product_uom ON product_uom.id = product_template.uom_id
+            )
+            SELECT
+                first_bom.product_id,
+                first_bom.rounding,
+                (
+                    SELECT COALESCE(SUM(attendance.hour_to - attendance.hour_from), 0)::numeric
+                    FROM resource_calendar_attendance AS attendance
+                    WHERE
+                        attendance.calendar_id = resource_calendar.id
+                        AND attendance.display_type IS NULL
+                ) / CASE WHEN resource_calendar.two_weeks_calendar IS TRUE THEN 2 ELSE 1 END AS weekly_hours,
+                SUM(
+                    mrp_routing_workcenter.time_cycle_manual * 100
+                    / NULLIF(mrp_workcenter.time_efficiency, 0)
+                    / NULLIF(mrp_workcenter.capacity, 0)
+                )::numeric AS minutes_per_unit
+            FROM first_bom
+            INNER JOIN mrp_routing_workcenter ON mrp_routing_workcenter.bom_id = first_bom.bom_id
+            INNER JOIN mrp_workcenter ON mrp_workcenter.id = mrp_routing_workcenter.workcenter_id
+            INNER JOIN resource_calendar ON resource_calendar.id = mrp_workcenter.resource_calendar_id
+            WHERE
+                first_bom.type = 'normal'
+                AND mrp_routing_workcenter.active IS TRUE
+                AND mrp_workcenter.active IS TRUE
+            GROUP BY first_bom.product_id, first_bom.rounding, mrp_workcenter.id, resource_calendar.id
+            ",
+        );
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal, Decimal, Option<Decimal>)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, rounding, weekly_hours, minutes_per_unit)) =
+            stream.try_next().await?
+        {
+            let Some(units) = minutes_per_unit.and_then(|minutes_per_unit| {
+                capacity_units(
+                    weekly_hours,
+                    minutes_per_unit,
+                    horizon,
+                    dp_from_rounding(rounding),
+                )
+            }) else {
+                continue;
+            };
+            let _ = capacity
+                .entry(product_id)
+                .and_modify(|capacity| *capacity = (*capacity).min(units))
+                .or_insert(units);
+        }
+
+        Ok(())
+    }
+
+    async fn reserved_breakdown(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        options: &QuantOptions,
+        scoped_products: Option<&[i32]>,
+        breakdown: &mut HashMap<ProductId, ReservedBreakdown>,
+    ) -> Result<(), sqlx::Error> {
+        breakdown.clear();
+
+        // stock_move_line.product_qty is the reserved quantity in the product's UoM
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                stock_move_line.product_id,
+                COALESCE(SUM(stock_move_line.product_qty) FILTER (
+                    WHERE stock_picking_type.code = 'outgoing'
+                ), 0),
+                COALESCE(SUM(stock_move_line.product_qty) FILTER (
+                    WHERE stock_picking_type.code = 'mrp_operation'
+                ), 0),
+                COALESCE(SUM(stock_move_line.product_qty) FILTER (
+                    WHERE stock_picking_type.code IS NULL
+                    OR stock_picking_type.code NOT IN ('outgoing', 'mrp_operation')
+                ), 0)
+            FROM stock_move_line
+            INNER JOIN stock_move ON stock_move.id = stock_move_line.move_id
+            INNER JOIN stock_location ON stock_location.id = stock_move_line.location_id
+            LEFT JOIN stock_picking_type ON stock_picking_type.id = stock_move.picking_type_id
+            WHERE
+                stock_move_line.state NOT IN ('done', 'cancel')
+                AND stock_move_line.product_qty > 0
+                AND stock_location.parent_path like ",
+        );
+        let _ = query.push_bind(warehouse_location_path);
+        push_usage_filter(&mut query, "stock_location.usage", &options.location_usages);
+
+        if !options.hold_locations.is_empty() {
+            let _ = query.push(" AND NOT");
+            push_hold_predicate(&mut query, &options.hold_locations);
+        }
+
+        if let Some(product_ids) = scoped_products {
+            if product_ids.is_empty() {
+                return Ok(());
+            }
+
+            let _ = query.push(" AND stock_move_line.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY stock_move_line.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal, Decimal, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, delivery, manufacturing, internal)) = stream.try_next().await? {
+            let _ = breakdown.insert(
+                product_id,
+                ReservedBreakdown {
+                    delivery,
+                    manufacturing,
+                    internal,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn oldest_reservations(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        options: &QuantOptions,
+        scoped_products: Option<&[i32]>,
+        oldest: &mut HashMap<ProductId, i32>,
+    ) -> Result<(), sqlx::Error> {
+        oldest.clear();
+
+        // A reservation is a move line holding stock, created when the move is reserved; Odoo
+        // stores its timestamps as UTC without a time zone
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                stock_move_line.product_id,
+                GREATEST(
+                    EXTRACT(DAY FROM (now() AT TIME ZONE 'UTC') - MIN(stock_move_line.create_date)),
+                    0
+                )::int4
+            FROM stock_move_line
+            INNER JOIN stock_location ON stock_location.id = stock_move_line.location_id
+            WHERE
+                stock_move_line.state NOT IN ('done', 'cancel')
+                AND stock_move_line.move_id IS NOT NULL
+                AND stock_move_line.product_qty > 0
+                AND stock_location.parent_path like ",
+        );
+        let _ = query.push_bind(warehouse_location_path);
+        push_usage_filter(&mut query, "stock_location.usage", &options.location_usages);
+
+        if !options.hold_locations.is_empty() {
+            let _ = query.push(" AND NOT");
+            push_hold_predicate(&mut query, &options.hold_locations);
+        }
+
+        if let Some(product_ids) = scoped_products {
+            if product_ids.is_empty() {
+                return Ok(());
+            }
+
+            let _ = query.push(" AND stock_move_line.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY stock_move_line.product_id");
+
+        let mut stream = query.build_query_as::<(ProductId, i32)>().fetch(&mut *conn);
+
+        while let Some((product_id, days)) = stream.try_next().await? {
+            let _ = oldest.insert(product_id, days);
+        }
+
+        Ok(())
+    }
+
+    async fn incoming_breakdown(
+        &self,
+        conn: &mut PgConnection,
+        warehouse_location_path: &str,
+        scoped_products: Option<&[i32]>,
+        options: &QuantOptions,
+        breakdown: &mut HashMap<ProductId, IncomingBreakdown>,
+    ) -> Result<(), sqlx::Error> {
+        breakdown.clear();
+        let move_states = options.move_state_names();
+
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                stock_move.product_id,
+                COALESCE(SUM(stock_move.product_qty) FILTER (
+                    WHERE stock_picking_type.code = 'incoming'
+                ), 0),
+                COALESCE(SUM(stock_move.product_qty) FILTER (
+                    WHERE stock_picking_type.code = 'mrp_operation'
+                ), 0),
+                COALESCE(SUM(stock_move.product_qty) FILTER (
+                    WHERE stock_picking_type.code IS NULL
+                    OR stock_picking_type.code NOT IN ('incoming', 'mrp_operation')
+                ), 0)",
+        );
+        let _ = query.push(MOVES_IN_FROM);
+        let _ = query.push(
+            "
+            LEFT JOIN stock_picking_type ON stock_picking_type.id = stock_move.picking_type_id",
+        );
+        push_moves_in_filters(&mut query, &move_states, warehouse_location_path, options);
+
+        if let Some(product_ids) = scoped_products {
+            if product_ids.is_empty() {
+                return Ok(());
+            }
+
+            let _ = query.push(" AND stock_move.product_id = ANY(");
+            let _ = query.push_bind(product_ids);
+            let _ = query.push(")");
+        }
+
+        let _ = query.push(" GROUP BY stock_move.product_id");
+
+        let mut stream = query
+            .build_query_as::<(ProductId, Decimal, Decimal, Decimal)>()
+            .fetch(&mut *conn);
+
+        while let Some((product_id, purchase, manufacturing, internal)) = stream.try_next().await? {
+            let _ = breakdown.insert(
+                product_id,
+                IncomingBreakdown {
+                    purchase,
+                    manufacturing,
+                    internal,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn open_moves(
+        &self,
+        pool: &PgPool,
+        warehouse_location_path: &str,
+        products: &[ProductId],
+        options: &QuantOptions,
+    ) -> Result<HashMap<ProductId, Vec<MoveDetail>>, sqlx::Error> {
+        let move_states = options.move_state_names();
+        let product_ids: Vec<i32> = products.iter().map(|product| product.0).collect();
+        let mut moves: HashMap<ProductId, Vec<MoveDetail>> = HashMap::new();
+
+        for direction in [MoveDirection::Incoming, MoveDirection::Outgoing] {
+            let mut query = QueryBuilder::new(MOVE_DETAIL_SELECT);
+            match direction {
+                MoveDirection::Incoming => {
+                    let _ = query.push(MOVES_IN_FROM);
+                    let _ = query.push(MOVE_DETAIL_JOINS);
+                    push_moves_in_filters(
+                        &mut query,
+                        &move_states,
+                        warehouse_location_path,
+                        options,
+                    );
+                }
+                MoveDirection::Outgoing => {
+                    let _ = query.push(MOVES_OUT_FROM);
+                    let _ = query.push(MOVE_DETAIL_JOINS);
+                    push_moves_out_filters(
+                        &mut query,
+                        &move_states,
+                        warehouse_location_path,
+                        options,
+                    );
+                }
+            }
+            let _ = query.push(" AND stock_move.product_id = ANY(");
+            let _ = query.push_bind(&product_ids);
+            let _ = query.push(") ORDER BY stock_move.date, stock_move.id");
+
+            let mut stream = query
+                .build_query_as::<(ProductId, String, Option<String>, String, Decimal)>()
+                .fetch(pool);
+
+            while let Some((product_id, reference, partner, date, quantity)) =
+                stream.try_next().await?
+            {
+                moves.entry(product_id).or_default().push(MoveDetail {
+                    direction,
+                    reference,
+                    partner,
+                    date,
+                    quantity,
+                });
+            }
+        }
+
+        Ok(moves)
+    }
+
+    async fn warehouse(
+        &self,
+        pool: &PgPool,
+        id: i32,
+        _lang: Option<&str>,
+    ) -> Result<Warehouse, sqlx::Error> {
+        sqlx::query_as::<_, Warehouse>(
+            "
+            SELECT
+                stock_warehouse.id,
+                stock_location.parent_path || '%' as location_path,
+                stock_warehouse.name,
+                stock_warehouse.company_id,
+                view_location.parent_path || '%' as view_location_path
+            FROM stock_warehouse
+            INNER JOIN stock_location ON stock_location.id = stock_warehouse.lot_stock_id
+            LEFT JOIN stock_location AS view_location ON view_location.id = stock_warehouse.view_location_id
+            WHERE
+                stock_warehouse.id = $1
+                AND
+                stock_warehouse.active is true
+                AND
+                stock_location.active is true
+                AND
+                stock_location.usage = 'internal'
+        ",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn location_scope(
+        &self,
+        pool: &PgPool,
+        location_id: i32,
+        _lang: Option<&str>,
+    ) -> Result<Warehouse, sqlx::Error> {
+        sqlx::query_as::<_, Warehouse>(
+            "
+            SELECT
+                stock_warehouse.id,
+                stock_location.parent_path || '%' as location_path,
+                stock_warehouse.name,
+                stock_warehouse.company_id,
+                stock_location.id as location_id
+            FROM stock_location
+            INNER JOIN stock_warehouse ON stock_warehouse.id = stock_location.warehouse_id
+            WHERE
+                stock_location.id = $1
+                AND
+                stock_location.active is true
+                AND
+                stock_warehouse.active is true
+        ",
+        )
+        .bind(location_id)
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn product_categories(
+        &self,
+        pool: &PgPool,
+        products: &[ProductId],
+    ) -> Result<HashMap<ProductId, String>, sqlx::Error> {
+        let product_ids: Vec<i32> = products.iter().map(|product| product.0).collect();
+
+        sqlx::query_as::<_, (ProductId, String)>(
+            "
+            SELECT
+                product_product.id,
+                COALESCE(product_category.complete_name, product_category.name)
+            FROM product_product
+            INNER JOIN product_template ON product_template.id = product_product.product_tmpl_id
+            INNER JOIN product_category ON product_category.id = product_template.categ_id
+            WHERE
+                product_product.id = ANY($1)
+        ",
+        )
+        .bind(product_ids)
+        .fetch(pool)
+        .try_collect()
+        .await
+    }
+
+    async fn product_references(
+        &self,
+        pool: &PgPool,
+        products: &[ProductId],
+        _lang: Option<&str>,
+    ) -> Result<HashMap<ProductId, String>, sqlx::Error> {
+        let product_ids: Vec<i32> = products.iter().map(|product| product.0).collect();
+
+        sqlx::query_as::<_, (ProductId, String)>(
+            "
+            SELECT
+                product_product.id,
+                COALESCE('[' || product_product.default_code || '] ', '') || product_template.name
+            FROM product_product
+            INNER JOIN product_template ON product_template.id = product_product.product_tmpl_id
+            WHERE
+                product_product.id = ANY($1)
+        ",
+        )
+        .bind(product_ids)
+        .fetch(pool)
+        .try_collect()
+        .await
+    }
+
+    async fn dropped_bom_lines(
+        &self,
+        pool: &PgPool,
+        company_id: Option<i32>,
+    ) -> Result<Vec<DroppedBomLine>, sqlx::Error> {
+        if !self.has_mrp_bom {
+            return Ok(Vec::new());
+        }
+
+        // The BoM side mirrors the MRP edges query in `relations`, the component side negates it
+        let mut query = QueryBuilder::new(
+            "
+            SELECT
+                mrp_bom.id AS bom_id,
+                product_product.id AS product_id,
+                COALESCE('[' || product_product.default_code || '] ', '') || product_template.name AS product,
+                mrp_bom_line.product_id AS component_id,
+                COALESCE('[' || line_product_product.default_code || '] ', '') || line_product_template.name AS component,
+                line_product_product.active IS TRUE AS component_active,
+                line_product_template.type AS component_type
+            FROM mrp_bom_line
+            INNER JOIN mrp_bom ON mrp_bom.id = mrp_bom_line.bom_id
+            INNER JOIN product_template ON product_template.id = mrp_bom.product_tmpl_id
+            INNER JOIN product_product ON (product_product.product_tmpl_id = product_template.id AND mrp_bom.product_id IS NULL) OR product_product.id = mrp_bom.product_id
+            INNER JOIN product_product AS line_product_product ON line_product_product.id = mrp_bom_line.product_id
+            INNER JOIN product_template AS line_product_template ON line_product_template.id = line_product_product.product_tmpl_id
+            WHERE
+                product_template.type = 'product'
+                AND product_product.active IS TRUE
+                AND mrp_bom.active IS TRUE
+                AND mrp_bom.product_qty > 0
+                AND mrp_bom_line.product_qty > 0
+                AND (
+                    line_product_product.active IS NOT TRUE
+                    OR line_product_template.type <> 'product'
+                )
+            ",
+        );
+        push_company_scope(&mut query, "mrp_bom.company_id", company_id);
+        let _ = query.push(" ORDER BY product_product.id, mrp_bom.id, mrp_bom_line.product_id");
+
+        query
+            .build_query_as::<DroppedBomLine>()
+            .fetch(pool)
+            .try_collect()
+            .await
+    }
+
+    async fn locations(
+        &self,
+        pool: &PgPool,
+        warehouse_location_path: &str,
+    ) -> Result<Vec<Location>, sqlx::Error> {
+        sqlx::query_as::<_, Location>(
+            "
+            SELECT
+                stock_location.id,
+                stock_location.location_id AS parent_id,
+                CASE
+                    WHEN stock_location.parent_path || '%' = $1
+                    THEN COALESCE(stock_location.complete_name, stock_location.name)
+                    ELSE stock_location.name
+                END AS name,
+                stock_location.usage,
+                COALESCE(SUM(stock_quant.quantity), 0) AS quantity,
+                COALESCE(SUM(stock_quant.reserved_quantity), 0) AS reserved
+            FROM stock_location
+            LEFT JOIN stock_quant ON stock_quant.location_id = stock_location.id
+            WHERE
+                stock_location.parent_path like $1
+                AND stock_location.active is true
+            GROUP BY stock_location.id
+            ORDER BY stock_location.parent_path
+        ",
+        )
+        .bind(warehouse_location_path)
+        .fetch(pool)
+        .try_collect()
+        .await
+    }
+
+    async fn audit(
+        &self,
+        pool: &PgPool,
+        check: AuditCheck,
+        warehouse_location_path: &str,
+    ) -> Result<Vec<Anomaly>, sqlx::Error> {
+        // Every query selects (record id, product id, location id, detail)
+        let (sql, in_warehouse) = match check {
+            AuditCheck::NegativeQuant => (
+                "
+                SELECT
+                    stock_quant.id,
+                    stock_quant.product_id,
+                    stock_quant.location_id,
+                    'quantity=' || stock_quant.quantity
+                FROM stock_quant
+                INNER JOIN stock_location ON stock_location.id = stock_quant.location_id
+                WHERE
+                    stock_location.parent_path like $1
+                    AND stock_quant.quantity < 0
+                ORDER BY stock_quant.id
+                ",
+                true,
+            ),
+            AuditCheck::ReservedAboveQuantity => (
+                "
+                SELECT
+                    stock_quant.id,
+                    stock_quant.product_id,
+                    stock_quant.location_id,
+                    'quantity=' || stock_quant.quantity
+                        || ', reserved_quantity=' || stock_quant.reserved_quantity
+                FROM stock_quant
+                INNER JOIN stock_location ON stock_location.id = stock_quant.location_id
+                WHERE
+                    stock_location.parent_path like $1
+                    AND stock_quant.reserved_quantity > GREATEST(stock_quant.quantity, 0)
+                ORDER BY stock_quant.id
+                ",
+                true,
+            ),
+            AuditCheck::MoveMissingLocation => (
+                "
+                SELECT
+                    stock_move.id,
+                    stock_move.product_id,
+                    NULL::integer,
+                    'location_id=' || COALESCE(stock_move.location_id::text, 'NULL')
+                        || ', location_dest_id=' || COALESCE(stock_move.location_dest_id::text, 'NULL')
+                FROM stock_move
+                LEFT JOIN stock_location AS source_location ON source_location.id = stock_move.location_id
+                LEFT JOIN stock_location AS dest_location ON dest_location.id = stock_move.location_dest_id
+                WHERE
+                    stock_move.state NOT IN ('done', 'cancel')
+                    AND (source_location.id IS NULL OR dest_location.id IS NULL)
+                ORDER BY stock_move.id
+                ",
+                false,
+            ),
+            AuditCheck::MissingUomRounding => (
+                "
+                SELECT
+                    product_product.id,
+                    product_product.id,
+                    NULL::integer,
+                    'uom_id=' || COALESCE(product_template.uom_id::text, 'NULL')
+                        || ', rounding=' || COALESCE(uom_uom.rounding::text, 'NULL')
+                FROM product_product
+                INNER JOIN product_template ON product_template.id = product_product.product_tmpl_id
+                LEFT JOIN uom_uom ON uom_uom.id = product_template.uom_id
+                WHERE
+                    product_product.active is true
+                    AND product_template.type = 'product'
+                    AND (uom_uom.rounding IS NULL OR uom_uom.rounding <= 0)
+                ORDER BY product_product.id
+                ",
+                false,
+            ),
+            AuditCheck::LocationWithoutParentPath => (
+                "
+                SELECT
+                    stock_location.id,
+                    NULL::integer,
+                    stock_location.id,
+                    'complete_name=' || COALESCE(stock_location.complete_name, stock_location.name)
+                FROM stock_location
+                WHERE
+                    COALESCE(stock_location.parent_path, '') = ''
+                    AND EXISTS (
+                        SELECT 1 FROM stock_quant WHERE stock_quant.location_id = stock_location.id
+                    )
+                ORDER BY stock_location.id
+                ",
+                false,
+            ),
+            AuditCheck::BomUomCategory | AuditCheck::BomLineUomCategory if !self.has_mrp_bom => {
+                return Ok(Vec::new());
+            }
+            AuditCheck::BomUomCategory => (
+                "
+                SELECT
+                    mrp_bom.id,
+                    mrp_bom.product_id,
+                    NULL::integer,
+                    'product_tmpl_id=' || mrp_bom.product_tmpl_id
+                        || ', bom_uom=' || mrp_uom.name || ' (category ' || mrp_uom.category_id || ')'
+                        || ', product_uom=' || product_uom.name || ' (category ' || product_uom.category_id || ')'
+                FROM mrp_bom
+                INNER JOIN product_template ON product_template.id = mrp_bom.product_tmpl_id
+                INNER JOIN uom_uom AS mrp_uom ON mrp_uom.id = mrp_bom.product_uom_id
+                INNER JOIN uom_uom AS product_uom ON product_uom.id = product_template.uom_id
+                WHERE
+                    mrp_bom.active is true
+                    AND mrp_uom.category_id <> product_uom.category_id
+                ORDER BY mrp_bom.id
+                ",
+                false,
+            ),
+            AuditCheck::BomLineUomCategory => (
+                "
+                SELECT
+                    mrp_bom_line.id,
+                    mrp_bom_line.product_id,
+                    NULL::integer,
+                    'bom_id=' || mrp_bom_line.bom_id
+                        || ', line_uom=' || line_uom.name || ' (category ' || line_uom.category_id || ')'
+                        || ', component_uom=' || line_product_uom.name || ' (category ' || line_product_uom.category_id || ')'
+                FROM mrp_bom_line
+                INNER JOIN mrp_bom ON mrp_bom.id = mrp_bom_line.bom_id
+                INNER JOIN product_product AS line_product_product ON line_product_product.id = mrp_bom_line.product_id
+                INNER JOIN product_template AS line_product_template ON line_product_template.id = line_product_product.product_tmpl_id
+                INNER JOIN uom_uom AS line_uom ON line_uom.id = mrp_bom_line.product_uom_id
+                INNER JOIN uom_uom AS line_product_uom ON line_product_uom.id = line_product_template.uom_id
+                WHERE
+                    mrp_bom.active is true
+                    AND line_uom.category_id <> line_product_uom.category_id
+                ORDER BY mrp_bom_line.id
+                ",
+                false,
+            ),
+        };
+
+        let mut query = sqlx::query_as::<_, (i32, Option<i32>, Option<i32>, String)>(sql);
+        if in_warehouse {
+            query = query.bind(warehouse_location_path);
+        }
+
+        query
+            .fetch(pool)
+            .map_ok(|row| Anomaly::new(check, row))
+            .try_collect()
+            .await
+    }
+
+    async fn products_where(
+        &self,
+        pool: &PgPool,
+        predicate: &ProductWhere,
+    ) -> Result<Vec<ProductId>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let _ = sqlx::query("SET TRANSACTION READ ONLY")
+            .execute(&mut *tx)
+            .await?;
+
+        let products = sqlx::query_as::<_, (ProductId,)>(&format!(
+            "
+            SELECT product_product.id
+            FROM product_product
+            INNER JOIN product_template
+                ON product_template.id = product_product.product_tmpl_id
+            WHERE
+                product_product.active is true
+                AND ({})
+            ORDER BY product_product.id
+            ",
+            predicate.0
+        ))
+        .fetch(&mut *tx)
+        .map_ok(|(id,)| id)
+        .try_collect()
+        .await?;
+
+        tx.rollback().await?;
+        Ok(products)
+    }
+
+    async fn products_with_attribute_values(
+        &self,
+        pool: &PgPool,
+        filters: &[AttributeValue],
+    ) -> Result<Vec<ProductId>, sqlx::Error> {
+        let mut query = QueryBuilder::new(
+            "
+            SELECT product_product.id
+            FROM product_product
+            WHERE
+                product_product.active is true
+            ",
+        );
+
+        for (attribute, values) in AttributeValue::grouped(filters) {
+            let _ = query.push(
+                " AND EXISTS (
+                    SELECT 1
+                    FROM product_variant_combination
+                    INNER JOIN product_template_attribute_value
+                        ON product_template_attribute_value.id = product_variant_combination.product_template_attribute_value_id
+                    INNER JOIN product_attribute
+                        ON product_attribute.id = product_template_attribute_value.attribute_id
+                    INNER JOIN product_attribute_value
+                        ON product_attribute_value.id = product_template_attribute_value.product_attribute_value_id
+                    WHERE
+                        product_variant_combination.product_product_id = product_product.id
+                        AND product_attribute.name = ",
+            );
+            let _ = query.push_bind(attribute);
+            let _ = query.push(" AND product_attribute_value.name = ANY(");
+            let _ = query.push_bind(values);
+            let _ = query.push("))");
+        }
+
+        let _ = query.push(" ORDER BY product_product.id");
+
+        query
+            .build_query_as::<(ProductId,)>()
+            .fetch(pool)
+            .map_ok(|(id,)| id)
+            .try_collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use sqlx::{Connection, PgConnection, QueryBuilder};
+
+    use super::{MOVES_IN_FROM, MOVES_OUT_FROM, push_moves_in_filters, push_moves_out_filters};
+    use crate::dialect::QuantOptions;
+
+    /// Postgres database the queries are run against, inside a transaction rolled back at the
+    /// end; the tests needing it are skipped when unset.
+    const TEST_DB_URL: &str = "ODOO_RAPID_QUANT_TEST_DB_URL";
+
+    const MOVES_SCHEMA: &str = "
+        CREATE TEMP TABLE stock_location (
+            id int PRIMARY KEY, usage text, parent_path text, company_id int
+        );
+        CREATE TEMP TABLE stock_picking_type (id int PRIMARY KEY, code text, sequence_code text);
+        CREATE TEMP TABLE stock_rule (id int PRIMARY KEY, route_id int);
+        CREATE TEMP TABLE ir_model_data (module text, name text, model text, res_id int);
+        CREATE TEMP TABLE stock_move (
+            id int PRIMARY KEY, product_id int, product_qty numeric, state text,
+            location_id int, location_dest_id int, picking_type_id int, rule_id int
+        );
+        INSERT INTO stock_location VALUES
+            (1, 'supplier', '1/', NULL), (2, 'view', '2/', 1), (3, 'internal', '2/3/', 1),
+            (4, 'customer', '4/', NULL);
+        INSERT INTO stock_picking_type VALUES
+            (1, 'incoming', 'IN'), (2, 'outgoing', 'OUT'), (3, 'incoming', 'DS');
+        INSERT INTO stock_rule VALUES (1, 9);
+        INSERT INTO ir_model_data VALUES
+            ('stock_dropshipping', 'route_drop_shipping', 'stock.location.route', 9);
+        INSERT INTO stock_move VALUES
+            (1, 1, 5, 'assigned', 1, 3, 1, NULL),
+            (2, 1, 7, 'assigned', 1, 3, 3, NULL),
+            (3, 1, 11, 'assigned', 1, 3, 1, 1),
+            (4, 1, 2, 'assigned', 3, 4, 2, NULL),
+            (5, 1, 13, 'assigned', 3, 4, 3, NULL);";
+
+    async fn moved(conn: &mut PgConnection, options: &QuantOptions) -> (Decimal, Decimal) {
+        let move_states = options.move_state_names();
+        let mut incoming = QueryBuilder::new("SELECT COALESCE(SUM(stock_move.product_qty), 0)");
+        let _ = incoming.push(MOVES_IN_FROM);
+        push_moves_in_filters(&mut incoming, &move_states, "2/%", options);
+        let mut outgoing = QueryBuilder::new("SELECT COALESCE(SUM(stock_move.product_qty), 0)");
+        let _ = outgoing.push(MOVES_OUT_FROM);
+        push_moves_out_filters(&mut outgoing, &move_states, "2/%", options);
+
+        (
+            incoming
+                .build_query_scalar()
+                .fetch_one(&mut *conn)
+                .await
+                .expect("incoming moves"),
+            outgoing
+                .build_query_scalar()
+                .fetch_one(&mut *conn)
+                .await
+                .expect("outgoing moves"),
+        )
+    }
+
+    #[tokio::test]
+    async fn dropship_moves_through_the_warehouse_are_left_out() {
+        let Ok(url) = std::env::var(TEST_DB_URL) else {
+            return;
+        };
+        let mut conn = PgConnection::connect(&url).await.expect("test database");
+        let mut tx = conn.begin().await.expect("transaction");
+        sqlx::raw_sql(MOVES_SCHEMA)
+            .execute(&mut *tx)
+            .await
+            .expect("moves schema");
+
+        // A DS operation or a dropship route rule marks the move, wherever it goes
+        let options = QuantOptions::default();
+        assert_eq!(
+            moved(&mut tx, &options).await,
+            (Decimal::from(5), Decimal::from(2))
+        );
+
+        let options = QuantOptions {
+            include_dropship: true,
+            ..QuantOptions::default()
+        };
+        assert_eq!(
+            moved(&mut tx, &options).await,
+            (Decimal::from(23), Decimal::from(15))
+        );
+        tx.rollback().await.expect("rollback");
     }
 }
@@ -0,0 +1,280 @@
+use std::fmt;
+
+use sqlx::{
+    PgPool,
+    postgres::{PgConnectOptions, PgPoolOptions},
+};
+
+use crate::{
+    dialect::{AdapterRegistry, AttributeValue, BuildAdapterError, OdooAdapter, ProductWhere},
+    odoo::{DetectOdooVersionError, OdooVersion},
+    product::{CollectError, Graph, ProductId},
+    warehouse::{StockScope, Warehouse, WarehouseScope},
+};
+
+/// Sets up a [`Graph`] from a source database: connects, detects the Odoo version, picks its
+/// adapter and looks up the warehouse or location, then optionally collects availability.
+pub struct GraphBuilder<'a> {
+    source: PgConnectOptions,
+    scope: Option<StockScope>,
+    warehouse_scope: WarehouseScope,
+    lang: Option<String>,
+    products: Vec<ProductId>,
+    attribute_values: Vec<AttributeValue>,
+    product_where: Option<ProductWhere>,
+    configure: Option<Configure<'a>>,
+    registry: AdapterRegistry,
+    dialect: Option<String>,
+}
+
+type Configure<'a> = Box<dyn FnOnce(&mut Graph) + Send + 'a>;
+
+impl fmt::Debug for GraphBuilder<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GraphBuilder")
+            .field("scope", &self.scope)
+            .field("warehouse_scope", &self.warehouse_scope)
+            .field("lang", &self.lang)
+            .field("products", &self.products)
+            .field("attribute_values", &self.attribute_values)
+            .field("product_where", &self.product_where)
+            .field("registry", &self.registry.names())
+            .field("dialect", &self.dialect)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> GraphBuilder<'a> {
+    /// `registry` holds the adapters to pick from, e.g. the built-in ones plus one for a
+    /// customized fork.
+    pub fn new(source: PgConnectOptions, registry: AdapterRegistry) -> Self {
+        Self {
+            source,
+            scope: None,
+            warehouse_scope: WarehouseScope::default(),
+            lang: None,
+            products: Vec::new(),
+            attribute_values: Vec::new(),
+            product_where: None,
+            configure: None,
+            registry,
+            dialect: None,
+        }
+    }
+
+    /// The warehouse or location to compute availability for; required.
+    pub fn scope(mut self, scope: StockScope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// The locations counted as a warehouse's stock; warehouse scopes only.
+    pub fn warehouse_scope(mut self, warehouse_scope: WarehouseScope) -> Self {
+        self.warehouse_scope = warehouse_scope;
+        self
+    }
+
+    /// Language of the warehouse name, from Odoo 16 onwards.
+    pub fn lang(mut self, lang: Option<String>) -> Self {
+        self.lang = lang;
+        self
+    }
+
+    /// Only compute these products (and their dependencies); every product when empty.
+    pub fn products(mut self, products: Vec<ProductId>) -> Self {
+        self.products = products;
+        self
+    }
+
+    /// Only compute products carrying all of these `attribute:value` pairs, within `products`.
+    pub fn attribute_values(mut self, attribute_values: Vec<AttributeValue>) -> Self {
+        self.attribute_values = attribute_values;
+        self
+    }
+
+    /// Use the adapter registered under this name instead of detecting the Odoo version.
+    pub fn dialect(mut self, dialect: Option<String>) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Only compute products matching this SQL predicate, within `products`.
+    pub fn product_where(mut self, product_where: Option<ProductWhere>) -> Self {
+        self.product_where = product_where;
+        self
+    }
+
+    /// Set collection options (`with_*` metrics, quant options, timeouts, ...) on the graph
+    /// before anything is collected.
+    pub fn configure(mut self, configure: impl FnOnce(&mut Graph) + Send + 'a) -> Self {
+        self.configure = Some(Box::new(configure));
+        self
+    }
+
+    /// Connect and set up the graph, without collecting anything.
+    pub async fn build(self) -> Result<Graph, GraphBuildError> {
+        let scope = self.scope.ok_or(GraphBuildError::MissingScope)?;
+
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_with(self.source)
+            .await
+            .map_err(GraphBuildError::Connect)?;
+
+        let adapter = match self.dialect.as_deref() {
+            Some(name) => self.registry.by_name(&pool, name).await?,
+            None => {
+                let detected = OdooVersion::detect_from_database(&pool).await?;
+                self.registry.by_major(&pool, detected).await?
+            }
+        };
+        tracing::info!("Using adapter for Odoo major {}.", adapter.major());
+
+        if self.lang.is_some() && adapter.major() < OdooVersion::V16 {
+            tracing::warn!(
+                "--lang has no effect before Odoo 16, names are not stored as translated jsonb"
+            );
+        }
+
+        let warehouse = scope_warehouse(
+            adapter.as_ref(),
+            &pool,
+            scope,
+            self.warehouse_scope,
+            self.lang.as_deref(),
+        )
+        .await?;
+
+        let mut graph = Graph::new(pool, warehouse, adapter)
+            .await
+            .map_err(GraphBuildError::Sql)?;
+        if let Some(configure) = self.configure {
+            configure(&mut graph);
+        }
+        Ok(graph)
+    }
+
+    /// Set up the graph and compute availability for the requested products.
+    pub async fn collect(mut self) -> Result<Graph, GraphBuildError> {
+        let mut products = std::mem::take(&mut self.products);
+        let attribute_values = std::mem::take(&mut self.attribute_values);
+        let product_where = self.product_where.take();
+        let mut graph = self.build().await?;
+
+        let mut filters = Vec::new();
+        if !attribute_values.is_empty() {
+            filters.push(
+                graph
+                    .adapter
+                    .products_with_attribute_values(&graph.pool, &attribute_values)
+                    .await
+                    .map_err(GraphBuildError::Sql)?,
+            );
+        }
+        if let Some(predicate) = product_where.as_ref() {
+            filters.push(
+                graph
+                    .adapter
+                    .products_where(&graph.pool, predicate)
+                    .await
+                    .map_err(GraphBuildError::ProductWhere)?,
+            );
+        }
+
+        // Every filter narrows the products down further
+        for (index, matching) in filters.into_iter().enumerate() {
+            products = if products.is_empty() && index == 0 {
+                matching
+            } else {
+                products
+                    .into_iter()
+                    .filter(|product| matching.contains(product))
+                    .collect()
+            };
+
+            if products.is_empty() {
+                return Err(GraphBuildError::NoMatchingProducts);
+            }
+        }
+
+        graph.collect(&products).await?;
+        Ok(graph)
+    }
+}
+
+/// Look up a warehouse, or a location of a warehouse, as the scope availability is computed for.
+pub async fn scope_warehouse(
+    adapter: &dyn OdooAdapter,
+    pool: &PgPool,
+    scope: StockScope,
+    warehouse_scope: WarehouseScope,
+    lang: Option<&str>,
+) -> Result<Warehouse, GraphBuildError> {
+    let location_scope = |location_id| async move {
+        adapter
+            .location_scope(pool, location_id, lang)
+            .await
+            .map_err(|source| GraphBuildError::LocationNotFound {
+                location_id,
+                source,
+            })
+    };
+
+    let warehouse_id = match scope {
+        StockScope::Warehouse(warehouse_id) => warehouse_id,
+        StockScope::Location(location_id) => return location_scope(location_id).await,
+    };
+    let mut warehouse = adapter
+        .warehouse(pool, warehouse_id, lang)
+        .await
+        .map_err(|source| GraphBuildError::WarehouseNotFound {
+            warehouse_id,
+            source,
+        })?;
+
+    match warehouse_scope {
+        WarehouseScope::LotStock => {}
+        WarehouseScope::View => {
+            warehouse.location_path = warehouse
+                .view_location_path
+                .clone()
+                .ok_or(GraphBuildError::NoViewLocation(warehouse_id))?;
+        }
+        WarehouseScope::CustomLocation(location_id) => {
+            warehouse.location_path = location_scope(location_id).await?.location_path;
+        }
+    }
+    Ok(warehouse)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GraphBuildError {
+    #[error("no warehouse or location to compute availability for")]
+    MissingScope,
+    #[error("failed to connect to the source database: {0}")]
+    Connect(#[source] sqlx::Error),
+    #[error(transparent)]
+    Detect(#[from] DetectOdooVersionError),
+    #[error(transparent)]
+    Adapter(#[from] BuildAdapterError),
+    #[error("warehouse {warehouse_id} not found or archived")]
+    WarehouseNotFound {
+        warehouse_id: i32,
+        source: sqlx::Error,
+    },
+    #[error("location {location_id} not found, archived or outside any warehouse")]
+    LocationNotFound {
+        location_id: i32,
+        source: sqlx::Error,
+    },
+    #[error("warehouse {0} has no view location to count stock under")]
+    NoViewLocation(i32),
+    #[error("no products match the product filters")]
+    NoMatchingProducts,
+    #[error("--product-where failed: {0}")]
+    ProductWhere(#[source] sqlx::Error),
+    #[error(transparent)]
+    Collect(#[from] CollectError),
+    #[error(transparent)]
+    Sql(sqlx::Error),
+}
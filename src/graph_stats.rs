@@ -0,0 +1,201 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use petgraph::{
+    graphmap::DiGraphMap,
+    visit::{Dfs, Reversed},
+};
+use rust_decimal::Decimal;
+
+use crate::product::{Product, ProductId, Quant};
+
+/// Size and shape of the product graph, for sizing runs and spotting data pathologies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphStats {
+    /// products per classification
+    pub nodes: BTreeMap<&'static str, usize>,
+
+    /// BoM lines and commingled links, per classification of the product they feed
+    pub edges: BTreeMap<&'static str, usize>,
+
+    /// longest chain of BoM/commingled levels above a simple product, `None` when cyclic
+    pub max_depth: Option<usize>,
+
+    /// groups of products linked to each other through any BoM or commingled link
+    pub components: usize,
+
+    /// simple and normal-BoM products with neither quants nor open moves in the warehouse
+    pub without_quants: usize,
+
+    /// kits with the most distinct components at any level, largest first
+    pub largest_kits: Vec<(ProductId, Product, usize)>,
+}
+
+impl GraphStats {
+    pub fn new(
+        graph: &DiGraphMap<ProductId, Decimal>,
+        catalogue: &HashMap<ProductId, Product>,
+        raw_quants: &HashMap<ProductId, Quant>,
+        top: usize,
+    ) -> Self {
+        let mut nodes = BTreeMap::new();
+        let mut without_quants = 0;
+        let mut largest_kits = Vec::new();
+
+        for product_id in graph.nodes() {
+            let Some(product) = catalogue.get(&product_id) else {
+                continue;
+            };
+            *nodes.entry(product.type_label()).or_default() += 1;
+
+            match product {
+                Product::Simple(_) | Product::MrpNormal(_, _)
+                    if !raw_quants.contains_key(&product_id) =>
+                {
+                    without_quants += 1;
+                }
+                _ => {}
+            }
+
+            if let Product::MrpPhantom(_, _) | Product::MrpNormal(_, _) = product {
+                let mut components = 0;
+                let mut dfs = Dfs::new(Reversed(graph), product_id);
+                while dfs.next(Reversed(graph)).is_some() {
+                    components += 1;
+                }
+                // The kit itself is the first node visited
+                largest_kits.push((product_id, *product, components - 1));
+            }
+        }
+
+        let mut edges = BTreeMap::new();
+        for (_, product_id, _) in graph.all_edges() {
+            if let Some(product) = catalogue.get(&product_id) {
+                *edges.entry(product.type_label()).or_default() += 1;
+            }
+        }
+
+        largest_kits.sort_by(|(left_id, _, left), (right_id, _, right)| {
+            right.cmp(left).then(left_id.cmp(right_id))
+        });
+        largest_kits.truncate(top);
+
+        Self {
+            nodes,
+            edges,
+            max_depth: max_depth(graph),
+            components: petgraph::algo::connected_components(graph),
+            without_quants,
+            largest_kits,
+        }
+    }
+}
+
+fn max_depth(graph: &DiGraphMap<ProductId, Decimal>) -> Option<usize> {
+    let sorted = petgraph::algo::toposort(graph, None).ok()?;
+    let mut depths: HashMap<ProductId, usize> = HashMap::with_capacity(sorted.len());
+
+    // Dependencies come first, so every source's depth is known when its product is reached
+    for product in sorted {
+        let depth = graph
+            .neighbors_directed(product, petgraph::Incoming)
+            .filter_map(|dependency| depths.get(&dependency))
+            .map(|depth| depth + 1)
+            .max()
+            .unwrap_or(0);
+        let _ = depths.insert(product, depth);
+    }
+
+    Some(depths.into_values().max().unwrap_or(0))
+}
+
+fn write_counts(
+    f: &mut fmt::Formatter<'_>,
+    label: &str,
+    counts: &BTreeMap<&'static str, usize>,
+) -> fmt::Result {
+    let total: usize = counts.values().sum();
+    write!(f, "{label}: {total}")?;
+    for (index, (name, count)) in counts.iter().enumerate() {
+        f.write_str(if index == 0 { " (" } else { ", " })?;
+        write!(f, "{name}={count}")?;
+    }
+    if !counts.is_empty() {
+        f.write_str(")")?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_counts(f, "products", &self.nodes)?;
+        write_counts(f, "links", &self.edges)?;
+        match self.max_depth {
+            Some(depth) => writeln!(f, "max BoM depth: {depth}")?,
+            None => writeln!(f, "max BoM depth: unknown, the graph has cycles")?,
+        }
+        writeln!(f, "connected components: {}", self.components)?;
+        writeln!(f, "products without quants: {}", self.without_quants)?;
+        if !self.largest_kits.is_empty() {
+            writeln!(f, "largest kits:")?;
+        }
+        for (product_id, product, components) in &self.largest_kits {
+            writeln!(
+                f,
+                "  Product {} [{}]: {components} components",
+                product_id.0,
+                product.type_label()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use petgraph::graphmap::DiGraphMap;
+    use rust_decimal::Decimal;
+
+    use super::GraphStats;
+    use crate::product::{Product, ProductId, Quant};
+
+    #[test]
+    fn counts_shape_of_the_graph() {
+        let catalogue = HashMap::from([
+            (ProductId(1), Product::Simple(0)),
+            (ProductId(2), Product::Simple(0)),
+            (ProductId(3), Product::MrpPhantom(Decimal::ONE, 0)),
+            (ProductId(4), Product::MrpNormal(Decimal::ONE, 0)),
+            (ProductId(5), Product::Simple(0)),
+        ]);
+        let mut graph = DiGraphMap::new();
+        for product in catalogue.keys() {
+            let _ = graph.add_node(*product);
+        }
+        let _ = graph.add_edge(ProductId(1), ProductId(3), Decimal::ONE);
+        let _ = graph.add_edge(ProductId(2), ProductId(3), Decimal::TWO);
+        let _ = graph.add_edge(ProductId(3), ProductId(4), Decimal::ONE);
+        let raw_quants = HashMap::from([(ProductId(1), Quant::default())]);
+
+        let stats = GraphStats::new(&graph, &catalogue, &raw_quants, 1);
+        assert_eq!(stats.nodes["Simple"], 3);
+        assert_eq!(stats.edges["MrpPhantom"], 2);
+        assert_eq!(stats.edges["MrpNormal"], 1);
+        assert_eq!(stats.max_depth, Some(2));
+        assert_eq!(stats.components, 2);
+        assert_eq!(stats.without_quants, 3);
+        assert_eq!(
+            stats.largest_kits,
+            vec![(ProductId(4), Product::MrpNormal(Decimal::ONE, 0), 3)]
+        );
+        assert!(
+            stats
+                .to_string()
+                .contains("products: 5 (MrpNormal=1, MrpPhantom=1, Simple=3)")
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::{collections::HashMap, io::Write};
+
+use rust_decimal::Decimal;
+
+/// A stock location under the warehouse, with the totals of the quants directly in it.
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct Location {
+    pub id: i32,
+    pub parent_id: Option<i32>,
+
+    /// full name for the topmost location, own name below it
+    pub name: String,
+    pub usage: String,
+    pub quantity: Decimal,
+    pub reserved: Decimal,
+}
+
+/// Print the locations as a tree, each with its own quant totals and those of its subtree.
+pub fn write_tree<W: Write>(writer: &mut W, locations: &[Location]) -> std::io::Result<()> {
+    let mut children: HashMap<Option<i32>, Vec<&Location>> = HashMap::new();
+    for location in locations {
+        let parent = location
+            .parent_id
+            .filter(|parent| locations.iter().any(|other| other.id == *parent));
+        children.entry(parent).or_default().push(location);
+    }
+    for siblings in children.values_mut() {
+        siblings.sort_by(|left, right| left.name.cmp(&right.name).then(left.id.cmp(&right.id)));
+    }
+
+    for root in children.get(&None).into_iter().flatten() {
+        write_node(writer, root, &children, "", None)?;
+    }
+    Ok(())
+}
+
+fn subtree_totals(
+    location: &Location,
+    children: &HashMap<Option<i32>, Vec<&Location>>,
+) -> (Decimal, Decimal) {
+    children
+        .get(&Some(location.id))
+        .into_iter()
+        .flatten()
+        .map(|child| subtree_totals(child, children))
+        .fold(
+            (location.quantity, location.reserved),
+            |(quantity, reserved), (child_quantity, child_reserved)| {
+                (quantity + child_quantity, reserved + child_reserved)
+            },
+        )
+}
+
+/// `is_last` is `None` for a root, which is printed without a connector.
+fn write_node<W: Write>(
+    writer: &mut W,
+    location: &Location,
+    children: &HashMap<Option<i32>, Vec<&Location>>,
+    prefix: &str,
+    is_last: Option<bool>,
+) -> std::io::Result<()> {
+    let (connector, child_prefix) = match is_last {
+        None => ("", String::new()),
+        Some(true) => ("└── ", format!("{prefix}    ")),
+        Some(false) => ("├── ", format!("{prefix}│   ")),
+    };
+    let (quantity, reserved) = subtree_totals(location, children);
+    writeln!(
+        writer,
+        "{prefix}{connector}{} [{}, id {}]: quantity={}, reserved={} (with children: quantity={quantity}, reserved={reserved})",
+        location.name, location.usage, location.id, location.quantity, location.reserved
+    )?;
+
+    let siblings = children
+        .get(&Some(location.id))
+        .map_or(&[][..], Vec::as_slice);
+    for (index, child) in siblings.iter().enumerate() {
+        write_node(
+            writer,
+            child,
+            children,
+            &child_prefix,
+            Some(index + 1 == siblings.len()),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::{Location, write_tree};
+
+    fn location(id: i32, parent_id: Option<i32>, name: &str, quantity: i64) -> Location {
+        Location {
+            id,
+            parent_id,
+            name: name.to_string(),
+            usage: "internal".to_string(),
+            quantity: Decimal::from(quantity),
+            reserved: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn prints_children_sorted_with_subtree_totals() {
+        let locations = vec![
+            location(8, Some(7), "WH/Stock", 1),
+            location(12, Some(8), "Shelf 2", 5),
+            location(11, Some(8), "Shelf 1", 2),
+            location(13, Some(11), "Bin A", 3),
+        ];
+
+        let mut output = Vec::new();
+        write_tree(&mut output, &locations).expect("writing to a Vec cannot fail");
+
+        assert_eq!(
+            String::from_utf8(output).expect("tree must be UTF-8"),
+            "\
+WH/Stock [internal, id 8]: quantity=1, reserved=0 (with children: quantity=11, reserved=0)
+├── Shelf 1 [internal, id 11]: quantity=2, reserved=0 (with children: quantity=5, reserved=0)
+│   └── Bin A [internal, id 13]: quantity=3, reserved=0 (with children: quantity=3, reserved=0)
+└── Shelf 2 [internal, id 12]: quantity=5, reserved=0 (with children: quantity=5, reserved=0)
+"
+        );
+    }
+}
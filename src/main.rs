@@ -2,13 +2,22 @@
 #![cfg_attr(test, allow(unused_results))]
 
 use anyhow::Context;
-use clap::Parser;
-use product::{AvailabilityOutputMode, DiagnosticNode, OutputAvailability, ProductId};
+use clap::{Parser, ValueEnum};
+use product::{
+    AvailabilityOutputMode, CollectPhase, ComputeError, Explanation, FreeFormula, MoveDetail,
+    OutputAvailability, Product, ProductId, ProductMetrics,
+};
+use rust_decimal::Decimal;
+use schemars::{JsonSchema, Schema, SchemaGenerator, generate::SchemaSettings};
 use serde::Serialize;
+use serde_json::value::RawValue;
 use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
     io::{BufWriter, Write, stdout},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::io::AsyncBufReadExt;
 
 use sqlx::{
     ConnectOptions,
@@ -16,136 +25,589 @@ use sqlx::{
 };
 
 use crate::{
-    cli::{Args, LogLevel, StdoutFormat},
-    sink::{SinkExecutionError, SinkPlaceholder},
+    alert::{Alert, AlertPayload},
+    audit::AuditCheck,
+    cli::{
+        Args, Command, CompareArgs, CompareFormat, Compat, Detail, GraphStatsArgs, InfoFormat,
+        LogLevel, OverlapPolicy, ResupplyPolicy, SchemaFormat, SinkTruncate, SortKey, StdoutFormat,
+    },
+    dialect::{AdapterRegistry, HoldLocations},
+    dump::ScratchDatabase,
+    graph_builder::{GraphBuildError, GraphBuilder, scope_warehouse},
+    output::{FlushingWriter, OutputContext, OutputRegistry},
+    pipe::{PipeError, PipeRequest, PipeResponse},
+    policy::{PolicyFailure, RunOutput},
+    postgres_sink::{PostgresSink, class_template, sink_template, validate_sink},
+    sink::{RunRecord, RunStatus, Sink, SinkDriver, SinkPlaceholder, SinkRate, SinkRunIdentity},
+    state::{Checkpoint, ScopeProgress, StateFile, StateRow},
+    statsd::Statsd,
+    warehouse::StockScope,
 };
 
+mod alert;
+mod audit;
+mod checksum;
 mod cli;
+mod compare;
 mod dialect;
+mod dump;
+mod graph_builder;
+mod graph_stats;
+mod locations;
 mod odoo;
+mod output;
+mod pipe;
+mod policy;
+mod postgres_sink;
 mod product;
+mod pushgateway;
+mod shutdown;
 mod sink;
+mod state;
+mod statsd;
 mod warehouse;
+mod webhook;
+
+/// A decimal in a `jsonl` row: a string by default, or a bare number carrying the value's own
+/// decimal places with `--jsonl-numbers`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum JsonlDecimal {
+    String(String),
+    Number(Box<RawValue>),
+}
+
+impl JsonlDecimal {
+    fn new(value: Decimal, numbers: bool) -> Result<Self, serde_json::Error> {
+        if numbers {
+            Ok(Self::Number(RawValue::from_string(value.to_string())?))
+        } else {
+            Ok(Self::String(value.to_string()))
+        }
+    }
+
+    /// Schema of a decimal written with or without `--jsonl-numbers`.
+    fn schema(numbers: bool) -> Schema {
+        if numbers {
+            schemars::json_schema!({ "type": "number" })
+        } else {
+            schemars::json_schema!({
+                "type": "string",
+                "pattern": "^-?[0-9]+(\\.[0-9]+)?$",
+            })
+        }
+    }
+}
+
+impl JsonSchema for JsonlDecimal {
+    fn schema_name() -> Cow<'static, str> {
+        "JsonlDecimal".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schemars::json_schema!({
+            "anyOf": [Self::schema(false), Self::schema(true)],
+        })
+    }
+}
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct JsonlAvailabilityRow<'a> {
     product_id: i32,
     warehouse_id: i32,
     warehouse_name: &'a str,
-    quantity: String,
-    reserved: String,
-    incoming: String,
-    outgoing: String,
-    buildable: String,
-    free_immediately: String,
-    virtual_available: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location_id: Option<i32>,
+    run_id: &'a str,
+    quantity: JsonlDecimal,
+    reserved: JsonlDecimal,
+    incoming: JsonlDecimal,
+    outgoing: JsonlDecimal,
+    buildable: JsonlDecimal,
+    free_immediately: JsonlDecimal,
+    virtual_available: JsonlDecimal,
+    net_on_order: JsonlDecimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_cost: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    free_weight: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    free_volume: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_velocity: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("enum" = ["A", "B", "C"]))]
+    abc_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days_of_cover: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    soft_reserved: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    free_after_quotes: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incoming_unconfirmed: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_hold: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserved_delivery: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserved_manufacturing: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserved_internal: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incoming_purchase: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incoming_manufacturing: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incoming_internal: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resuppliable: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resupply_lead_days: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kit_sets_available: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buildable_capacity_limited: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fill_rate: Option<JsonlDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oldest_reservation_days: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moves: Option<Vec<JsonlMove<'a>>>,
+}
+
+/// Version of the `jsonl` row layout, bumped when fields are renamed, removed or change type.
+const JSONL_SCHEMA_VERSION: u32 = 1;
+
+/// The `--jsonl-meta` line heading the `jsonl` rows of a warehouse or location.
+#[derive(Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct JsonlMeta<'a> {
+    #[serde(rename = "type")]
+    #[schemars(extend("const" = "meta"))]
+    kind: &'static str,
+    schema_version: u32,
+    tool_version: &'static str,
+    run_id: &'a str,
+    warehouse_id: i32,
+    warehouse_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location_id: Option<i32>,
+    odoo_major: u16,
+    free_formula: &'static str,
+    kit_semantics: &'static str,
+    /// When the run started, in Unix seconds
+    generated_at: u64,
+}
+
+/// A `--jsonl-error-rows` line: a product left out of the rows because its availability could
+/// not be computed.
+#[derive(Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct JsonlErrorRow<'a> {
+    #[serde(rename = "type")]
+    #[schemars(extend("const" = "error"))]
+    kind: &'static str,
+    product_id: i32,
+    warehouse_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location_id: Option<i32>,
+    run_id: &'a str,
+    error: String,
 }
 
-fn write_diagnostic_tree<W: Write>(
+/// A `--warehouse-group` row of the `jsonl` output: availability summed over the group's
+/// warehouses.
+#[derive(Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct JsonlGroupRow<'a> {
+    product_id: i32,
+    warehouse_group: &'a str,
+    warehouse_ids: &'a [i32],
+    run_id: &'a str,
+    quantity: JsonlDecimal,
+    reserved: JsonlDecimal,
+    incoming: JsonlDecimal,
+    outgoing: JsonlDecimal,
+    buildable: JsonlDecimal,
+    free_immediately: JsonlDecimal,
+    virtual_available: JsonlDecimal,
+    net_on_order: JsonlDecimal,
+}
+
+/// One of the open moves listed under `--detail moves`.
+#[derive(Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct JsonlMove<'a> {
+    #[schemars(extend("enum" = ["in", "out"]))]
+    direction: &'static str,
+    reference: &'a str,
+    partner: Option<&'a str>,
+    #[schemars(extend("format" = "date-time"))]
+    date: &'a str,
+    quantity: JsonlDecimal,
+}
+
+impl<'a> JsonlMove<'a> {
+    fn list(moves: &'a [MoveDetail], numbers: bool) -> Result<Vec<Self>, serde_json::Error> {
+        moves
+            .iter()
+            .map(|detail| {
+                Ok(Self {
+                    direction: detail.direction.as_str(),
+                    reference: &detail.reference,
+                    partner: detail.partner.as_deref(),
+                    date: &detail.date,
+                    quantity: JsonlDecimal::new(detail.quantity, numbers)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// `--print-info json`: what this binary supports, for tooling to check before building a
+/// command line.
+fn build_info() -> serde_json::Value {
+    fn names<T: ValueEnum>() -> Vec<String> {
+        T::value_variants()
+            .iter()
+            .filter_map(ValueEnum::to_possible_value)
+            .map(|value| value.get_name().to_owned())
+            .collect()
+    }
+
+    let registry = AdapterRegistry::builtin();
+    let mut odoo_majors: Vec<u16> = registry
+        .majors()
+        .into_iter()
+        .flat_map(|(_, majors)| majors)
+        .collect();
+    odoo_majors.sort_unstable();
+    odoo_majors.dedup();
+    let dialects: Vec<serde_json::Value> = registry
+        .majors()
+        .into_iter()
+        .map(|(name, majors)| serde_json::json!({ "name": name, "odoo_majors": majors }))
+        .collect();
+    let placeholders: Vec<&str> = SinkPlaceholder::ALL
+        .iter()
+        .chain(&SinkPlaceholder::RUN)
+        .map(|placeholder| placeholder.name())
+        .collect();
+
+    serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "odoo_majors": odoo_majors,
+        "dialects": dialects,
+        "stdout_formats": OutputRegistry::builtin().names(),
+        "schema_formats": names::<SchemaFormat>(),
+        "compare_formats": names::<CompareFormat>(),
+        "jsonl_schema_version": JSONL_SCHEMA_VERSION,
+        "sink_drivers": ["postgres"],
+        "sink_placeholders": placeholders,
+        // The crate declares no optional cargo features yet
+        "features": Vec::<&str>::new(),
+    })
+}
+
+/// JSON Schema (draft 2020-12) for the lines of the `jsonl` output, derived from the types
+/// writing them: the `--jsonl-meta` line, the rows, the `--warehouse-group` rows and the
+/// `--jsonl-error-rows` lines.
+fn jsonl_schema(args: &Args) -> serde_json::Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .for_serialize()
+        .into_generator();
+    let lines = [
+        generator.subschema_for::<JsonlMeta<'static>>(),
+        generator.subschema_for::<JsonlAvailabilityRow<'static>>(),
+        generator.subschema_for::<JsonlGroupRow<'static>>(),
+        generator.subschema_for::<JsonlErrorRow<'static>>(),
+    ];
+    let mut definitions = generator.take_definitions(true);
+    let _ = definitions.insert(
+        JsonlDecimal::schema_name().into_owned(),
+        JsonlDecimal::schema(args.jsonl_numbers).into(),
+    );
+
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "odoo-rapid-quant jsonl line",
+        "oneOf": lines,
+        "$defs": definitions,
+    })
+}
+
+/// The `--explain` report: the raw values of the product, what each dependency contributes and
+/// how the contributions are combined, using the same rounding as the computation.
+fn write_explanation<W: Write>(
     writer: &mut W,
-    node: &DiagnosticNode,
+    explanation: &Explanation,
     mode: AvailabilityOutputMode,
-    prefix: &mut Vec<bool>,
-    is_last: bool,
+    formula: FreeFormula,
 ) -> anyhow::Result<()> {
-    let avail = node.availability.output(mode);
-
-    if prefix.is_empty() {
-        // Root node
-        writeln!(
+    let product = explanation.product;
+    let dp = product.dp();
+    writeln!(
+        writer,
+        "Product {} [{}], rounded towards zero to {dp} decimal places",
+        explanation.product_id.0,
+        product.type_label()
+    )?;
+    match &explanation.raw_quant {
+        Some(q) => writeln!(
             writer,
-            "Product {} [{}]",
-            node.product_id.0,
-            node.product.type_label()
-        )?;
-        // Indent for sub-lines: root has no connector, just two spaces
-        let sub_indent = "  ";
-        writeln!(writer, "{sub_indent}computed: {avail}")?;
-        if let Some(ref q) = node.raw_quant {
-            writeln!(
-                writer,
-                "{sub_indent}raw: qty={}, reserved={}, incoming={}, outgoing={}",
-                q.quantity, q.reserved, q.incoming, q.outgoing
-            )?;
-        }
-    } else {
-        // Build prefix string from ancestor bools
-        let mut line = String::new();
-        for &has_sibling in prefix[..prefix.len() - 1].iter() {
-            line.push_str(if has_sibling { "│   " } else { "    " });
-        }
-        let connector = if is_last { "└── " } else { "├── " };
-        let req_qty = node
-            .required_qty
-            .expect("non-root node must have required_qty");
-        writeln!(
-            writer,
-            "{}{}[requires {}] Product {} [{}]",
-            line,
-            connector,
-            req_qty,
-            node.product_id.0,
-            node.product.type_label()
-        )?;
+            "  raw: qty={}, reserved={}, incoming={}, outgoing={}",
+            q.quantity, q.reserved, q.incoming, q.outgoing
+        )?,
+        None => writeln!(writer, "  raw: no quants or open moves")?,
+    }
 
-        // Sub-lines indent: same prefix + continuation for this node's depth
-        let mut sub_indent = line.clone();
-        sub_indent.push_str(if is_last { "    " } else { "│   " });
-        sub_indent.push_str("  ");
-
-        writeln!(writer, "{sub_indent}computed: {avail}")?;
-        if let Some(ref q) = node.raw_quant {
-            writeln!(
-                writer,
-                "{sub_indent}raw: qty={}, reserved={}, incoming={}, outgoing={}",
-                q.quantity, q.reserved, q.incoming, q.outgoing
-            )?;
-        }
-        // Normalized line
-        let qty_norm = mode.project(node.availability.quantity / req_qty);
-        let free_norm = mode.project(node.availability.free_immediately() / req_qty);
-        let virtual_norm = mode.project(node.availability.virtual_available() / req_qty);
+    for dependency in &explanation.dependencies {
         writeln!(
             writer,
-            "{sub_indent}normalized (÷{req_qty}): qty={qty_norm}, free={free_norm}, virtual_available={virtual_norm}"
+            "  dependency Product {} [{}], requires {}:",
+            dependency.product_id.0,
+            dependency.product.type_label(),
+            dependency.required_qty
         )?;
+        writeln!(writer, "    available: {}", dependency.availability)?;
+        match &dependency.terms {
+            Some(terms) => {
+                let terms: Vec<String> = terms
+                    .fields()
+                    .into_iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect();
+                writeln!(
+                    writer,
+                    "    terms (÷{}): {}",
+                    dependency.required_qty,
+                    terms.join(", ")
+                )?;
+            }
+            None => writeln!(writer, "    ignored: required quantity is not positive")?,
+        }
     }
 
-    let child_count = node.children.len();
-    for (i, child) in node.children.iter().enumerate() {
-        let child_is_last = i == child_count - 1;
-        prefix.push(!child_is_last);
-        write_diagnostic_tree(writer, child, mode, prefix, child_is_last)?;
-        let _ = prefix.pop();
+    let rules: &[&str] = match product {
+        Product::Simple(_) => &[
+            "quantity, reserved, incoming, outgoing = raw, rounded",
+            "buildable = free",
+        ],
+        Product::MrpPhantom(_, _) => &[
+            "quantity, incoming, free, virtual_available = min(terms) × BoM quantity, rounded",
+            "reserved = quantity - free",
+            "outgoing = quantity + incoming - virtual_available",
+            "buildable = min(terms.buildable) × BoM quantity",
+        ],
+        Product::MrpNormal(_, _) => &[
+            "quantity, reserved, incoming, outgoing = raw",
+            "buildable = min(terms.buildable) × BoM quantity",
+        ],
+        Product::Commingled(_) => &["every field = sum(terms), rounded"],
+    };
+    if let Product::MrpPhantom(bom_qty, _) | Product::MrpNormal(bom_qty, _) = product {
+        writeln!(writer, "  BoM quantity: {bom_qty}")?;
+    }
+    for rule in rules {
+        writeln!(writer, "  rule: {rule}")?;
+    }
+    if product.is_integral() {
+        writeln!(writer, "  rule: floored to whole units")?;
     }
 
+    writeln!(writer, "  computed: {}", explanation.availability)?;
+    writeln!(
+        writer,
+        "  output: {}",
+        explanation.availability.output(mode, formula)
+    )?;
     Ok(())
 }
 
-fn write_jsonl_row<W: Write>(
+fn write_jsonl_row<W: Write + ?Sized>(
     writer: &mut W,
+    row: &JsonlAvailabilityRow<'_>,
+) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *writer, row)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn jsonl_row<'a>(
     product: ProductId,
-    warehouse: &warehouse::Warehouse,
+    warehouse: &'a warehouse::Warehouse,
+    run_id: &'a str,
     availability: &OutputAvailability,
-) -> anyhow::Result<()> {
-    let row = JsonlAvailabilityRow {
+    metrics: &ProductMetrics,
+    numbers: bool,
+) -> Result<JsonlAvailabilityRow<'a>, serde_json::Error> {
+    let decimal = |value: Decimal| JsonlDecimal::new(value, numbers);
+    let optional = |value: Option<Decimal>| value.map(decimal).transpose();
+
+    Ok(JsonlAvailabilityRow {
         product_id: product.0,
         warehouse_id: warehouse.id.0,
         warehouse_name: &warehouse.name,
-        quantity: availability.quantity.to_string(),
-        reserved: availability.reserved.to_string(),
-        incoming: availability.incoming.to_string(),
-        outgoing: availability.outgoing.to_string(),
-        buildable: availability.buildable.to_string(),
-        free_immediately: availability.free_immediately.to_string(),
-        virtual_available: availability.virtual_available.to_string(),
-    };
+        location_id: warehouse.location_id,
+        run_id,
+        quantity: decimal(availability.quantity)?,
+        reserved: decimal(availability.reserved)?,
+        incoming: decimal(availability.incoming)?,
+        outgoing: decimal(availability.outgoing)?,
+        buildable: decimal(availability.buildable)?,
+        free_immediately: decimal(availability.free_immediately)?,
+        virtual_available: decimal(availability.virtual_available)?,
+        net_on_order: decimal(availability.net_on_order)?,
+        value: optional(metrics.value)?,
+        unit_cost: optional(metrics.unit_cost)?,
+        free_weight: optional(metrics.free_weight)?,
+        free_volume: optional(metrics.free_volume)?,
+        outbound_velocity: optional(metrics.outbound_velocity)?,
+        abc_class: metrics.abc_class.map(|class| class.as_str()),
+        days_of_cover: optional(metrics.days_of_cover)?,
+        soft_reserved: optional(metrics.soft_reserved)?,
+        free_after_quotes: optional(metrics.free_after_quotes)?,
+        incoming_unconfirmed: optional(metrics.incoming_unconfirmed)?,
+        on_hold: optional(metrics.on_hold)?,
+        reserved_delivery: optional(metrics.reserved_delivery)?,
+        reserved_manufacturing: optional(metrics.reserved_manufacturing)?,
+        reserved_internal: optional(metrics.reserved_internal)?,
+        incoming_purchase: optional(metrics.incoming_purchase)?,
+        incoming_manufacturing: optional(metrics.incoming_manufacturing)?,
+        incoming_internal: optional(metrics.incoming_internal)?,
+        resuppliable: optional(metrics.resuppliable)?,
+        resupply_lead_days: metrics.resupply_lead_days,
+        kit_sets_available: optional(metrics.kit_sets_available)?,
+        buildable_capacity_limited: optional(metrics.buildable_capacity_limited)?,
+        fill_rate: optional(metrics.fill_rate)?,
+        oldest_reservation_days: metrics.oldest_reservation_days,
+        moves: None,
+    })
+}
 
-    serde_json::to_writer(&mut *writer, &row)?;
-    writer.write_all(b"\n")?;
+/// Render one row through the `--stdout-template`. Quantities are passed as exact decimal strings,
+/// like the default `jsonl` output.
+fn write_template_row<W: Write>(
+    writer: &mut W,
+    template: &minijinja::Template<'_, '_>,
+    graph: &product::Graph,
+    product: ProductId,
+    warehouse: &warehouse::Warehouse,
+    availability: &OutputAvailability,
+    metrics: &ProductMetrics,
+) -> anyhow::Result<()> {
+    let mut context: Vec<(&str, minijinja::Value)> = vec![
+        ("product_id", product.0.into()),
+        (
+            "product_type",
+            graph
+                .catalogue
+                .get(&product)
+                .map(|product| product.type_label())
+                .into(),
+        ),
+        ("warehouse_id", warehouse.id.0.into()),
+        ("warehouse_name", warehouse.name.as_str().into()),
+        ("location_id", warehouse.location_id.into()),
+        ("quantity", availability.quantity.to_string().into()),
+        ("reserved", availability.reserved.to_string().into()),
+        ("incoming", availability.incoming.to_string().into()),
+        ("outgoing", availability.outgoing.to_string().into()),
+        ("buildable", availability.buildable.to_string().into()),
+        (
+            "free_immediately",
+            availability.free_immediately.to_string().into(),
+        ),
+        (
+            "virtual_available",
+            availability.virtual_available.to_string().into(),
+        ),
+        ("net_on_order", availability.net_on_order.to_string().into()),
+    ];
+    for (name, value) in metrics.fields() {
+        context.push((name, value.into()));
+    }
+
+    let rendered = template
+        .render(minijinja::Value::from_iter(context))
+        .with_context(|| {
+            format!(
+                "failed to render --stdout-template for product_id={}",
+                product.0
+            )
+        })?;
+    writeln!(writer, "{rendered}")?;
     Ok(())
 }
 
+/// Availability per product summed over the warehouses of a `--warehouse-group`.
+#[derive(Debug, Default)]
+struct GroupTotals {
+    products: BTreeMap<ProductId, OutputAvailability>,
+}
+
+impl GroupTotals {
+    fn add(
+        &mut self,
+        graph: &product::Graph,
+        products: &[ProductId],
+        output_mode: AvailabilityOutputMode,
+    ) -> anyhow::Result<()> {
+        for product in products {
+            let output = graph
+                .get(product)
+                .with_context(|| format!("missing availability for product_id={}", product.0))?
+                .output(output_mode, graph.free_formula);
+            let _ = self
+                .products
+                .entry(*product)
+                .and_modify(|total| *total += &output)
+                .or_insert(output);
+        }
+        Ok(())
+    }
+
+    fn write<W: Write>(
+        &self,
+        writer: &mut W,
+        cli: &Args,
+        name: &str,
+        identity: &SinkRunIdentity,
+    ) -> anyhow::Result<()> {
+        for (product, output) in &self.products {
+            match cli.stdout {
+                Some(StdoutFormat::Jsonl) => {
+                    let decimal = |value: Decimal| JsonlDecimal::new(value, cli.jsonl_numbers);
+                    let row = JsonlGroupRow {
+                        product_id: product.0,
+                        warehouse_group: name,
+                        warehouse_ids: &cli.group_warehouses,
+                        run_id: &identity.run_id,
+                        quantity: decimal(output.quantity)?,
+                        reserved: decimal(output.reserved)?,
+                        incoming: decimal(output.incoming)?,
+                        outgoing: decimal(output.outgoing)?,
+                        buildable: decimal(output.buildable)?,
+                        free_immediately: decimal(output.free_immediately)?,
+                        virtual_available: decimal(output.virtual_available)?,
+                        net_on_order: decimal(output.net_on_order)?,
+                    };
+                    serde_json::to_writer(&mut *writer, &row)?;
+                    writer.write_all(b"\n")?;
+                }
+                _ => writeln!(writer, "{product:?}, group {name}: {output}")?,
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
 fn init_tracing(log_level: LogLevel) -> anyhow::Result<()> {
     let env_filter = if std::env::var_os("RUST_LOG").is_some() {
         tracing_subscriber::EnvFilter::try_from_default_env().context("invalid RUST_LOG value")?
@@ -165,124 +627,1811 @@ fn init_tracing(log_level: LogLevel) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let cli = Args::parse();
-    init_tracing(cli.log_level)?;
+/// `--dry-run`: print what the sink phase of a scope would write, and where, without writing.
+/// With `--validate-sink`, the statements are still prepared against the sink.
+async fn dry_run_sink(
+    args: &Args,
+    sink_db_url: &str,
+    graph: &product::Graph,
+    products: &[ProductId],
+) -> anyhow::Result<()> {
+    let warehouse = &graph.warehouse;
+    let sink_options = sink_db_url.parse::<PgConnectOptions>()?;
+    let sink = format!(
+        "{}@{}:{}",
+        sink_options
+            .get_database()
+            .unwrap_or(sink_options.get_username()),
+        sink_options.get_host(),
+        sink_options.get_port()
+    );
+    let scope = warehouse.label();
+
+    if args.validate_sink {
+        let sink_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_with(sink_options)
+            .await?;
+        let template = sink_template(args, &sink_pool, false).await;
+        let outcome = match template {
+            Ok(template) => validate_sink(args, &sink_pool, template.as_ref()).await,
+            Err(err) => Err(err),
+        };
+        sink_pool.close().await;
+        outcome?;
+    }
 
-    let src_pool_options: PgConnectOptions = cli
-        .src_db_url
+    if let (Some(table), Some(truncate)) = (args.sink_table.as_ref(), args.sink_truncate) {
+        match truncate {
+            SinkTruncate::Warehouse => eprintln!(
+                "Dry run: would delete the rows of warehouse {} from {table} at {sink}",
+                warehouse.id.0
+            ),
+            SinkTruncate::Table => eprintln!("Dry run: would truncate {table} at {sink}"),
+        }
+    }
+    let class_rows = products
+        .iter()
+        .filter(|product| class_template(args, graph, product).is_some())
+        .count();
+    let target = match (
+        args.sink_db_stmt.as_ref(),
+        args.sink_table.as_ref(),
+        args.sink_call.as_ref(),
+    ) {
+        (Some(_), _, _) => Some("--sink-db-stmt".to_owned()),
+        (None, Some(table), _) => Some(format!("table {table}")),
+        (None, None, Some(call)) => Some(format!("routine {}", call.routine)),
+        (None, None, None) => None,
+    };
+    if let Some(target) = target {
+        eprintln!(
+            "Dry run: would write {} rows of {scope} to {target} at {sink}",
+            products.len() - class_rows
+        );
+    }
+    if class_rows > 0 {
+        eprintln!(
+            "Dry run: would write {class_rows} rows of {scope} with per-class statements at {sink}"
+        );
+    }
+    if let (Some(table), Some(_)) = (args.sink_table.as_ref(), args.sink_stale_stmt.as_ref()) {
+        eprintln!(
+            "Dry run: would run --sink-stale-stmt for the products of {table} absent from {scope}"
+        );
+    }
+    if let Some(runs_table) = args.sink_runs_table.as_ref() {
+        eprintln!("Dry run: would record the run of {scope} in {runs_table} at {sink}");
+    }
+    Ok(())
+}
+
+/// Write the rows of `products` through `sink`, in chunks of its batch size paced by
+/// `--sink-rate`, committing at each `--checkpoint-every` checkpoint and once at the end.
+async fn write_sink(
+    args: &Args,
+    sink: &mut dyn Sink,
+    products: &[ProductId],
+    mut progress: Option<&mut ScopeProgress<'_>>,
+) -> anyhow::Result<u64> {
+    let mut rate = args.sink_rate.map(SinkRate::new);
+    // Rows written since the last --checkpoint-every commit
+    let mut uncommitted = Vec::new();
+    let mut rows_written = 0;
+
+    sink.begin().await?;
+    for chunk in products.chunks(sink.batch_size().max(1)) {
+        shutdown::check()?;
+        if let Some(rate) = rate.as_mut() {
+            rate.acquire(chunk.len()).await;
+        }
+        rows_written += sink.write_batch(chunk).await?;
+        uncommitted.extend_from_slice(chunk);
+        checkpoint_sink(sink, args, progress.as_deref_mut(), &mut uncommitted).await?;
+    }
+    rows_written += sink.finish(rate.as_mut()).await?;
+    sink.commit().await?;
+
+    Ok(rows_written)
+}
+
+/// With `--checkpoint-every`, once that many rows are written: commit them, record them in the
+/// state file, and carry on in a new unit of work of the sink.
+async fn checkpoint_sink(
+    sink: &mut dyn Sink,
+    args: &Args,
+    progress: Option<&mut ScopeProgress<'_>>,
+    uncommitted: &mut Vec<ProductId>,
+) -> anyhow::Result<()> {
+    let (Some(every), Some(progress)) = (args.checkpoint_every, progress) else {
+        return Ok(());
+    };
+    if uncommitted.len() < every.get() {
+        return Ok(());
+    }
+
+    sink.commit().await?;
+    progress.commit(uncommitted)?;
+    tracing::debug!(rows = uncommitted.len(), "Committed a sink checkpoint");
+    uncommitted.clear();
+
+    sink.begin().await
+}
+
+fn source_options(url: &str) -> anyhow::Result<PgConnectOptions> {
+    Ok(url
         .parse::<PgConnectOptions>()?
-        .log_slow_statements(log::LevelFilter::Warn, Duration::from_secs(2));
+        .log_slow_statements(log::LevelFilter::Warn, Duration::from_secs(2)))
+}
 
-    let src_pool = PgPoolOptions::new()
-        .max_connections(1)
-        .connect_with(src_pool_options)
+/// `compare`: collect both databases concurrently and report the differences between them.
+async fn run_compare(cli: &Args, compare: &CompareArgs) -> anyhow::Result<()> {
+    let Some(scope) = cli.stock_scope() else {
+        anyhow::bail!("compare requires --warehouse or a single --location");
+    };
+
+    let (left, right) = tokio::try_join!(
+        collect_graph(cli, source_options(&compare.left_db_url)?, scope),
+        collect_graph(cli, source_options(&compare.right_db_url)?, scope),
+    )?;
+
+    let output_mode = AvailabilityOutputMode::from_allow_negative(cli.allow_negative);
+    let availability = |collected: Option<(product::Graph, Vec<ProductId>)>| {
+        let mut availability = BTreeMap::new();
+        if let Some((graph, products)) = collected {
+            for product in products {
+                let output = graph
+                    .get(&product)
+                    .with_context(|| format!("missing availability for product_id={}", product.0))?
+                    .output(output_mode, graph.free_formula);
+                let _ = availability.insert(product, output);
+            }
+        }
+        anyhow::Ok(availability)
+    };
+
+    let tolerance = compare.tolerance();
+    let differences = compare::differences(&availability(left)?, &availability(right)?, tolerance);
+
+    let mut writer = BufWriter::new(stdout().lock());
+    for difference in &differences {
+        match compare.format {
+            CompareFormat::Human => writeln!(writer, "{difference}")?,
+            CompareFormat::Jsonl => {
+                serde_json::to_writer(&mut writer, difference)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+    }
+    writer.flush()?;
+
+    if !differences.is_empty() {
+        anyhow::bail!(
+            "{} differences above the tolerance of {tolerance}",
+            differences.len()
+        );
+    }
+
+    tracing::info!("No differences above the tolerance of {tolerance}");
+    Ok(())
+}
+
+async fn run_graph_stats(cli: &Args, stats: &GraphStatsArgs) -> anyhow::Result<()> {
+    let (Some(scope), Some(src_db_url)) = (cli.stock_scope(), cli.src_db_url.as_deref()) else {
+        anyhow::bail!("graph-stats requires --warehouse or a single --location, and --src-db-url");
+    };
+
+    let Some((graph, _)) = collect_graph(cli, source_options(src_db_url)?, scope).await? else {
+        return Ok(());
+    };
+
+    let report =
+        graph_stats::GraphStats::new(&graph.graph, &graph.catalogue, &graph.raw_quants, stats.top);
+    let mut writer = BufWriter::new(stdout().lock());
+    write!(writer, "{report}")?;
+    writer.flush()?;
+    Ok(())
+}
+
+async fn run_detect_cycles(cli: &Args) -> anyhow::Result<()> {
+    let (Some(scope), Some(src_db_url)) = (cli.stock_scope(), cli.src_db_url.as_deref()) else {
+        anyhow::bail!(
+            "detect-cycles requires --warehouse or a single --location, and --src-db-url"
+        );
+    };
+
+    let mut graph = connect_graph(cli, source_options(src_db_url)?, scope).await?;
+    graph.collect_relations().await?;
+
+    let cycles = product::cycles(&graph.graph);
+    let involved: Vec<ProductId> = cycles.iter().flatten().copied().collect();
+    let references = graph
+        .adapter
+        .product_references(&graph.pool, &involved, cli.lang.as_deref())
         .await?;
+    let describe = |product: &ProductId| match references.get(product) {
+        Some(reference) => format!("Product {} {reference}", product.0),
+        None => format!("Product {}", product.0),
+    };
 
-    let detected = odoo::OdooVersion::detect_from_database(&src_pool).await?;
-    let adapter = detected.dialect(&src_pool).await?;
-    tracing::info!("Using adapter for Odoo major {}.", adapter.major());
+    let mut writer = BufWriter::new(stdout().lock());
+    for cycle in &cycles {
+        writeln!(writer, "Cycle of {} products:", cycle.len())?;
+        writeln!(writer, "  {}", describe(&cycle[0]))?;
+        for product in cycle.iter().skip(1).chain(&cycle[..1]) {
+            writeln!(writer, "  uses {}", describe(product))?;
+        }
+    }
+    writer.flush()?;
 
-    let warehouse = adapter.warehouse(&src_pool, cli.warehouse).await?;
+    if !cycles.is_empty() {
+        anyhow::bail!("{} BoM/commingled cycles found", cycles.len());
+    }
 
-    let mut graph = product::Graph::new(src_pool, warehouse.clone(), adapter).await?;
+    tracing::info!("No BoM/commingled cycles found");
+    Ok(())
+}
 
-    let requested_products: Vec<ProductId> = cli.product.iter().copied().map(ProductId).collect();
+async fn run_check_bom_lines(cli: &Args) -> anyhow::Result<()> {
+    let (Some(scope), Some(src_db_url)) = (cli.stock_scope(), cli.src_db_url.as_deref()) else {
+        anyhow::bail!(
+            "check-bom-lines requires --warehouse or a single --location, and --src-db-url"
+        );
+    };
 
-    if cli.stdout == Some(StdoutFormat::Diagnose) && requested_products.len() != 1 {
-        anyhow::bail!("--stdout diagnose requires exactly one --product <ID>");
+    let graph = connect_graph(cli, source_options(src_db_url)?, scope).await?;
+    let lines = graph
+        .adapter
+        .dropped_bom_lines(&graph.pool, graph.bom_company())
+        .await?;
+
+    let mut writer = BufWriter::new(stdout().lock());
+    for line in &lines {
+        writeln!(writer, "{line}")?;
     }
+    writer.flush()?;
 
-    graph.collect(&requested_products).await?;
+    if !lines.is_empty() {
+        anyhow::bail!(
+            "{} BoM lines with archived or non-storable components",
+            lines.len()
+        );
+    }
 
-    let products = if requested_products.is_empty() {
-        graph.computed_products()
-    } else {
-        requested_products
+    tracing::info!("No BoM lines with archived or non-storable components");
+    Ok(())
+}
+
+async fn run_locations(cli: &Args) -> anyhow::Result<()> {
+    let (Some(scope), Some(src_db_url)) = (cli.stock_scope(), cli.src_db_url.as_deref()) else {
+        anyhow::bail!("locations requires --warehouse or a single --location, and --src-db-url");
+    };
+
+    let graph = connect_graph(cli, source_options(src_db_url)?, scope).await?;
+    let locations = graph
+        .adapter
+        .locations(&graph.pool, &graph.warehouse.location_path)
+        .await?;
+
+    let mut writer = BufWriter::new(stdout().lock());
+    locations::write_tree(&mut writer, &locations)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// `--pipe`: build the product graph once, then answer the requests read from stdin, one JSON
+/// line each, until stdin closes. Each request recollects the stock of its products only.
+async fn run_pipe(cli: &Args) -> anyhow::Result<()> {
+    let (Some(scope), Some(src_db_url)) = (cli.stock_scope(), cli.src_db_url.as_deref()) else {
+        anyhow::bail!("--pipe requires --warehouse or a single --location, and --src-db-url");
     };
 
+    let mut graph = connect_graph(cli, source_options(src_db_url)?, scope).await?;
+    graph.collect_relations().await?;
+    let started_warehouse = graph.warehouse.clone();
+    let run_id = cli
+        .run_id
+        .clone()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
     let output_mode = AvailabilityOutputMode::from_allow_negative(cli.allow_negative);
+    tracing::info!(run_id, "Reading requests from stdin");
 
-    if let Some(stdout_format) = cli.stdout {
-        let lock = stdout().lock();
-        let mut writer = BufWriter::new(lock);
-
-        match stdout_format {
-            StdoutFormat::Diagnose => {
-                let root_id = products[0];
-                let tree = graph
-                    .diagnostic_tree(root_id, None)
-                    .with_context(|| format!("product {} not found in graph", root_id.0))?;
-                write_diagnostic_tree(&mut writer, &tree, output_mode, &mut vec![], true)?;
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match PipeRequest::parse(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                write_pipe_line(&PipeError {
+                    id: None,
+                    error: err.to_string(),
+                })?;
+                continue;
             }
-            _ => {
-                for product in &products {
-                    let availability = graph.get(product).with_context(|| {
-                        format!("missing availability for product_id={}", product.0)
-                    })?;
-                    let output = availability.output(output_mode);
-                    match stdout_format {
-                        StdoutFormat::Human => {
-                            writeln!(writer, "{:?}, {}: {}", product, warehouse.name, output)?;
-                        }
-                        StdoutFormat::Jsonl => {
-                            write_jsonl_row(&mut writer, *product, &warehouse, &output)?;
-                        }
-                        StdoutFormat::Diagnose => unreachable!(),
-                    }
+        };
+
+        match pipe_collect(cli, &mut graph, &started_warehouse, &request).await {
+            Ok(()) => {
+                let mut response = PipeResponse {
+                    id: request.id.as_ref(),
+                    rows: Vec::new(),
+                    missing: Vec::new(),
+                };
+                for product in request.products() {
+                    let Some(availability) = graph.get(&product) else {
+                        response.missing.push(product.0);
+                        continue;
+                    };
+                    response.rows.push(jsonl_row(
+                        product,
+                        &graph.warehouse,
+                        &run_id,
+                        &availability.output(output_mode, graph.free_formula),
+                        &graph.metrics(&product, output_mode),
+                        cli.jsonl_numbers,
+                    )?);
                 }
+                write_pipe_line(&response)?;
             }
+            // A failed request is answered, the next one may well succeed
+            Err(err) => write_pipe_line(&PipeError {
+                id: request.id.as_ref(),
+                error: format!("{err:#}"),
+            })?,
         }
     }
+    Ok(())
+}
 
-    if let Some(sink_stmt_template) = cli.sink_db_stmt.as_ref() {
-        let sink_db_url = cli
-            .sink_db_url
-            .as_deref()
-            .expect("clap requires --sink-db-url when --sink-db-stmt is set");
+/// Recollect the stock of the products of a `--pipe` request, in its warehouse if it names one.
+async fn pipe_collect(
+    cli: &Args,
+    graph: &mut product::Graph,
+    started_warehouse: &warehouse::Warehouse,
+    request: &PipeRequest,
+) -> anyhow::Result<()> {
+    let warehouse = match request.warehouse_id {
+        Some(warehouse_id)
+            if warehouse_id != started_warehouse.id.0
+                || started_warehouse.location_id.is_some() =>
+        {
+            scope_warehouse(
+                graph.adapter.as_ref(),
+                &graph.pool,
+                StockScope::Warehouse(warehouse_id),
+                cli.warehouse_scope(),
+                cli.lang.as_deref(),
+            )
+            .await?
+        }
+        _ => started_warehouse.clone(),
+    };
+    graph.requested_products = request.products();
+    graph.rescope(warehouse).await?;
+    Ok(())
+}
 
-        let sink_pool = PgPoolOptions::new()
-            .max_connections(1)
-            .connect(sink_db_url)
+/// Write one `--pipe` answer and flush it, as the client waits for it.
+fn write_pipe_line<T: Serialize>(line: &T) -> anyhow::Result<()> {
+    let mut writer = stdout().lock();
+    serde_json::to_writer(&mut writer, line)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+async fn run_audit(cli: &Args) -> anyhow::Result<()> {
+    let (Some(scope), Some(src_db_url)) = (cli.stock_scope(), cli.src_db_url.as_deref()) else {
+        anyhow::bail!("audit requires --warehouse or a single --location, and --src-db-url");
+    };
+
+    let graph = connect_graph(cli, source_options(src_db_url)?, scope).await?;
+
+    let mut writer = BufWriter::new(stdout().lock());
+    let mut found = 0;
+    for check in AuditCheck::ALL {
+        let anomalies = graph
+            .adapter
+            .audit(&graph.pool, check, &graph.warehouse.location_path)
             .await?;
+        tracing::info!(
+            check = check.as_str(),
+            anomalies = anomalies.len(),
+            "Audited"
+        );
+        for anomaly in &anomalies {
+            serde_json::to_writer(&mut writer, anomaly)?;
+            writer.write_all(b"\n")?;
+        }
+        found += anomalies.len();
+    }
+    writer.flush()?;
+
+    if found > 0 {
+        anyhow::bail!("{found} anomalies found");
+    }
+
+    tracing::info!("No anomalies found");
+    Ok(())
+}
+
+/// A graph builder for the source database with the collection options from the command line.
+fn graph_builder(
+    args: &Args,
+    src_pool_options: PgConnectOptions,
+    scope: StockScope,
+) -> GraphBuilder<'_> {
+    GraphBuilder::new(src_pool_options, AdapterRegistry::builtin())
+        .scope(scope)
+        .warehouse_scope(args.warehouse_scope())
+        .lang(args.lang.clone())
+        .dialect(args.dialect.clone())
+        .configure(|graph| {
+            graph.source_retries = args.source_retries;
+            graph.with_valuation = args.with_valuation;
+            graph.with_weight_volume = args.with_weight_volume;
+            graph.with_velocity = args.with_velocity;
+            graph.with_days_of_cover = args.with_days_of_cover;
+            graph.with_fill_rate = args.with_fill_rate;
+            graph.with_quotations = args.with_quotations;
+            graph.with_draft_purchases = args.include_draft_po;
+            graph.with_on_hold = args.with_on_hold;
+            graph.with_resupply = args.resupply.is_some();
+            graph.include_resupply = args.resupply == Some(ResupplyPolicy::Include);
+            graph.component_warehouses = args.components_from.clone();
+            graph.buildable_from_alternatives = args.buildable_from_alternatives;
+            graph.capacity_horizon = args.capacity_horizon;
+            graph.with_reserved_breakdown = args.with_reserved_breakdown;
+            graph.with_reservation_age = args.with_reservation_age;
+            graph.with_incoming_breakdown = args.with_incoming_breakdown;
+            graph.quant_options.hold_locations = HoldLocations {
+                usages: args.hold_location_usage.clone(),
+                name_patterns: args.hold_location_name.clone(),
+                ids: args.hold_location.clone(),
+            };
+            graph.velocity_window = args.velocity_window;
+            graph.quant_options.move_states = args.move_states.clone();
+            graph.quant_options.include_transit = args.include_transit;
+            graph.quant_options.include_dropship = args.include_dropship;
+            graph.quant_options.location_usages = args.include_usages.clone();
+            graph.quant_options.ignore_negative_quants = args.ignore_negative_quants;
+            graph.quant_options.odoo_forecast = args.compat == Some(Compat::OdooForecast);
+            graph.free_formula = args.free_formula;
+            graph.kit_semantics = args.kit_semantics;
+            graph.company_scoped = args.company_scoped;
+            for phase in CollectPhase::ALL {
+                let timeout = args
+                    .phase_timeout
+                    .iter()
+                    .rev()
+                    .find(|phase_timeout| phase_timeout.phase == phase)
+                    .map(|phase_timeout| phase_timeout.timeout)
+                    .or(args.query_timeout);
+                if let Some(timeout) = timeout {
+                    let _ = graph.query_timeouts.insert(phase, timeout);
+                }
+            }
+        })
+}
+
+/// Connect to a source database and set up a graph with the collection options, before any
+/// collection.
+async fn connect_graph(
+    args: &Args,
+    src_pool_options: PgConnectOptions,
+    scope: StockScope,
+) -> anyhow::Result<product::Graph> {
+    Ok(graph_builder(args, src_pool_options, scope).build().await?)
+}
+
+/// Connect to a source database and compute availability for the requested products, or `None`
+/// when the filters match no products.
+async fn collect_graph(
+    args: &Args,
+    src_pool_options: PgConnectOptions,
+    scope: StockScope,
+) -> anyhow::Result<Option<(product::Graph, Vec<ProductId>)>> {
+    let requested_products: Vec<ProductId> = match args.explain {
+        Some(product_id) => vec![ProductId(product_id)],
+        None => args.product_ids().into_iter().map(ProductId).collect(),
+    };
+
+    // --attribute-value can only narrow the products down to one after connecting
+    let diagnose = args.stdout == Some(StdoutFormat::Diagnose);
+    if diagnose
+        && (requested_products.len() > 1
+            || (requested_products.is_empty()
+                && args.attribute_value.is_empty()
+                && args.product_where.is_none()))
+    {
+        anyhow::bail!("--stdout diagnose requires exactly one --product <ID>");
+    }
+
+    let graph = match graph_builder(args, src_pool_options, scope)
+        .products(requested_products)
+        .attribute_values(args.attribute_value.clone())
+        .product_where(args.product_where.clone())
+        .collect()
+        .await
+    {
+        Ok(graph) => graph,
+        Err(GraphBuildError::NoMatchingProducts) => {
+            tracing::warn!("No products match the --attribute-value or --product-where filters");
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if diagnose && graph.requested_products.len() != 1 {
+        anyhow::bail!("--stdout diagnose requires exactly one --product <ID>");
+    }
+
+    let products = output_products(args, &graph);
+    Ok(Some((graph, products)))
+}
 
-        let mut tx = sink_pool.begin().await?;
+/// The products to print and sink: those requested, or every computed one, without the
+/// `--exclude-product` ones, which still count as components. Only the rows passing the
+/// `--only-below-*`/`--only-above-*` thresholds are kept, ordered by `--sort-by` and cut to
+/// `--limit`, if given.
+fn output_products(args: &Args, graph: &product::Graph) -> Vec<ProductId> {
+    let mut products = if graph.requested_products.is_empty() {
+        graph.computed_products()
+    } else {
+        graph.requested_products.clone()
+    };
+    products.retain(|product| {
+        !args.exclude_product.contains(&product.0) && !graph.compute_errors.contains_key(product)
+    });
+    let output_mode = AvailabilityOutputMode::from_allow_negative(args.allow_negative);
+    arrange_products(&mut products, args, |product| {
+        graph
+            .get(product)
+            .map(|availability| availability.output(output_mode, graph.free_formula))
+    });
+    products
+}
+
+/// Keep the `products` whose published availability, from `output`, passes the thresholds,
+/// ordered by `--sort-by` and cut to `--limit`. Products without availability are kept.
+fn arrange_products(
+    products: &mut Vec<ProductId>,
+    args: &Args,
+    output: impl Fn(&ProductId) -> Option<OutputAvailability>,
+) {
+    products.retain(|product| output(product).is_none_or(|output| args.passes_thresholds(&output)));
+    if let Some(sort_by) = args.sort_by {
+        sort_products(products, sort_by, args.desc, &output);
+    }
+    if let Some(limit) = args.limit {
+        products.truncate(limit.get());
+    }
+}
+
+/// Keep only the rows of `products` whose values changed since they were recorded in `state`,
+/// returning their new values to record once written.
+fn retain_changed(
+    state: &StateFile,
+    scope: &str,
+    graph: &product::Graph,
+    products: &mut Vec<ProductId>,
+    output_mode: AvailabilityOutputMode,
+) -> HashMap<ProductId, StateRow> {
+    let mut changed = HashMap::new();
+    let unchanged = products.len();
+    products.retain(|product| {
+        let Some(availability) = graph.get(product) else {
+            return true;
+        };
+        let row = StateRow {
+            availability: availability.output(output_mode, graph.free_formula),
+            metrics: graph
+                .metrics(product, output_mode)
+                .fields()
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect(),
+        };
+        if !state.changed(scope, *product, &row) {
+            return false;
+        }
+        let _ = changed.insert(*product, row);
+        true
+    });
+    tracing::info!(
+        scope,
+        changed = products.len(),
+        unchanged = unchanged - products.len(),
+        "Compared rows with the state file"
+    );
+    changed
+}
+
+/// What writing one stock scope got through.
+#[derive(Debug, Default)]
+struct ScopeWritten {
+    rows: u64,
+    /// Products whose published `free_immediately` dropped to zero or below since the previous
+    /// run, known with `--state-file` only
+    newly_out_of_stock: Vec<ProductId>,
+}
+
+/// Write one stock scope; with `--state-file`, only its changed rows not committed yet by the run
+/// being resumed, recording them in the state file as they are written.
+async fn write_tracked_scope(
+    cli: &Args,
+    graph: &product::Graph,
+    products: &mut Vec<ProductId>,
+    state: Option<&mut StateFile>,
+    identity: &SinkRunIdentity,
+    template_env: &minijinja::Environment<'_>,
+    started_at: SystemTime,
+) -> anyhow::Result<ScopeWritten> {
+    let (Some(state), Some(path)) = (state, cli.state_file.as_deref()) else {
+        let rows = write_scope(
+            cli,
+            graph,
+            products,
+            identity,
+            template_env,
+            started_at,
+            None,
+        )
+        .await?;
+        return Ok(ScopeWritten {
+            rows,
+            ..ScopeWritten::default()
+        });
+    };
+
+    let scope = state::scope_key(&graph.warehouse);
+    if let Some(checkpoint) = state.checkpoint.as_ref() {
+        if checkpoint.is_completed(&scope) {
+            tracing::info!(scope, "Skipping a scope written by the resumed run");
+            products.clear();
+            return Ok(ScopeWritten::default());
+        }
+        products.retain(|product| !checkpoint.is_committed(&scope, *product));
+    }
+    let output_mode = AvailabilityOutputMode::from_allow_negative(cli.allow_negative);
+    let pending = retain_changed(state, &scope, graph, products, output_mode);
+
+    // Only changed rows can cross a threshold, and only rows emitted before have a previous value
+    let thresholds = cli.alert_thresholds();
+    let mut alerts: Vec<Alert> = pending
+        .iter()
+        .filter_map(|(product, row)| {
+            let previous = state.previous(&scope, *product)?;
+            Some(thresholds.crossings(*product, &previous.availability, &row.availability))
+        })
+        .flatten()
+        .collect();
+    alerts.sort_by_key(|alert| alert.product_id);
+    let mut newly_out_of_stock: Vec<ProductId> = pending
+        .iter()
+        .filter(|(product, row)| {
+            row.availability.free_immediately <= Decimal::ZERO
+                && state
+                    .previous(&scope, **product)
+                    .is_some_and(|previous| previous.availability.free_immediately > Decimal::ZERO)
+        })
+        .map(|(product, _)| *product)
+        .collect();
+    newly_out_of_stock.sort_unstable();
 
-        for product in &products {
+    let mut progress = ScopeProgress::new(state, path, scope, pending);
+    let rows = write_scope(
+        cli,
+        graph,
+        products,
+        identity,
+        template_env,
+        started_at,
+        Some(&mut progress),
+    )
+    .await?;
+
+    // A rehearsal leaves the state file as it was, for the real run to emit the same rows
+    if cli.dry_run {
+        if cli.alert_webhook.is_some() && !alerts.is_empty() {
+            eprintln!(
+                "Dry run: would send {} threshold alerts to the --alert-webhook",
+                alerts.len()
+            );
+        }
+        return Ok(ScopeWritten {
+            rows,
+            newly_out_of_stock,
+        });
+    }
+
+    // Sent before the rows are recorded, so a failed alert is raised again by the next run
+    if let Some(url) = cli.alert_webhook.as_deref().filter(|_| !alerts.is_empty()) {
+        let warehouse = &graph.warehouse;
+        webhook::post_json(
+            url,
+            &AlertPayload {
+                run_id: &identity.run_id,
+                warehouse_id: warehouse.id.0,
+                warehouse_name: &warehouse.name,
+                location_id: warehouse.location_id,
+                alerts: &alerts,
+            },
+        )
+        .await?;
+        tracing::info!(alerts = alerts.len(), "Sent threshold alerts");
+    }
+    progress.complete()?;
+    Ok(ScopeWritten {
+        rows,
+        newly_out_of_stock,
+    })
+}
+
+/// Sort `products` by the published value of `sort_by`, ties by product id. Products without
+/// availability come first, or last with `desc`.
+fn sort_products(
+    products: &mut [ProductId],
+    sort_by: SortKey,
+    desc: bool,
+    output: impl Fn(&ProductId) -> Option<OutputAvailability>,
+) {
+    let keys: HashMap<ProductId, Option<Decimal>> = products
+        .iter()
+        .map(|product| {
+            let output = output(product);
+            let key = match sort_by {
+                SortKey::Free => output.map(|output| output.free_immediately),
+                SortKey::Quantity => output.map(|output| output.quantity),
+                SortKey::Virtual => output.map(|output| output.virtual_available),
+                SortKey::Product => Some(Decimal::from(product.0)),
+            };
+            (*product, key)
+        })
+        .collect();
+
+    products.sort_by(|left, right| {
+        let order = keys[left].cmp(&keys[right]);
+        let order = if desc { order.reverse() } else { order };
+        order.then(left.cmp(right))
+    });
+}
+
+/// Print and sink the availability computed for one stock scope, returning the rows written to
+/// the sink.
+async fn write_scope(
+    cli: &Args,
+    graph: &product::Graph,
+    products: &[ProductId],
+    identity: &SinkRunIdentity,
+    template_env: &minijinja::Environment<'_>,
+    started_at: SystemTime,
+    progress: Option<&mut ScopeProgress<'_>>,
+) -> anyhow::Result<u64> {
+    let warehouse = graph.warehouse.clone();
+
+    let output_mode = AvailabilityOutputMode::from_allow_negative(cli.allow_negative);
+
+    if let Some(product_id) = cli.explain {
+        let explanation = graph
+            .explain(ProductId(product_id))
+            .with_context(|| format!("product {product_id} not found in graph"))?;
+        let mut writer = BufWriter::new(stdout().lock());
+        write_explanation(&mut writer, &explanation, output_mode, graph.free_formula)?;
+        writer.flush()?;
+        return Ok(0);
+    }
+
+    if let Some(stdout_format) = cli.stdout.filter(|_| !cli.group_only) {
+        let registry = OutputRegistry::builtin();
+        let output = registry.get(stdout_format.as_str())?;
+        let moves = match cli.detail {
+            Some(Detail::Moves) if output.detail_rows() => Some(
+                graph
+                    .adapter
+                    .open_moves(
+                        &graph.pool,
+                        warehouse.stock_location_path(graph.quant_options.odoo_forecast),
+                        products,
+                        &graph.quant_options,
+                    )
+                    .await?,
+            ),
+            _ => None,
+        };
+        let context = OutputContext {
+            cli,
+            graph,
+            products,
+            identity,
+            started_at,
+            output_mode,
+            moves: moves.as_ref(),
+        };
+        let mut writer = FlushingWriter::new(stdout().lock(), cli.flush_every);
+        output.write(&mut writer, &context).await?;
+        writer.flush()?;
+    }
+
+    if cli.stdout_template.is_some() {
+        let template = template_env.get_template("row")?;
+        let mut writer = FlushingWriter::new(stdout().lock(), cli.flush_every);
+
+        for product in products {
             let availability = graph
                 .get(product)
                 .with_context(|| format!("missing availability for product_id={}", product.0))?;
-            let output = availability.output(output_mode);
-
-            let mut query = sqlx::query(&sink_stmt_template.sql);
-            for placeholder in &sink_stmt_template.placeholders {
-                query = match placeholder {
-                    SinkPlaceholder::ProductId => query.bind(product.0),
-                    SinkPlaceholder::WarehouseId => query.bind(warehouse.id.0),
-                    SinkPlaceholder::Quantity => query.bind(output.quantity),
-                    SinkPlaceholder::Reserved => query.bind(output.reserved),
-                    SinkPlaceholder::Incoming => query.bind(output.incoming),
-                    SinkPlaceholder::Outgoing => query.bind(output.outgoing),
-                    SinkPlaceholder::Buildable => query.bind(output.buildable),
-                    SinkPlaceholder::FreeImmediately => query.bind(output.free_immediately),
-                    SinkPlaceholder::VirtualAvailable => query.bind(output.virtual_available),
-                };
+            let output = availability.output(output_mode, graph.free_formula);
+            let metrics = graph.metrics(product, output_mode);
+            write_template_row(
+                &mut writer,
+                &template,
+                graph,
+                *product,
+                &warehouse,
+                &output,
+                &metrics,
+            )?;
+        }
+        writer.flush()?;
+    }
+
+    if let Some(sink_db_url) = cli.sink_db_url.as_deref() {
+        if cli.dry_run {
+            dry_run_sink(cli, sink_db_url, graph, products).await?;
+            return Ok(0);
+        }
+        let sink_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(sink_db_url)
+            .await?;
+
+        let outcome = match PostgresSink::new(cli, &sink_pool, graph, output_mode, identity).await {
+            Ok(mut sink) => write_sink(cli, &mut sink, products, progress).await,
+            Err(err) => Err(err),
+        };
+
+        // Waits for the rollback of a failed or interrupted transaction to go through
+        sink_pool.close().await;
+        return outcome;
+    }
+
+    Ok(0)
+}
+
+/// A warehouse or location being worked on, for its `--sink-runs-table` record.
+#[derive(Debug)]
+struct ScopeRun {
+    /// Unknown until a location's warehouse is looked up
+    warehouse_id: Option<i32>,
+    products_computed: usize,
+}
+
+impl ScopeRun {
+    fn new(scope: StockScope) -> Self {
+        Self {
+            warehouse_id: match scope {
+                StockScope::Warehouse(id) => Some(id),
+                StockScope::Location(_) => None,
+            },
+            products_computed: 0,
+        }
+    }
+}
+
+/// Record how a scope ended in the `--sink-runs-table`, with the rows it wrote when it succeeded.
+/// A record that cannot be inserted is logged; it does not change how the scope ended.
+async fn record_scope_run(
+    cli: &Args,
+    identity: &SinkRunIdentity,
+    started_at: SystemTime,
+    scope: ScopeRun,
+    rows_written: Option<u64>,
+) {
+    let (Some(sink_db_url), Some(runs_table)) =
+        (cli.sink_db_url.as_deref(), cli.sink_runs_table.as_ref())
+    else {
+        return;
+    };
+    if cli.dry_run || cli.explain.is_some() {
+        return;
+    }
+    let Some(warehouse_id) = scope.warehouse_id else {
+        tracing::error!(
+            "Cannot record the run in {runs_table}: it failed before the warehouse of its location was known"
+        );
+        return;
+    };
+
+    let record = RunRecord {
+        started_at,
+        finished_at: SystemTime::now(),
+        warehouse_id,
+        products_computed: scope.products_computed as i64,
+        rows_written: rows_written.unwrap_or_default() as i64,
+        tool_version: identity.tool_version,
+        run_id: identity.run_id.clone(),
+        status: match (rows_written, shutdown::requested()) {
+            (Some(_), _) => RunStatus::Success,
+            (None, Some(_)) => RunStatus::Interrupted,
+            (None, None) => RunStatus::Failed,
+        },
+    };
+    let inserted = async {
+        let sink_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(sink_db_url)
+            .await?;
+        let inserted = record.insert(&sink_pool, runs_table).await;
+        sink_pool.close().await;
+        inserted
+    }
+    .await;
+    if let Err(err) = inserted {
+        tracing::error!(
+            warehouse_id,
+            "Failed to record the run in {runs_table}: {err}"
+        );
+    }
+}
+
+/// Check the requested scopes for stock counted in more than one of them, returning the indexes
+/// of the scopes to leave out of the `--warehouse-group` rows.
+fn overlapping_scopes(
+    args: &Args,
+    warehouses: &[warehouse::Warehouse],
+) -> anyhow::Result<HashSet<usize>> {
+    let mut left_out = HashSet::new();
+    for (outer, inner) in warehouse::nested_scopes(warehouses) {
+        let (outer_label, inner_label) = (warehouses[outer].label(), warehouses[inner].label());
+        match args.overlapping_scopes {
+            OverlapPolicy::Count => tracing::warn!(
+                "The stock of {inner_label} is also under {outer_label}; --warehouse-group rows count it twice, pass --overlapping-scopes dedupe to count it once"
+            ),
+            OverlapPolicy::Dedupe => {
+                tracing::info!(
+                    "The stock of {inner_label} is also under {outer_label}; only counting it there in --warehouse-group rows"
+                );
+                let _ = left_out.insert(inner);
             }
+            OverlapPolicy::Error => anyhow::bail!(
+                "the stock of {inner_label} is also under {outer_label}, and --overlapping-scopes error is set"
+            ),
+        }
+    }
+    Ok(left_out)
+}
 
-            let _ =
-                query
-                    .execute(&mut *tx)
-                    .await
-                    .map_err(|source| SinkExecutionError::Execute {
-                        product_id: product.0,
-                        warehouse_id: warehouse.id.0,
-                        source,
-                    })?;
+/// The source tables read by a run with the flags of `args`, beyond those always read.
+fn checksum_tables(args: &Args) -> Vec<&'static str> {
+    let mut tables = Vec::new();
+    if args.with_quotations {
+        tables.extend(["sale_order", "sale_order_line"]);
+    }
+    if args.include_draft_po {
+        tables.extend(["purchase_order", "purchase_order_line"]);
+    }
+    if !args.include_dropship {
+        tables.extend(["ir_model_data", "stock_picking_type", "stock_rule"]);
+    }
+    if args.with_reserved_breakdown || args.with_incoming_breakdown || args.include_draft_po {
+        tables.push("stock_picking_type");
+    }
+    if args.with_valuation {
+        tables.push("stock_valuation_layer");
+    }
+    if args.resupply.is_some() {
+        tables.extend([
+            "stock_rule",
+            "stock_location_route",
+            "stock_wh_resupply_table",
+        ]);
+    }
+    if args.capacity_horizon.is_some() {
+        tables.extend([
+            "mrp_routing_workcenter",
+            "mrp_workcenter",
+            "resource_calendar",
+            "resource_calendar_attendance",
+        ]);
+    }
+    if !args.attribute_value.is_empty() {
+        tables.extend(["product_attribute", "product_attribute_value"]);
+    }
+    if args.group_by == Some(cli::GroupBy::Category) {
+        tables.push("product_category");
+    }
+    if args.detail == Some(Detail::Moves) {
+        tables.extend(["res_partner", "stock_picking"]);
+    }
+    tables.sort_unstable();
+    tables.dedup();
+    tables
+}
+
+/// The input checksum of each stock scope, by state file key.
+async fn input_checksums(
+    args: &Args,
+    src_pool_options: &PgConnectOptions,
+    identity: &SinkRunIdentity,
+    scopes: &[StockScope],
+) -> anyhow::Result<HashMap<String, String>> {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_with(src_pool_options.clone())
+        .await?;
+    let fingerprint = checksum::source_fingerprint(&pool, &checksum_tables(args)).await;
+    pool.close().await;
+    let source = format!("{}|{}", identity.source_db, fingerprint?);
+    let options = args.checksum_options();
+
+    Ok(scopes
+        .iter()
+        .map(|scope| {
+            let key = state::stock_scope_key(*scope);
+            let checksum = checksum::input_checksum(&source, &key, &options);
+            (key, checksum)
+        })
+        .collect())
+}
+
+/// Record the input checksum of a scope written successfully in the state file.
+fn record_inputs(
+    state: Option<&mut StateFile>,
+    checksums: &mut HashMap<String, String>,
+    graph: &product::Graph,
+) {
+    let scope = state::scope_key(&graph.warehouse);
+    if let (Some(state), Some(checksum)) = (state, checksums.remove(&scope)) {
+        state.record_inputs(&scope, checksum);
+    }
+}
+
+/// Send the collection phase and write timings of a scope, and its product and row counts.
+fn report_scope(
+    statsd: &Statsd,
+    graph: &product::Graph,
+    products: &[ProductId],
+    written: &ScopeWritten,
+    write_duration: Duration,
+) {
+    for (phase, duration) in &graph.phase_timings {
+        statsd.timing(&format!("collect.{}", phase.as_str()), *duration);
+    }
+    statsd.timing("write", write_duration);
+    statsd.count("products", products.len() as u64);
+    statsd.count("rows_written", written.rows);
+}
+
+/// What a run got through, logged when it ends, whether it finished or not.
+#[derive(Debug, Default)]
+struct RunSummary {
+    scopes: usize,
+    /// Products computed for output, before `--state-file` leaves out the unchanged ones
+    computed: usize,
+    products: usize,
+    rows_written: u64,
+    newly_out_of_stock: Vec<ProductId>,
+    /// Requested products without availability, per scope, for `--fail-on-missing`
+    missing: Vec<i32>,
+    /// Products whose availability could not be computed, with the scope they were left out of
+    compute_errors: Vec<(String, ComputeError)>,
+}
+
+impl RunSummary {
+    fn add(
+        &mut self,
+        args: &Args,
+        graph: &product::Graph,
+        computed: usize,
+        products: &[ProductId],
+        written: ScopeWritten,
+    ) {
+        self.scopes += 1;
+        self.computed += computed;
+        self.products += products.len();
+        self.rows_written += written.rows;
+        self.newly_out_of_stock.extend(written.newly_out_of_stock);
+        self.compute_errors.extend(
+            graph
+                .compute_errors
+                .values()
+                .map(|error| (graph.warehouse.label(), error.clone())),
+        );
+        for product in &graph.requested_products {
+            if graph.get(product).is_none()
+                && !args.exclude_product.contains(&product.0)
+                && !self.missing.contains(&product.0)
+            {
+                self.missing.push(product.0);
+            }
         }
+    }
 
-        tx.commit().await?;
+    /// Check the `--fail-on-*` conditions against what the run produced.
+    fn check(&self, args: &Args) -> Result<(), PolicyFailure> {
+        args.failure_policy().check(&RunOutput {
+            missing: &self.missing,
+            computed: self.computed,
+            sink: args
+                .sink_db_url
+                .as_ref()
+                .filter(|_| !args.dry_run)
+                .map(|_| (self.products as u64, self.rows_written)),
+        })
     }
 
-    Ok(())
+    /// The `--notify-webhook` message: how the run ended and what it got through.
+    fn notification(
+        &self,
+        identity: &SinkRunIdentity,
+        started_at: SystemTime,
+        error: Option<&anyhow::Error>,
+        tracks_state: bool,
+    ) -> String {
+        /// Newly out of stock products listed by id, the rest are only counted
+        const LISTED: usize = 20;
+
+        let elapsed = started_at.elapsed().unwrap_or_default().as_secs_f64();
+        let status = match (error, shutdown::requested()) {
+            (None, _) => "succeeded".to_owned(),
+            (Some(_), Some(signal)) => format!("was interrupted by {}", signal.as_str()),
+            (Some(_), None) => "failed".to_owned(),
+        };
+        let mut text = format!(
+            "odoo-rapid-quant run {} {status} after {elapsed:.1}s: {} scopes, {} products computed, {} rows written",
+            identity.run_id, self.scopes, self.products, self.rows_written
+        );
+        if tracks_state {
+            text.push_str(&format!(
+                "\nNewly out of stock: {} products",
+                self.newly_out_of_stock.len()
+            ));
+            if !self.newly_out_of_stock.is_empty() {
+                let listed: Vec<String> = self
+                    .newly_out_of_stock
+                    .iter()
+                    .take(LISTED)
+                    .map(|product| product.0.to_string())
+                    .collect();
+                text.push_str(&format!(" ({}", listed.join(", ")));
+                let more = self.newly_out_of_stock.len().saturating_sub(LISTED);
+                if more > 0 {
+                    text.push_str(&format!(" and {more} more"));
+                }
+                text.push(')');
+            }
+        }
+        if !self.compute_errors.is_empty() {
+            text.push_str(&format!(
+                "\nNot computed: {} products (see the log)",
+                self.compute_errors.len()
+            ));
+        }
+        if let Some(error) = error {
+            text.push_str(&format!("\nError: {error:#}"));
+        }
+        text
+    }
+
+    fn metrics(&self, started_at: SystemTime, failed: bool) -> pushgateway::RunMetrics {
+        pushgateway::RunMetrics {
+            scopes: self.scopes,
+            products: self.products,
+            rows_written: self.rows_written,
+            duration: started_at.elapsed().unwrap_or_default(),
+            finished_at: SystemTime::now(),
+            succeeded: !failed,
+        }
+    }
+
+    fn log(&self, identity: &SinkRunIdentity, started_at: SystemTime, failed: bool) {
+        let elapsed = started_at.elapsed().unwrap_or_default();
+        for (scope, error) in &self.compute_errors {
+            tracing::error!(scope, "Left out of the run: {error}");
+        }
+        match shutdown::requested() {
+            Some(signal) if failed => tracing::warn!(
+                run_id = identity.run_id,
+                scopes = self.scopes,
+                products = self.products,
+                rows_written = self.rows_written,
+                ?elapsed,
+                "Run interrupted by {}",
+                signal.as_str()
+            ),
+            _ => tracing::info!(
+                run_id = identity.run_id,
+                scopes = self.scopes,
+                products = self.products,
+                rows_written = self.rows_written,
+                ?elapsed,
+                failed,
+                "Finished run"
+            ),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut cli = Args::parse();
+    init_tracing(cli.log_level)?;
+    if let Some(path) = cli.exclude_products_from.as_deref() {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let excluded = cli::parse_product_ids(&contents)
+            .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+        cli.exclude_product.extend(excluded);
+    }
+    if let (Some(name), Some(path)) = (
+        cli.warehouse_group.as_deref(),
+        cli.warehouse_groups.as_deref(),
+    ) {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let groups = cli::parse_warehouse_groups(&contents)
+            .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+        cli.group_warehouses = groups
+            .get(name)
+            .with_context(|| format!("no warehouse group '{name}' in {}", path.display()))?
+            .clone();
+    }
+    let started_at = SystemTime::now();
+
+    if let Some(SchemaFormat::Jsonl) = cli.print_schema {
+        let mut writer = stdout().lock();
+        serde_json::to_writer_pretty(&mut writer, &jsonl_schema(&cli))?;
+        writer.write_all(b"\n")?;
+        return Ok(());
+    }
+
+    if let Some(InfoFormat::Json) = cli.print_info {
+        let mut writer = stdout().lock();
+        serde_json::to_writer_pretty(&mut writer, &build_info())?;
+        writer.write_all(b"\n")?;
+        return Ok(());
+    }
+
+    if let Some(dialect) = cli.dialect.as_deref() {
+        AdapterRegistry::builtin().check_name(dialect)?;
+    }
+
+    let scratch = match (cli.src_dump.as_deref(), cli.scratch_db_url.as_deref()) {
+        (Some(dump), Some(server_url)) => {
+            let scratch = ScratchDatabase::restore(dump, server_url).await?;
+            cli.src_db_url = Some(scratch.url.clone());
+            Some(scratch)
+        }
+        _ => None,
+    };
+
+    let outcome = run(&cli, started_at).await;
+
+    if let Some(scratch) = scratch {
+        if let Err(err) = scratch.drop_database().await {
+            tracing::warn!("Failed to drop the scratch database: {err}");
+        }
+    }
+
+    match (outcome, shutdown::requested()) {
+        (Err(err), Some(signal)) => {
+            eprintln!("Error: {err:?}");
+            std::process::exit(signal.exit_code());
+        }
+        (Err(err), None) => match err.downcast_ref::<PolicyFailure>() {
+            Some(failure) => {
+                eprintln!("Error: {err:?}");
+                std::process::exit(failure.exit_code());
+            }
+            None => Err(err),
+        },
+        (outcome, _) => outcome,
+    }
+}
+
+/// Run a subcommand, or compute availability and write it out.
+async fn run(cli: &Args, started_at: SystemTime) -> anyhow::Result<()> {
+    match cli.command.as_ref() {
+        Some(Command::Compare(compare)) => return run_compare(cli, compare).await,
+        Some(Command::GraphStats(stats)) => return run_graph_stats(cli, stats).await,
+        Some(Command::DetectCycles) => return run_detect_cycles(cli).await,
+        Some(Command::CheckBomLines) => return run_check_bom_lines(cli).await,
+        Some(Command::Audit) => return run_audit(cli).await,
+        Some(Command::Locations) => return run_locations(cli).await,
+        None if cli.pipe => return run_pipe(cli).await,
+        None => {}
+    }
+
+    // Compile the template before touching the database, so syntax errors fail fast
+    let mut template_env = minijinja::Environment::new();
+    if let Some(source) = cli.stdout_template.as_deref() {
+        template_env
+            .add_template("row", source)
+            .context("invalid --stdout-template")?;
+    }
+
+    // Check the sink scheme before the (long) collection, so an unsupported sink fails fast
+    if let Some(sink_db_url) = cli.sink_db_url.as_deref() {
+        let _ = SinkDriver::from_url(sink_db_url)?;
+    }
+
+    let scopes = cli.stock_scopes();
+    let (Some((_, other_scopes)), Some(src_db_url)) =
+        (scopes.split_first(), cli.src_db_url.as_deref())
+    else {
+        anyhow::bail!(
+            "--warehouse, --warehouse-group or --location, and --src-db-url are required"
+        );
+    };
+    if !other_scopes.is_empty() {
+        if cli.stdout == Some(StdoutFormat::Html) {
+            anyhow::bail!("--stdout html takes a single warehouse or --location");
+        }
+        // Each scope commits on its own, so truncating would drop the previous ones
+        if cli.sink_truncate.is_some() {
+            anyhow::bail!("--sink-truncate takes a single warehouse or --location");
+        }
+    }
+    if cli.warehouse_group.is_some()
+        && !matches!(cli.stdout, Some(StdoutFormat::Human | StdoutFormat::Jsonl))
+    {
+        anyhow::bail!("--warehouse-group prints its rows with --stdout human or jsonl");
+    }
+    if cli.group_by.is_some() && cli.stdout != Some(StdoutFormat::Human) {
+        anyhow::bail!("--group-by only applies to --stdout human");
+    }
+    let mut group_totals = cli.warehouse_group.as_ref().map(|_| GroupTotals::default());
+    let mut state = cli.state_file.as_deref().map(StateFile::load).transpose()?;
+    let resumed = match state.as_mut().and_then(|state| state.checkpoint.take()) {
+        Some(checkpoint) if cli.resume => {
+            tracing::info!(run_id = checkpoint.run_id, "Resuming an unfinished run");
+            Some(checkpoint)
+        }
+        Some(checkpoint) => {
+            tracing::warn!(
+                run_id = checkpoint.run_id,
+                "The state file holds an unfinished run; starting afresh, pass --resume to continue it"
+            );
+            None
+        }
+        None if cli.resume => {
+            tracing::info!("No unfinished run to resume; starting afresh");
+            None
+        }
+        None => None,
+    };
+
+    let src_pool_options = source_options(src_db_url)?;
+    let identity = SinkRunIdentity::new(
+        &src_pool_options,
+        match (cli.run_id.clone(), resumed.as_ref()) {
+            (Some(run_id), _) => run_id,
+            (None, Some(checkpoint)) => checkpoint.run_id.clone(),
+            (None, None) => uuid::Uuid::new_v4().to_string(),
+        },
+    );
+    if let Some(state) = state.as_mut() {
+        state.checkpoint =
+            Some(resumed.unwrap_or_else(|| Checkpoint::new(identity.run_id.clone())));
+    }
+    // Checksummed before collecting, at the cost of a scan of every source table read, so only
+    // when asked to skip unchanged scopes
+    let mut checksums = match state.as_ref().filter(|_| cli.skip_if_unchanged) {
+        Some(_) => input_checksums(cli, &src_pool_options, &identity, &scopes).await?,
+        None => HashMap::new(),
+    };
+    let scopes: Vec<StockScope> = match state.as_ref().filter(|_| cli.skip_if_unchanged) {
+        Some(state) => scopes
+            .into_iter()
+            .filter(|scope| {
+                let key = state::stock_scope_key(*scope);
+                let unchanged = checksums
+                    .get(&key)
+                    .is_some_and(|checksum| state.inputs_unchanged(&key, checksum));
+                if unchanged {
+                    tracing::info!(
+                        scope = key,
+                        "Skipping a scope whose inputs are unchanged since the last successful run"
+                    );
+                }
+                !unchanged
+            })
+            .collect(),
+        None => scopes,
+    };
+    let Some((&first_scope, other_scopes)) = scopes.split_first() else {
+        tracing::info!("Every scope's inputs are unchanged since the last successful run");
+        return Ok(());
+    };
+    tracing::info!(
+        run_id = identity.run_id,
+        free_formula = cli.free_formula.as_str(),
+        "Starting run"
+    );
+    let statsd = cli
+        .statsd
+        .as_deref()
+        .filter(|_| !cli.dry_run)
+        .map(|address| Statsd::connect(address, &cli.statsd_prefix))
+        .transpose()?;
+    // Only the availability run stops gracefully; subcommands keep the default signal handling
+    shutdown::install()?;
+
+    let mut summary = RunSummary::default();
+    // Recorded as failed in the --sink-runs-table when the run stops while working on it
+    let mut current_scope = None;
+    let outcome = async {
+        current_scope = Some(ScopeRun::new(first_scope));
+        let Some((mut graph, mut products)) =
+            collect_graph(cli, src_pool_options, first_scope).await?
+        else {
+            current_scope = None;
+            return Ok(());
+        };
+        current_scope = Some(ScopeRun {
+            warehouse_id: Some(graph.warehouse.id.0),
+            products_computed: products.len(),
+        });
+        let mut warehouses = vec![graph.warehouse.clone()];
+        for scope in other_scopes {
+            warehouses.push(
+                scope_warehouse(
+                    graph.adapter.as_ref(),
+                    &graph.pool,
+                    *scope,
+                    cli.warehouse_scope(),
+                    cli.lang.as_deref(),
+                )
+                .await?,
+            );
+        }
+        let nested = overlapping_scopes(cli, &warehouses)?;
+        let output_mode = AvailabilityOutputMode::from_allow_negative(cli.allow_negative);
+        let computed = products.len();
+        let writing = Instant::now();
+        let written = write_tracked_scope(
+            cli,
+            &graph,
+            &mut products,
+            state.as_mut(),
+            &identity,
+            &template_env,
+            started_at,
+        )
+        .await?;
+        if let Some(scope) = current_scope.take() {
+            record_scope_run(cli, &identity, started_at, scope, Some(written.rows)).await;
+        }
+        if let Some(statsd) = statsd.as_ref() {
+            report_scope(statsd, &graph, &products, &written, writing.elapsed());
+        }
+        record_inputs(state.as_mut(), &mut checksums, &graph);
+        summary.add(cli, &graph, computed, &products, written);
+        if let Some(totals) = group_totals.as_mut().filter(|_| !nested.contains(&0)) {
+            totals.add(&graph, &products, output_mode)?;
+        }
+
+        // Later locations reuse the product graph and only recollect stock
+        for (index, warehouse) in warehouses.into_iter().enumerate().skip(1) {
+            shutdown::check()?;
+            current_scope = Some(ScopeRun {
+                warehouse_id: Some(warehouse.id.0),
+                products_computed: 0,
+            });
+            graph.rescope(warehouse).await?;
+            let mut products = output_products(cli, &graph);
+            current_scope = Some(ScopeRun {
+                warehouse_id: Some(graph.warehouse.id.0),
+                products_computed: products.len(),
+            });
+            let computed = products.len();
+            let writing = Instant::now();
+            let written = write_tracked_scope(
+                cli,
+                &graph,
+                &mut products,
+                state.as_mut(),
+                &identity,
+                &template_env,
+                started_at,
+            )
+            .await?;
+            if let Some(scope) = current_scope.take() {
+                record_scope_run(cli, &identity, started_at, scope, Some(written.rows)).await;
+            }
+            if let Some(statsd) = statsd.as_ref() {
+                report_scope(statsd, &graph, &products, &written, writing.elapsed());
+            }
+            record_inputs(state.as_mut(), &mut checksums, &graph);
+            summary.add(cli, &graph, computed, &products, written);
+            if let Some(totals) = group_totals.as_mut().filter(|_| !nested.contains(&index)) {
+                totals.add(&graph, &products, output_mode)?;
+            }
+        }
+
+        if let (Some(name), Some(totals)) = (cli.warehouse_group.as_deref(), group_totals) {
+            let mut writer = FlushingWriter::new(stdout().lock(), cli.flush_every);
+            totals.write(&mut writer, cli, name, &identity)?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if let (Err(_), Some(scope)) = (&outcome, current_scope) {
+        record_scope_run(cli, &identity, started_at, scope, None).await;
+    }
+    // A failed run keeps its checkpoint, for --resume to skip the rows it committed
+    if let (Ok(()), Some(state), Some(path)) = (&outcome, state.as_mut(), cli.state_file.as_deref())
+    {
+        if cli.dry_run {
+            eprintln!("Dry run: would save the state file {}", path.display());
+        } else {
+            state.checkpoint = None;
+            state.save(path)?;
+        }
+    }
+    // Only a completed run is checked; its rows stay committed and its state saved
+    let outcome = outcome.and_then(|()| Ok(summary.check(cli)?));
+    summary.log(&identity, started_at, outcome.is_err());
+    // A notification that fails to send is logged, it does not change how the run ended
+    if cli.dry_run {
+        for (flag, set) in [
+            ("--notify-webhook", cli.notify_webhook.is_some()),
+            ("--push-metrics", cli.push_metrics.is_some()),
+            ("--statsd", cli.statsd.is_some()),
+        ] {
+            if set {
+                eprintln!("Dry run: would report the run to {flag}");
+            }
+        }
+        return outcome;
+    }
+    if let Some(url) = cli.notify_webhook.as_deref() {
+        let text = summary.notification(
+            &identity,
+            started_at,
+            outcome.as_ref().err(),
+            cli.state_file.is_some(),
+        );
+        match webhook::post_json(url, &serde_json::json!({ "text": text })).await {
+            Ok(()) => tracing::info!("Sent the run notification"),
+            Err(err) => tracing::error!("Failed to send the run notification: {err}"),
+        }
+    }
+    if let Some(statsd) = statsd.as_ref() {
+        statsd.timing("run.duration", started_at.elapsed().unwrap_or_default());
+        let status = match (outcome.is_ok(), shutdown::requested()) {
+            (true, _) => "succeeded",
+            (false, Some(_)) => "interrupted",
+            (false, None) => "failed",
+        };
+        statsd.count(&format!("run.{status}"), 1);
+    }
+    if let Some(url) = cli.push_metrics.as_deref() {
+        let metrics = summary.metrics(started_at, outcome.is_err());
+        match pushgateway::push(url, &scopes, &metrics).await {
+            Ok(()) => tracing::info!("Pushed the run metrics"),
+            Err(err) => tracing::error!("Failed to push the run metrics: {err}"),
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use rust_decimal::Decimal;
+
+    use std::collections::HashMap;
+
+    use super::{
+        JsonlDecimal, JsonlErrorRow, JsonlGroupRow, JsonlMeta, JsonlMove, arrange_products,
+        jsonl_row, jsonl_schema, write_jsonl_row,
+    };
+    use crate::{
+        cli::Args,
+        product::{
+            AbcClass, MoveDetail, MoveDirection, OutputAvailability, ProductId, ProductMetrics,
+        },
+        warehouse::{Warehouse, WarehouseId},
+    };
+
+    /// Products 1 to 5, free 5, 3, unknown, 5 and 1.
+    fn free_outputs() -> HashMap<ProductId, OutputAvailability> {
+        [(1, 5), (2, 3), (4, 5), (5, 1)]
+            .into_iter()
+            .map(|(product, free)| {
+                let output = OutputAvailability {
+                    quantity: Decimal::from(free),
+                    reserved: Decimal::ZERO,
+                    incoming: Decimal::ZERO,
+                    outgoing: Decimal::ZERO,
+                    buildable: Decimal::ZERO,
+                    free_immediately: Decimal::from(free),
+                    virtual_available: Decimal::from(free),
+                    net_on_order: Decimal::ZERO,
+                };
+                (ProductId(product), output)
+            })
+            .collect()
+    }
+
+    fn arranged(options: &[&str]) -> Vec<i32> {
+        let mut argv = vec![
+            "odoo-rapid-quant",
+            "--warehouse",
+            "1",
+            "--src-db-url",
+            "postgres://localhost/odoo",
+            "--stdout",
+            "jsonl",
+        ];
+        argv.extend(options);
+        let outputs = free_outputs();
+        let mut products: Vec<ProductId> = (1..=5).map(ProductId).collect();
+        arrange_products(&mut products, &Args::parse_from(argv), |product| {
+            outputs.get(product).cloned()
+        });
+        products.into_iter().map(|product| product.0).collect()
+    }
+
+    #[test]
+    fn products_are_sorted_with_ties_by_product_id() {
+        assert_eq!(arranged(&[]), [1, 2, 3, 4, 5]);
+        // Products without availability first, ties in product id order
+        assert_eq!(arranged(&["--sort-by", "free"]), [3, 5, 2, 1, 4]);
+        // Reversed but for the ties, products without availability last
+        assert_eq!(arranged(&["--sort-by", "free", "--desc"]), [1, 4, 2, 5, 3]);
+        assert_eq!(
+            arranged(&["--sort-by", "product", "--desc"]),
+            [5, 4, 3, 2, 1]
+        );
+    }
+
+    #[test]
+    fn limit_cuts_the_rows_after_filtering_and_sorting() {
+        assert_eq!(arranged(&["--limit", "2"]), [1, 2]);
+        assert_eq!(
+            arranged(&["--sort-by", "free", "--desc", "--limit", "2"]),
+            [1, 4]
+        );
+        assert_eq!(
+            arranged(&[
+                "--sort-by",
+                "free",
+                "--only-below-free",
+                "5",
+                "--limit",
+                "2"
+            ]),
+            [3, 5]
+        );
+        assert_eq!(arranged(&["--limit", "10"]), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn jsonl_lines_match_the_schema() {
+        let warehouse = Warehouse {
+            id: WarehouseId(1),
+            location_path: "1/7/".to_string(),
+            name: "WH".to_string(),
+            company_id: 1,
+            location_id: Some(7),
+            view_location_path: None,
+        };
+        let output = OutputAvailability {
+            quantity: Decimal::new(125, 1),
+            reserved: Decimal::from(2),
+            incoming: Decimal::ZERO,
+            outgoing: Decimal::from(2),
+            buildable: Decimal::new(105, 1),
+            free_immediately: Decimal::new(105, 1),
+            virtual_available: Decimal::new(105, 1),
+            net_on_order: Decimal::from(-2),
+        };
+        let metrics = ProductMetrics {
+            value: Some(Decimal::new(2500, 2)),
+            abc_class: Some(AbcClass::A),
+            resupply_lead_days: Some(3),
+            ..ProductMetrics::default()
+        };
+        let moves = [MoveDetail {
+            direction: MoveDirection::Outgoing,
+            reference: "WH/OUT/00001".to_string(),
+            partner: None,
+            date: "2026-10-16T09:00:00Z".to_string(),
+            quantity: Decimal::from(2),
+        }];
+
+        for numbers in [false, true] {
+            let mut argv = vec!["odoo-rapid-quant", "--print-schema", "jsonl"];
+            if numbers {
+                argv.push("--jsonl-numbers");
+            }
+            let validator = jsonschema::validator_for(&jsonl_schema(&Args::parse_from(argv)))
+                .expect("the schema must be valid");
+
+            let mut lines = Vec::new();
+            let meta = JsonlMeta {
+                kind: "meta",
+                schema_version: super::JSONL_SCHEMA_VERSION,
+                tool_version: "1.2.3",
+                run_id: "nightly",
+                warehouse_id: 1,
+                warehouse_name: "WH",
+                location_id: Some(7),
+                odoo_major: 15,
+                free_formula: "odoo",
+                kit_semantics: "odoo",
+                generated_at: 1_760_000_000,
+            };
+            serde_json::to_writer(&mut lines, &meta).expect("meta line");
+            lines.push(b'\n');
+            let mut row = jsonl_row(
+                ProductId(5),
+                &warehouse,
+                "nightly",
+                &output,
+                &metrics,
+                numbers,
+            )
+            .expect("row");
+            row.moves = Some(JsonlMove::list(&moves, numbers).expect("moves"));
+            write_jsonl_row(&mut lines, &row).expect("row line");
+            let decimal = |value: Decimal| JsonlDecimal::new(value, numbers).expect("decimal");
+            let group = JsonlGroupRow {
+                product_id: 5,
+                warehouse_group: "north",
+                warehouse_ids: &[1, 2],
+                run_id: "nightly",
+                quantity: decimal(output.quantity),
+                reserved: decimal(output.reserved),
+                incoming: decimal(output.incoming),
+                outgoing: decimal(output.outgoing),
+                buildable: decimal(output.buildable),
+                free_immediately: decimal(output.free_immediately),
+                virtual_available: decimal(output.virtual_available),
+                net_on_order: decimal(output.net_on_order),
+            };
+            serde_json::to_writer(&mut lines, &group).expect("group line");
+            lines.push(b'\n');
+            let error = JsonlErrorRow {
+                kind: "error",
+                product_id: 6,
+                warehouse_id: 1,
+                location_id: None,
+                run_id: "nightly",
+                error: "cycle".to_string(),
+            };
+            serde_json::to_writer(&mut lines, &error).expect("error line");
+            lines.push(b'\n');
+
+            let lines = String::from_utf8(lines).expect("utf-8");
+            for line in lines.lines() {
+                let line: serde_json::Value = serde_json::from_str(line).expect("json line");
+                let errors: Vec<String> = validator
+                    .iter_errors(&line)
+                    .map(|error| error.to_string())
+                    .collect();
+                assert!(errors.is_empty(), "{line} does not match: {errors:?}");
+            }
+
+            // Rows are closed, so a renamed field is caught
+            let mut renamed: serde_json::Value =
+                serde_json::from_str(lines.lines().nth(1).expect("row")).expect("json row");
+            renamed["qty"] = renamed["quantity"].take();
+            assert!(!validator.is_valid(&renamed));
+            // Decimals are strings unless --jsonl-numbers
+            let mut row: serde_json::Value =
+                serde_json::from_str(lines.lines().nth(1).expect("row")).expect("json row");
+            row["quantity"] = if numbers {
+                serde_json::json!("12.5")
+            } else {
+                serde_json::json!(12.5)
+            };
+            assert!(!validator.is_valid(&row));
+        }
+    }
 }
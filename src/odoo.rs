@@ -2,8 +2,6 @@ use std::fmt;
 
 use sqlx::PgPool;
 
-use crate::dialect::{BuildAdapterError, OdooAdapter, v15};
-
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum OdooVersion {
     V15,
@@ -68,16 +66,6 @@ impl OdooVersion {
             Self::Other(major) => major,
         }
     }
-
-    pub async fn dialect(self, pool: &PgPool) -> Result<Box<dyn OdooAdapter>, BuildAdapterError> {
-        match self {
-            OdooVersion::V15 => {
-                let adapter = v15::Adapter::new(pool).await?;
-                Ok(Box::new(adapter))
-            }
-            _ => Err(BuildAdapterError::UnsupportedMajor(self.as_u16())),
-        }
-    }
 }
 
 impl fmt::Display for OdooVersion {
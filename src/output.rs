@@ -0,0 +1,997 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{BufWriter, Write},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::StreamExt;
+use rust_decimal::Decimal;
+
+use crate::{
+    JSONL_SCHEMA_VERSION, JsonlErrorRow, JsonlMeta, JsonlMove,
+    cli::{Args, FlushEvery, GroupBy},
+    jsonl_row,
+    product::{
+        self, AvailabilityOutputMode, DiagnosticNode, FreeFormula, MoveDetail, OutputAvailability,
+        ProductId, ProductMetrics,
+    },
+    shutdown,
+    sink::SinkRunIdentity,
+    warehouse, write_jsonl_row,
+};
+
+/// Everything a writer is handed to print the rows of one stock scope.
+pub struct OutputContext<'a> {
+    pub cli: &'a Args,
+    pub graph: &'a product::Graph,
+    /// The products to print, in output order
+    pub products: &'a [ProductId],
+    pub identity: &'a SinkRunIdentity,
+    pub started_at: SystemTime,
+    pub output_mode: AvailabilityOutputMode,
+    /// `--detail moves`, looked up only for the writers printing detail rows
+    pub moves: Option<&'a HashMap<ProductId, Vec<MoveDetail>>>,
+}
+
+/// Prints the availability of one stock scope to stdout in a `--stdout` format.
+#[async_trait(?Send)]
+pub trait OutputWriter: fmt::Debug {
+    /// Whether this format has room for `--detail moves` rows.
+    fn detail_rows(&self) -> bool {
+        false
+    }
+
+    async fn write(
+        &self,
+        writer: &mut dyn Write,
+        context: &OutputContext<'_>,
+    ) -> anyhow::Result<()>;
+}
+
+/// The `--stdout` formats a run can pick from, by name. Builds wanting another format can
+/// register their own `OutputWriter` next to the built-in ones; a later registration replaces
+/// the writer of the same name.
+#[derive(Debug, Clone)]
+pub struct OutputRegistry {
+    writers: Vec<(String, Arc<dyn OutputWriter>)>,
+}
+
+impl OutputRegistry {
+    /// The writers shipped with this crate.
+    pub fn builtin() -> Self {
+        let mut registry = Self {
+            writers: Vec::new(),
+        };
+        registry.register("human", Arc::new(HumanWriter));
+        registry.register("jsonl", Arc::new(JsonlWriter));
+        registry.register("diagnose", Arc::new(DiagnoseWriter));
+        registry.register("html", Arc::new(HtmlWriter));
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, writer: Arc<dyn OutputWriter>) {
+        self.writers.retain(|(registered, _)| registered != name);
+        self.writers.push((name.to_string(), writer));
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.writers.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<&dyn OutputWriter> {
+        self.writers
+            .iter()
+            .find(|(registered, _)| registered == name)
+            .map(|(_, writer)| writer.as_ref())
+            .with_context(|| {
+                format!(
+                    "no writer registered for --stdout {name}, expected one of: {}",
+                    self.names().join(", ")
+                )
+            })
+    }
+}
+
+/// Buffers stdout rows like a `BufWriter`, also handing them on at each `--flush-every`, checked
+/// as rows end, so a reader acting on rows as they come is not kept waiting for the whole scope.
+#[derive(Debug)]
+pub struct FlushingWriter<W: Write> {
+    inner: BufWriter<W>,
+    flush_every: Option<FlushEvery>,
+    /// Rows written since the last flush
+    rows: usize,
+    flushed_at: Instant,
+}
+
+impl<W: Write> FlushingWriter<W> {
+    pub fn new(inner: W, flush_every: Option<FlushEvery>) -> Self {
+        Self {
+            inner: BufWriter::new(inner),
+            flush_every,
+            rows: 0,
+            flushed_at: Instant::now(),
+        }
+    }
+
+    fn flush_due(&self) -> bool {
+        match self.flush_every {
+            Some(FlushEvery::Rows(rows)) => self.rows >= rows.get(),
+            Some(FlushEvery::Interval(interval)) => self.flushed_at.elapsed() >= interval,
+            None => false,
+        }
+    }
+}
+
+impl<W: Write> Write for FlushingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let rows = buf[..written].iter().filter(|byte| **byte == b'\n').count();
+        if rows > 0 {
+            self.rows += rows;
+            if self.flush_due() {
+                self.flush()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()?;
+        self.rows = 0;
+        self.flushed_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// `--stdout human`: aligned `name=value` rows, grouped and highlighted on request.
+#[derive(Debug)]
+struct HumanWriter;
+
+#[async_trait(?Send)]
+impl OutputWriter for HumanWriter {
+    fn detail_rows(&self) -> bool {
+        true
+    }
+
+    async fn write(
+        &self,
+        writer: &mut dyn Write,
+        context: &OutputContext<'_>,
+    ) -> anyhow::Result<()> {
+        let OutputContext {
+            cli,
+            graph,
+            products,
+            ..
+        } = *context;
+        let categories = match cli.group_by {
+            Some(GroupBy::Category) => Some(
+                graph
+                    .adapter
+                    .product_categories(&graph.pool, products)
+                    .await?,
+            ),
+            None => None,
+        };
+
+        write_human(
+            writer,
+            &graph.warehouse,
+            &published_rows(graph, products, context.output_mode)?,
+            HumanExtras {
+                categories: categories.as_ref(),
+                moves: context.moves,
+            },
+            cli.color.enabled().then_some(Highlight {
+                warn_below: cli.warn_below,
+            }),
+        )
+    }
+}
+
+/// `--stdout jsonl`: one JSON object per row, after an optional `--jsonl-meta` line.
+#[derive(Debug)]
+struct JsonlWriter;
+
+#[async_trait(?Send)]
+impl OutputWriter for JsonlWriter {
+    fn detail_rows(&self) -> bool {
+        true
+    }
+
+    async fn write(
+        &self,
+        writer: &mut dyn Write,
+        context: &OutputContext<'_>,
+    ) -> anyhow::Result<()> {
+        let OutputContext {
+            cli,
+            graph,
+            products,
+            identity,
+            output_mode,
+            ..
+        } = *context;
+        let warehouse = &graph.warehouse;
+        if cli.jsonl_meta {
+            let meta = JsonlMeta {
+                kind: "meta",
+                schema_version: JSONL_SCHEMA_VERSION,
+                tool_version: identity.tool_version,
+                run_id: &identity.run_id,
+                warehouse_id: warehouse.id.0,
+                warehouse_name: &warehouse.name,
+                location_id: warehouse.location_id,
+                odoo_major: graph.adapter.major().as_u16(),
+                free_formula: graph.free_formula.as_str(),
+                kit_semantics: graph.kit_semantics.as_str(),
+                generated_at: context
+                    .started_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            };
+            serde_json::to_writer(&mut *writer, &meta)?;
+            writer.write_all(b"\n")?;
+        }
+        let mut outputs = std::pin::pin!(graph.stream_availability(products, output_mode));
+        while let Some((product, output)) = outputs.next().await {
+            shutdown::check()?;
+            let metrics = graph.metrics(&product, output_mode);
+            let mut row = jsonl_row(
+                product,
+                warehouse,
+                &identity.run_id,
+                &output,
+                &metrics,
+                cli.jsonl_numbers,
+            )?;
+            if let Some(moves) = context.moves {
+                let detail = moves.get(&product).map_or(&[][..], Vec::as_slice);
+                row.moves = Some(JsonlMove::list(detail, cli.jsonl_numbers)?);
+            }
+            write_jsonl_row(writer, &row)?;
+        }
+        if cli.jsonl_error_rows {
+            for (product, error) in &graph.compute_errors {
+                let row = JsonlErrorRow {
+                    kind: "error",
+                    product_id: product.0,
+                    warehouse_id: warehouse.id.0,
+                    location_id: warehouse.location_id,
+                    run_id: &identity.run_id,
+                    error: error.to_string(),
+                };
+                serde_json::to_writer(&mut *writer, &row)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `--stdout diagnose`: the dependency tree of the first product.
+#[derive(Debug)]
+struct DiagnoseWriter;
+
+#[async_trait(?Send)]
+impl OutputWriter for DiagnoseWriter {
+    async fn write(
+        &self,
+        writer: &mut dyn Write,
+        context: &OutputContext<'_>,
+    ) -> anyhow::Result<()> {
+        let graph = context.graph;
+        let root_id = *context
+            .products
+            .first()
+            .context("--exclude-product leaves no product to diagnose")?;
+        let tree = graph
+            .diagnostic_tree(root_id, None)
+            .with_context(|| format!("product {} not found in graph", root_id.0))?;
+        write_diagnostic_tree(
+            writer,
+            &tree,
+            context.output_mode,
+            graph.free_formula,
+            &mut vec![],
+            true,
+        )
+    }
+}
+
+/// `--stdout html`: a single page report of the rows.
+#[derive(Debug)]
+struct HtmlWriter;
+
+#[async_trait(?Send)]
+impl OutputWriter for HtmlWriter {
+    async fn write(
+        &self,
+        writer: &mut dyn Write,
+        context: &OutputContext<'_>,
+    ) -> anyhow::Result<()> {
+        let graph = context.graph;
+        write_html(
+            writer,
+            &graph.warehouse,
+            &published_rows(graph, context.products, context.output_mode)?,
+        )
+    }
+}
+
+/// The published availability and metrics of one product, for the writers laying rows out.
+#[derive(Debug)]
+struct PublishedRow {
+    product: ProductId,
+    output: OutputAvailability,
+    metrics: ProductMetrics,
+}
+
+/// The published rows of `products`, in order.
+fn published_rows(
+    graph: &product::Graph,
+    products: &[ProductId],
+    output_mode: AvailabilityOutputMode,
+) -> anyhow::Result<Vec<PublishedRow>> {
+    products
+        .iter()
+        .map(|product| {
+            let availability = graph
+                .get(product)
+                .with_context(|| format!("missing availability for product_id={}", product.0))?;
+            Ok(PublishedRow {
+                product: *product,
+                output: availability.output(output_mode, graph.free_formula),
+                metrics: graph.metrics(product, output_mode),
+            })
+        })
+        .collect()
+}
+
+/// Running sums of the headline figures, for subtotal and total rows.
+#[derive(Debug, Default)]
+struct Subtotal {
+    products: usize,
+    quantity: Decimal,
+    free_immediately: Decimal,
+    virtual_available: Decimal,
+}
+
+impl Subtotal {
+    fn add(&mut self, availability: &OutputAvailability) {
+        self.products += 1;
+        self.quantity += availability.quantity;
+        self.free_immediately += availability.free_immediately;
+        self.virtual_available += availability.virtual_available;
+    }
+}
+
+impl fmt::Display for Subtotal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "products={}, quantity={}, free={}, virtual_available={}",
+            self.products, self.quantity, self.free_immediately, self.virtual_available
+        )
+    }
+}
+
+/// ANSI highlighting of human output rows by their free quantity.
+#[derive(Clone, Copy, Debug)]
+struct Highlight {
+    warn_below: Option<Decimal>,
+}
+
+impl Highlight {
+    const RED: &'static str = "\x1b[31m";
+    const YELLOW: &'static str = "\x1b[33m";
+
+    fn color(self, free: Decimal) -> Option<&'static str> {
+        if free <= Decimal::ZERO {
+            Some(Self::RED)
+        } else if self.warn_below.is_some_and(|threshold| free < threshold) {
+            Some(Self::YELLOW)
+        } else {
+            None
+        }
+    }
+}
+
+/// Lookups made for human output only, when grouping or detail rows are requested.
+#[derive(Debug, Default)]
+struct HumanExtras<'a> {
+    /// `--group-by category`
+    categories: Option<&'a HashMap<ProductId, String>>,
+
+    /// `--detail moves`
+    moves: Option<&'a HashMap<ProductId, Vec<MoveDetail>>>,
+}
+
+fn write_human<W: Write + ?Sized>(
+    writer: &mut W,
+    warehouse: &warehouse::Warehouse,
+    rows: &[PublishedRow],
+    extras: HumanExtras<'_>,
+    highlight: Option<Highlight>,
+) -> anyhow::Result<()> {
+    let HumanExtras { categories, moves } = extras;
+    let mut groups: BTreeMap<&str, Vec<&PublishedRow>> = BTreeMap::new();
+    for row in rows {
+        let group = categories
+            .map(|categories| categories.get(&row.product).map_or("", String::as_str))
+            .unwrap_or_default();
+        groups.entry(group).or_default().push(row);
+    }
+
+    // Rows are buffered so every `name=value` column can be padded to its widest cell; the
+    // subtotal and total rows follow their own layout.
+    let mut lines = Vec::new();
+    let mut columns: Vec<&'static str> = Vec::new();
+    let mut total = Subtotal::default();
+
+    for (group, rows) in groups {
+        let mut subtotal = Subtotal::default();
+
+        for PublishedRow {
+            product,
+            output,
+            metrics,
+        } in rows
+        {
+            let mut cells: Vec<(&'static str, String)> = output
+                .fields()
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect();
+            cells.extend(metrics.fields());
+            for (name, _) in &cells {
+                if !columns.contains(name) {
+                    columns.push(name);
+                }
+            }
+
+            lines.push(HumanLine::Row {
+                label: format!("{:?}, {}:", product, warehouse.label()),
+                cells,
+                free: output.free_immediately,
+            });
+            for detail in moves
+                .and_then(|moves| moves.get(product))
+                .into_iter()
+                .flatten()
+            {
+                lines.push(HumanLine::Summary(format!(
+                    "    {:<3} {} {} {} quantity={}",
+                    detail.direction.as_str(),
+                    detail.date,
+                    detail.reference,
+                    detail.partner.as_deref().unwrap_or("-"),
+                    detail.quantity,
+                )));
+            }
+            subtotal.add(output);
+            total.add(output);
+        }
+
+        if categories.is_some() {
+            lines.push(HumanLine::Summary(format!(
+                "Subtotal {}: {}",
+                group, subtotal
+            )));
+        }
+    }
+    lines.push(HumanLine::Summary(format!("Total: {}", total)));
+
+    let mut label_width = 0;
+    let mut widths = vec![0; columns.len()];
+    for line in &lines {
+        if let HumanLine::Row { label, cells, .. } = line {
+            label_width = label_width.max(label.len());
+            for (name, value) in cells {
+                if let Some(column) = columns.iter().position(|column| column == name) {
+                    widths[column] = widths[column].max(name.len() + value.len() + 1);
+                }
+            }
+        }
+    }
+
+    for line in &lines {
+        match line {
+            HumanLine::Row { label, cells, free } => {
+                let mut rendered = format!("{label:<label_width$}");
+                for (column, width) in columns.iter().zip(&widths) {
+                    let cell = cells
+                        .iter()
+                        .find(|(name, _)| name == column)
+                        .map(|(name, value)| format!("{name}={value},"))
+                        .unwrap_or_default();
+                    rendered.push(' ');
+                    rendered.push_str(&format!("{cell:<width$}", width = width + 1));
+                }
+                let rendered = rendered.trim_end();
+                let rendered = rendered.strip_suffix(',').unwrap_or(rendered);
+                match highlight.and_then(|highlight| highlight.color(*free)) {
+                    Some(color) => writeln!(writer, "{color}{rendered}\x1b[0m")?,
+                    None => writeln!(writer, "{rendered}")?,
+                }
+            }
+            HumanLine::Summary(summary) => writeln!(writer, "{summary}")?,
+        }
+    }
+
+    Ok(())
+}
+
+enum HumanLine {
+    Row {
+        label: String,
+        cells: Vec<(&'static str, String)>,
+        free: Decimal,
+    },
+    Summary(String),
+}
+
+const HTML_REPORT_SCRIPT: &str = r##"
+const table = document.querySelector("table");
+const body = table.tBodies[0];
+document.querySelector("#filter").addEventListener("input", (event) => {
+  const needle = event.target.value.toLowerCase();
+  for (const row of body.rows) {
+    row.hidden = !row.textContent.toLowerCase().includes(needle);
+  }
+});
+table.querySelectorAll("th").forEach((header, column) => {
+  header.addEventListener("click", () => {
+    const ascending = header.dataset.order !== "asc";
+    table.querySelectorAll("th").forEach((other) => delete other.dataset.order);
+    header.dataset.order = ascending ? "asc" : "desc";
+    const key = (row) => {
+      const text = row.cells[column].textContent;
+      const number = Number(text);
+      return text !== "" && !Number.isNaN(number) ? number : text;
+    };
+    const rows = Array.from(body.rows).sort((a, b) => {
+      const [left, right] = [key(a), key(b)];
+      const order = typeof left === typeof right
+        ? (left < right ? -1 : left > right ? 1 : 0)
+        : (typeof left === "number" ? -1 : 1);
+      return ascending ? order : -order;
+    });
+    body.append(...rows);
+  });
+});
+"##;
+
+const HTML_REPORT_STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1.5em; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.5em; }
+th { background: #f0f0f0; cursor: pointer; user-select: none; }
+th[data-order="asc"]::after { content: " \25B2"; }
+th[data-order="desc"]::after { content: " \25BC"; }
+td.number { text-align: right; }
+tr.negative td { background: #fde0e0; }
+"#;
+
+fn html_escape(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A single self-contained HTML page with a sortable, filterable table of the rows.
+fn write_html<W: Write + ?Sized>(
+    writer: &mut W,
+    warehouse: &warehouse::Warehouse,
+    published: &[PublishedRow],
+) -> anyhow::Result<()> {
+    let mut rows = Vec::with_capacity(published.len());
+    let mut columns: Vec<&'static str> = Vec::new();
+
+    for PublishedRow {
+        product,
+        output,
+        metrics,
+    } in published
+    {
+        let mut cells: Vec<(&'static str, String)> = output
+            .fields()
+            .into_iter()
+            .map(|(name, value)| (name, value.to_string()))
+            .collect();
+        cells.extend(metrics.fields());
+        for (name, _) in &cells {
+            if !columns.contains(name) {
+                columns.push(name);
+            }
+        }
+        rows.push((*product, output.free_immediately, cells));
+    }
+
+    let title = html_escape(&format!("Stock availability: {}", warehouse.label()));
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html lang=\"en\">")?;
+    writeln!(writer, "<head>")?;
+    writeln!(writer, "<meta charset=\"utf-8\">")?;
+    writeln!(writer, "<title>{title}</title>")?;
+    writeln!(writer, "<style>{HTML_REPORT_STYLE}</style>")?;
+    writeln!(writer, "</head>")?;
+    writeln!(writer, "<body>")?;
+    writeln!(writer, "<h1>{title}</h1>")?;
+    writeln!(
+        writer,
+        "<p><input id=\"filter\" type=\"search\" placeholder=\"Filter rows\"></p>"
+    )?;
+    writeln!(writer, "<table>")?;
+    write!(writer, "<thead><tr><th>product_id</th><th>warehouse</th>")?;
+    for column in &columns {
+        write!(writer, "<th>{column}</th>")?;
+    }
+    writeln!(writer, "</tr></thead>")?;
+    writeln!(writer, "<tbody>")?;
+    for (product, free, cells) in &rows {
+        if *free <= Decimal::ZERO {
+            write!(writer, "<tr class=\"negative\">")?;
+        } else {
+            write!(writer, "<tr>")?;
+        }
+        write!(
+            writer,
+            "<td class=\"number\">{}</td><td>{}</td>",
+            product.0,
+            html_escape(&warehouse.label())
+        )?;
+        for column in &columns {
+            let value = cells
+                .iter()
+                .find(|(name, _)| name == column)
+                .map(|(_, value)| html_escape(value))
+                .unwrap_or_default();
+            write!(writer, "<td class=\"number\">{value}</td>")?;
+        }
+        writeln!(writer, "</tr>")?;
+    }
+    writeln!(writer, "</tbody>")?;
+    writeln!(writer, "</table>")?;
+    writeln!(writer, "<script>{HTML_REPORT_SCRIPT}</script>")?;
+    writeln!(writer, "</body>")?;
+    writeln!(writer, "</html>")?;
+
+    Ok(())
+}
+
+fn write_diagnostic_tree<W: Write + ?Sized>(
+    writer: &mut W,
+    node: &DiagnosticNode,
+    mode: AvailabilityOutputMode,
+    formula: FreeFormula,
+    prefix: &mut Vec<bool>,
+    is_last: bool,
+) -> anyhow::Result<()> {
+    let avail = node.availability.output(mode, formula);
+
+    if prefix.is_empty() {
+        // Root node
+        writeln!(
+            writer,
+            "Product {} [{}]",
+            node.product_id.0,
+            node.product.type_label()
+        )?;
+        // Indent for sub-lines: root has no connector, just two spaces
+        let sub_indent = "  ";
+        writeln!(writer, "{sub_indent}computed: {avail}")?;
+        if let Some(ref q) = node.raw_quant {
+            writeln!(
+                writer,
+                "{sub_indent}raw: qty={}, reserved={}, incoming={}, outgoing={}",
+                q.quantity, q.reserved, q.incoming, q.outgoing
+            )?;
+        }
+    } else {
+        // Build prefix string from ancestor bools
+        let mut line = String::new();
+        for &has_sibling in prefix[..prefix.len() - 1].iter() {
+            line.push_str(if has_sibling { "│   " } else { "    " });
+        }
+        let connector = if is_last { "└── " } else { "├── " };
+        let req_qty = node
+            .required_qty
+            .expect("non-root node must have required_qty");
+        writeln!(
+            writer,
+            "{}{}[requires {}] Product {} [{}]",
+            line,
+            connector,
+            req_qty,
+            node.product_id.0,
+            node.product.type_label()
+        )?;
+
+        // Sub-lines indent: same prefix + continuation for this node's depth
+        let mut sub_indent = line.clone();
+        sub_indent.push_str(if is_last { "    " } else { "│   " });
+        sub_indent.push_str("  ");
+
+        writeln!(writer, "{sub_indent}computed: {avail}")?;
+        if let Some(ref q) = node.raw_quant {
+            writeln!(
+                writer,
+                "{sub_indent}raw: qty={}, reserved={}, incoming={}, outgoing={}",
+                q.quantity, q.reserved, q.incoming, q.outgoing
+            )?;
+        }
+        // Normalized line
+        let qty_norm = mode.project(node.availability.quantity / req_qty);
+        let free_norm = mode.project(node.availability.free(formula) / req_qty);
+        let virtual_norm = mode.project(node.availability.virtual_available() / req_qty);
+        writeln!(
+            writer,
+            "{sub_indent}normalized (÷{req_qty}): qty={qty_norm}, free={free_norm}, virtual_available={virtual_norm}"
+        )?;
+    }
+
+    let child_count = node.children.len();
+    for (i, child) in node.children.iter().enumerate() {
+        let child_is_last = i == child_count - 1;
+        prefix.push(!child_is_last);
+        write_diagnostic_tree(writer, child, mode, formula, prefix, child_is_last)?;
+        let _ = prefix.pop();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Write, sync::Arc};
+
+    use async_trait::async_trait;
+
+    use rust_decimal::Decimal;
+
+    use super::{
+        FlushingWriter, Highlight, HumanExtras, OutputContext, OutputRegistry, OutputWriter,
+        PublishedRow, html_escape, write_html, write_human,
+    };
+    use crate::{
+        cli::FlushEvery,
+        product::{OutputAvailability, ProductId, ProductMetrics},
+        warehouse::{Warehouse, WarehouseId},
+    };
+
+    #[derive(Debug)]
+    struct CsvWriter;
+
+    #[async_trait(?Send)]
+    impl OutputWriter for CsvWriter {
+        async fn write(
+            &self,
+            writer: &mut dyn Write,
+            context: &OutputContext<'_>,
+        ) -> anyhow::Result<()> {
+            for product in context.products {
+                writeln!(writer, "{}", product.0)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registered_writers_are_looked_up_by_name() {
+        let mut registry = OutputRegistry::builtin();
+        assert_eq!(registry.names(), vec!["human", "jsonl", "diagnose", "html"]);
+        assert!(registry.get("jsonl").expect("built in").detail_rows());
+        assert!(!registry.get("html").expect("built in").detail_rows());
+
+        registry.register("csv", Arc::new(CsvWriter));
+        registry.register("html", Arc::new(CsvWriter));
+        assert_eq!(
+            registry.names(),
+            vec!["human", "jsonl", "diagnose", "csv", "html"]
+        );
+        assert!(!registry.get("csv").expect("registered").detail_rows());
+        let err = OutputRegistry::builtin()
+            .get("csv")
+            .expect_err("not built in");
+        assert_eq!(
+            err.to_string(),
+            "no writer registered for --stdout csv, expected one of: human, jsonl, diagnose, html"
+        );
+    }
+
+    #[test]
+    fn flushing_writer_hands_rows_on_every_n_rows() {
+        let every = "2rows".parse().expect("flush interval must parse");
+        assert_eq!(every, FlushEvery::Rows(2.try_into().expect("non-zero")));
+        let mut writer = FlushingWriter::new(Vec::new(), Some(every));
+
+        writeln!(writer, "1").expect("write");
+        assert!(writer.inner.get_ref().is_empty());
+        // A row written in pieces only counts once it ends
+        write!(writer, "2").expect("write");
+        assert!(writer.inner.get_ref().is_empty());
+        write!(writer, "\n3\n").expect("write");
+        assert_eq!(writer.inner.get_ref(), b"1\n2\n3\n");
+
+        assert!("0".parse::<FlushEvery>().is_err());
+        assert_eq!(
+            "500ms".parse::<FlushEvery>(),
+            Ok(FlushEvery::Interval(std::time::Duration::from_millis(500)))
+        );
+    }
+
+    fn warehouse(name: &str) -> Warehouse {
+        Warehouse {
+            id: WarehouseId(1),
+            location_path: "1/7/".to_string(),
+            name: name.to_string(),
+            company_id: 1,
+            location_id: None,
+            view_location_path: None,
+        }
+    }
+
+    /// A row of `product` with `quantity` on hand, `reserved` of it reserved.
+    fn row(product: i32, quantity: i64, reserved: i64) -> PublishedRow {
+        let free = Decimal::from(quantity - reserved);
+        PublishedRow {
+            product: ProductId(product),
+            output: OutputAvailability {
+                quantity: Decimal::from(quantity),
+                reserved: Decimal::from(reserved),
+                incoming: Decimal::ZERO,
+                outgoing: Decimal::ZERO,
+                buildable: Decimal::ZERO,
+                free_immediately: free,
+                virtual_available: Decimal::from(quantity),
+                net_on_order: Decimal::ZERO,
+            },
+            metrics: ProductMetrics::default(),
+        }
+    }
+
+    #[test]
+    fn human_rows_are_aligned_and_totalled() {
+        let mut valued = row(1, 120, 20);
+        valued.metrics.value = Some(Decimal::new(2500, 2));
+        let rows = [valued, row(42, 5, 0)];
+
+        let mut written = Vec::new();
+        write_human(
+            &mut written,
+            &warehouse("WH"),
+            &rows,
+            HumanExtras::default(),
+            None,
+        )
+        .expect("human output");
+
+        // Columns are padded to their widest cell, the value column only ending the first row
+        assert_eq!(
+            String::from_utf8(written)
+                .expect("utf-8")
+                .lines()
+                .collect::<Vec<_>>(),
+            [
+                "ProductId(1), WH:  free=100, quantity=120, reserved=20, incoming=0, outgoing=0, buildable=0, virtual_available=120, net_on_order=0, value=25.00",
+                "ProductId(42), WH: free=5,   quantity=5,   reserved=0,  incoming=0, outgoing=0, buildable=0, virtual_available=5,   net_on_order=0",
+                "Total: products=2, quantity=125, free=105, virtual_available=125",
+            ]
+        );
+    }
+
+    #[test]
+    fn human_rows_are_subtotalled_by_category() {
+        let categories = HashMap::from([
+            (ProductId(1), "Saleable".to_string()),
+            (ProductId(2), "All".to_string()),
+            (ProductId(3), "Saleable".to_string()),
+        ]);
+        let rows = [row(1, 4, 1), row(2, 10, 0), row(3, 6, 6)];
+
+        let mut written = Vec::new();
+        write_human(
+            &mut written,
+            &warehouse("WH"),
+            &rows,
+            HumanExtras {
+                categories: Some(&categories),
+                moves: None,
+            },
+            None,
+        )
+        .expect("human output");
+
+        // Groups in category order, each closed by its subtotal, with columns aligned across them
+        assert_eq!(
+            String::from_utf8(written)
+                .expect("utf-8")
+                .lines()
+                .collect::<Vec<_>>(),
+            [
+                "ProductId(2), WH: free=10, quantity=10, reserved=0, incoming=0, outgoing=0, buildable=0, virtual_available=10, net_on_order=0",
+                "Subtotal All: products=1, quantity=10, free=10, virtual_available=10",
+                "ProductId(1), WH: free=3,  quantity=4,  reserved=1, incoming=0, outgoing=0, buildable=0, virtual_available=4,  net_on_order=0",
+                "ProductId(3), WH: free=0,  quantity=6,  reserved=6, incoming=0, outgoing=0, buildable=0, virtual_available=6,  net_on_order=0",
+                "Subtotal Saleable: products=2, quantity=10, free=3, virtual_available=10",
+                "Total: products=3, quantity=20, free=13, virtual_available=20",
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_are_highlighted_by_their_free_quantity() {
+        let highlight = Highlight { warn_below: None };
+        assert_eq!(highlight.color(Decimal::from(-1)), Some(Highlight::RED));
+        assert_eq!(highlight.color(Decimal::ZERO), Some(Highlight::RED));
+        assert_eq!(highlight.color(Decimal::from(3)), None);
+
+        let highlight = Highlight {
+            warn_below: Some(Decimal::from(5)),
+        };
+        assert_eq!(highlight.color(Decimal::ZERO), Some(Highlight::RED));
+        assert_eq!(
+            highlight.color(Decimal::new(49, 1)),
+            Some(Highlight::YELLOW)
+        );
+        assert_eq!(highlight.color(Decimal::from(5)), None);
+
+        // Only the rows are colored, reset at their end
+        let mut written = Vec::new();
+        write_human(
+            &mut written,
+            &warehouse("WH"),
+            &[row(1, 2, 2), row(2, 9, 0)],
+            HumanExtras::default(),
+            Some(highlight),
+        )
+        .expect("human output");
+        let written = String::from_utf8(written).expect("utf-8");
+        let lines: Vec<&str> = written.lines().collect();
+        assert!(lines[0].starts_with("\x1b[31mProductId(1), WH: free=0,"));
+        assert!(lines[0].ends_with("net_on_order=0\x1b[0m"));
+        assert!(lines[1].starts_with("ProductId(2), WH: free=9,"));
+        assert!(!lines[2].contains('\x1b'));
+    }
+
+    #[test]
+    fn html_report_escapes_the_warehouse_name() {
+        assert_eq!(
+            html_escape(r#"<b>"A&B's"</b>"#),
+            "&lt;b&gt;&quot;A&amp;B&#39;s&quot;&lt;/b&gt;"
+        );
+
+        let mut written = Vec::new();
+        write_html(
+            &mut written,
+            &warehouse(r#"Tom & Jerry's <"Main">"#),
+            &[row(7, 3, 1)],
+        )
+        .expect("html output");
+
+        let written = String::from_utf8(written).expect("utf-8");
+        let escaped = "Tom &amp; Jerry&#39;s &lt;&quot;Main&quot;&gt;";
+        assert!(written.contains(&format!("<title>Stock availability: {escaped}</title>")));
+        assert!(written.contains(&format!("<h1>Stock availability: {escaped}</h1>")));
+        assert!(written.contains(&format!(
+            "<tr><td class=\"number\">7</td><td>{escaped}</td><td class=\"number\">2</td>"
+        )));
+        assert!(!written.contains("Tom & Jerry"));
+        assert!(!written.contains("<\"Main\">"));
+    }
+}
@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+
+use crate::product::ProductId;
+
+/// One `--pipe` request line, e.g. `{"product_id": 42}` or
+/// `{"warehouse_id": 3, "products": [42, 43]}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipeRequest {
+    /// Echoed back in the response, for clients matching responses to requests
+    #[serde(default)]
+    pub id: Option<serde_json::Value>,
+    #[serde(default)]
+    product_id: Option<i32>,
+    #[serde(default)]
+    products: Vec<i32>,
+    /// Warehouse to compute against instead of the one the process started with
+    #[serde(default)]
+    pub warehouse_id: Option<i32>,
+}
+
+impl PipeRequest {
+    pub fn parse(line: &str) -> Result<Self, PipeRequestError> {
+        let request: Self = serde_json::from_str(line).map_err(PipeRequestError::Json)?;
+        if request.product_id.is_none() && request.products.is_empty() {
+            return Err(PipeRequestError::NoProducts);
+        }
+        Ok(request)
+    }
+
+    /// `product_id` and `products`, without duplicates, in the order given.
+    pub fn products(&self) -> Vec<ProductId> {
+        let mut products: Vec<ProductId> = Vec::with_capacity(self.products.len() + 1);
+        for product_id in self.product_id.iter().chain(&self.products) {
+            if !products.contains(&ProductId(*product_id)) {
+                products.push(ProductId(*product_id));
+            }
+        }
+        products
+    }
+}
+
+/// The line answering a request: a row per product found, in the order requested.
+#[derive(Debug, Serialize)]
+pub struct PipeResponse<'a, R> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a serde_json::Value>,
+    pub rows: Vec<R>,
+    /// Requested products that are not storable products of the source
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<i32>,
+}
+
+/// The line answering a request that could not be served; the process carries on.
+#[derive(Debug, Serialize)]
+pub struct PipeError<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a serde_json::Value>,
+    pub error: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PipeRequestError {
+    #[error("invalid request: {0}")]
+    Json(#[source] serde_json::Error),
+    #[error("invalid request: expected product_id or products")]
+    NoProducts,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PipeRequest, PipeRequestError};
+    use crate::product::ProductId;
+
+    #[test]
+    fn requests_name_products_and_optionally_a_warehouse() {
+        let request = PipeRequest::parse(r#"{"product_id": 42}"#).expect("request must parse");
+        assert_eq!(request.products(), vec![ProductId(42)]);
+        assert_eq!(request.warehouse_id, None);
+
+        let request =
+            PipeRequest::parse(r#"{"id": "a", "warehouse_id": 3, "products": [43, 42, 43]}"#)
+                .expect("request must parse");
+        assert_eq!(request.products(), vec![ProductId(43), ProductId(42)]);
+        assert_eq!(request.warehouse_id, Some(3));
+        assert_eq!(request.id, Some(serde_json::json!("a")));
+
+        assert!(matches!(
+            PipeRequest::parse(r#"{"warehouse_id": 3}"#),
+            Err(PipeRequestError::NoProducts)
+        ));
+        assert!(matches!(
+            PipeRequest::parse(r#"{"product": 42}"#),
+            Err(PipeRequestError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,122 @@
+/// The `--fail-on-*` conditions a run checks once it has otherwise completed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FailurePolicy {
+    pub missing: bool,
+    pub empty: bool,
+    pub short_write: bool,
+}
+
+/// What a completed run produced, as far as the failure policies are concerned.
+#[derive(Debug, Default)]
+pub struct RunOutput<'a> {
+    /// Requested products without computed availability, in any scope
+    pub missing: &'a [i32],
+    /// Products the scopes computed rows for, counted before `--state-file` leaves out the
+    /// unchanged ones
+    pub computed: usize,
+    /// Rows handed to the sink and rows it reported written, when a sink is set
+    pub sink: Option<(u64, u64)>,
+}
+
+/// A `--fail-on-*` condition met by a run. Each has its own exit status so a scheduler can tell
+/// them apart from each other and from an error (status 1).
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PolicyFailure {
+    #[error("requested products have no availability: {}", list(.0))]
+    Missing(Vec<i32>),
+    #[error("no products were computed")]
+    Empty,
+    #[error("the sink wrote {written} of the {computed} rows computed")]
+    ShortWrite { computed: u64, written: u64 },
+}
+
+impl PolicyFailure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Missing(_) => 3,
+            Self::Empty => 4,
+            Self::ShortWrite { .. } => 5,
+        }
+    }
+}
+
+fn list(products: &[i32]) -> String {
+    products
+        .iter()
+        .map(i32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl FailurePolicy {
+    /// The first enabled condition `output` meets, checked in the order of their exit statuses.
+    pub fn check(self, output: &RunOutput<'_>) -> Result<(), PolicyFailure> {
+        if self.missing && !output.missing.is_empty() {
+            return Err(PolicyFailure::Missing(output.missing.to_vec()));
+        }
+        if self.empty && output.computed == 0 {
+            return Err(PolicyFailure::Empty);
+        }
+        match output.sink {
+            Some((computed, written)) if self.short_write && written < computed => {
+                Err(PolicyFailure::ShortWrite { computed, written })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailurePolicy, PolicyFailure, RunOutput};
+
+    #[test]
+    fn only_enabled_conditions_fail_with_their_own_status() {
+        let output = RunOutput {
+            missing: &[],
+            computed: 3,
+            sink: Some((3, 2)),
+        };
+        assert_eq!(FailurePolicy::default().check(&output), Ok(()));
+        let policy = FailurePolicy {
+            missing: true,
+            empty: true,
+            short_write: true,
+        };
+        let failure = policy.check(&output).expect_err("short write");
+        assert_eq!(
+            failure,
+            PolicyFailure::ShortWrite {
+                computed: 3,
+                written: 2
+            }
+        );
+        assert_eq!(failure.exit_code(), 5);
+
+        let output = RunOutput {
+            missing: &[42, 43],
+            computed: 0,
+            sink: None,
+        };
+        let failure = policy.check(&output).expect_err("missing products");
+        assert_eq!(
+            failure.to_string(),
+            "requested products have no availability: 42, 43"
+        );
+        assert_eq!(failure.exit_code(), 3);
+        let policy = FailurePolicy {
+            missing: false,
+            ..policy
+        };
+        assert_eq!(policy.check(&output), Err(PolicyFailure::Empty));
+        assert_eq!(PolicyFailure::Empty.exit_code(), 4);
+
+        // Computed products whose rows are all unchanged wrote nothing, but are not empty
+        let output = RunOutput {
+            missing: &[],
+            computed: 2,
+            sink: Some((0, 0)),
+        };
+        assert_eq!(policy.check(&output), Ok(()));
+    }
+}
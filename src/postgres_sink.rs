@@ -0,0 +1,583 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use rust_decimal::Decimal;
+use sqlx::{
+    PgConnection, PgPool, Postgres, Transaction, postgres::PgArguments, query::Query, types::Json,
+};
+
+use crate::{
+    cli::{Args, SinkTruncate},
+    jsonl_row,
+    product::{self, AvailabilityOutputMode, Product, ProductId},
+    sink::{
+        RunRecord, Sink, SinkExecutionError, SinkPlaceholder, SinkRate, SinkRow, SinkRunIdentity,
+        SinkStmtTemplate, SinkValue, acquire_advisory_lock, advisory_lock_key,
+    },
+    warehouse,
+};
+
+/// The templated-statement sink of a `postgres://` `--sink-db-url`: `--sink-db-stmt`,
+/// `--sink-table` or `--sink-call`, and the per-class statements, run in a transaction holding
+/// an advisory lock on the target.
+pub struct PostgresSink<'a> {
+    args: &'a Args,
+    pool: &'a PgPool,
+    graph: &'a product::Graph,
+    output_mode: AvailabilityOutputMode,
+    identity: &'a SinkRunIdentity,
+    /// The default statement, for products without a class statement
+    template: Option<SinkStmtTemplate>,
+    /// The `--sink-array-batch` form of `template`
+    batch_stmt: Option<SinkStmtTemplate>,
+    lock_key: String,
+    /// `--sink-truncate`, done in the first transaction only
+    truncate: Option<SinkTruncate>,
+    rows_json_sent: bool,
+    /// The products handed to `write_batch`, whose rows are not stale
+    computed: HashSet<i32>,
+    tx: Option<Transaction<'static, Postgres>>,
+}
+
+impl<'a> PostgresSink<'a> {
+    /// Resolve the statements of `args`, creating or migrating `--sink-table` on request, and
+    /// check them with `--validate-sink`.
+    pub async fn new(
+        args: &'a Args,
+        pool: &'a PgPool,
+        graph: &'a product::Graph,
+        output_mode: AvailabilityOutputMode,
+        identity: &'a SinkRunIdentity,
+    ) -> anyhow::Result<Self> {
+        let template = sink_template(args, pool, args.sink_create_table).await?;
+
+        for (flag, template) in class_templates(args) {
+            if template.is_set_based() {
+                anyhow::bail!("{flag} cannot use {{rows_json}}, class statements run once per row");
+            }
+        }
+
+        if args.validate_sink {
+            validate_sink(args, pool, template.as_ref()).await?;
+        }
+
+        let batch_stmt = match (template.as_ref(), args.sink_array_batch) {
+            (Some(template), Some(_)) if !template.is_set_based() => {
+                let batch_stmt = template.array_batch()?;
+                tracing::debug!(sql = batch_stmt.sql, "Generated sink array batch statement");
+                Some(batch_stmt)
+            }
+            _ => None,
+        };
+
+        let lock_target = match (args.sink_table.as_ref(), template.as_ref()) {
+            (Some(table), _) => table.to_string(),
+            (None, Some(template)) => template.sql.clone(),
+            (None, None) => class_templates(args)
+                .map(|(_, template)| template.sql.as_str())
+                .collect::<Vec<_>>()
+                .join(";"),
+        };
+
+        Ok(Self {
+            args,
+            pool,
+            graph,
+            output_mode,
+            identity,
+            template,
+            batch_stmt,
+            lock_key: advisory_lock_key(&lock_target, graph.warehouse.id.0),
+            truncate: args.sink_table.as_ref().and(args.sink_truncate),
+            rows_json_sent: false,
+            computed: HashSet::new(),
+            tx: None,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink<'_> {
+    fn batch_size(&self) -> usize {
+        match (&self.template, self.args.sink_array_batch) {
+            // One statement for the whole scope
+            (Some(template), _) if template.is_set_based() => usize::MAX,
+            (Some(_), Some(batch_size)) => batch_size,
+            _ => 1,
+        }
+    }
+
+    async fn begin(&mut self) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        acquire_advisory_lock(&mut tx, &self.lock_key, self.args.lock_wait).await?;
+
+        if let (Some(table), Some(truncate)) = (self.args.sink_table.as_ref(), self.truncate.take())
+        {
+            let warehouse_id = match truncate {
+                SinkTruncate::Warehouse => Some(self.graph.warehouse.id.0),
+                SinkTruncate::Table => None,
+            };
+            let removed = table.truncate(&mut tx, warehouse_id).await?;
+            tracing::info!(removed, "Cleared sink table {table} before writing");
+        }
+
+        self.tx = Some(tx);
+        Ok(())
+    }
+
+    async fn write_row(&mut self, product: ProductId) -> anyhow::Result<u64> {
+        self.write_batch(&[product]).await
+    }
+
+    async fn write_batch(&mut self, products: &[ProductId]) -> anyhow::Result<u64> {
+        let (args, graph, output_mode, identity) =
+            (self.args, self.graph, self.output_mode, self.identity);
+        let warehouse = &graph.warehouse;
+        let tx = self
+            .tx
+            .as_mut()
+            .context("sink rows written outside of a transaction")?;
+        let mut written = 0;
+        self.computed
+            .extend(products.iter().map(|product| product.0));
+
+        // Products without a class statement fall back to the default statement, if any
+        let mut default_products = Vec::with_capacity(products.len());
+        for product in products {
+            match class_template(args, graph, product) {
+                Some(template) => {
+                    written += execute_sink_row(
+                        tx,
+                        template,
+                        graph,
+                        warehouse,
+                        *product,
+                        output_mode,
+                        identity,
+                    )
+                    .await?;
+                }
+                None if self.template.is_some() => default_products.push(*product),
+                None => {}
+            }
+        }
+
+        match (self.template.as_ref(), self.batch_stmt.as_ref()) {
+            (Some(template), _) if template.is_set_based() => {
+                written += execute_rows_json(
+                    tx,
+                    template,
+                    graph,
+                    &default_products,
+                    output_mode,
+                    identity,
+                )
+                .await?;
+                self.rows_json_sent = true;
+            }
+            (Some(_), Some(batch_stmt)) if !default_products.is_empty() => {
+                written += write_sink_array_batch(
+                    tx,
+                    batch_stmt,
+                    graph,
+                    warehouse,
+                    &default_products,
+                    output_mode,
+                    identity,
+                )
+                .await?;
+            }
+            (Some(template), None) => {
+                for product in &default_products {
+                    written += execute_sink_row(
+                        tx,
+                        template,
+                        graph,
+                        warehouse,
+                        *product,
+                        output_mode,
+                        identity,
+                    )
+                    .await?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(written)
+    }
+
+    async fn finish(&mut self, mut rate: Option<&mut SinkRate>) -> anyhow::Result<u64> {
+        let (args, graph) = (self.args, self.graph);
+        let warehouse = &graph.warehouse;
+        let tx = self
+            .tx
+            .as_mut()
+            .context("sink rows written outside of a transaction")?;
+        let mut written = 0;
+
+        // A {rows_json} statement runs once per scope, even without rows to write
+        if let Some(template) = self.template.as_ref().filter(|_| !self.rows_json_sent) {
+            if template.is_set_based() {
+                written +=
+                    execute_rows_json(tx, template, graph, &[], self.output_mode, self.identity)
+                        .await?;
+                self.rows_json_sent = true;
+            }
+        }
+
+        if let (Some(table), Some(stale_stmt)) =
+            (args.sink_table.as_ref(), args.sink_stale_stmt.as_ref())
+        {
+            let requested: HashSet<i32> = graph
+                .requested_products
+                .iter()
+                .map(|product| product.0)
+                .collect();
+            let stale: Vec<i32> = table
+                .existing_products(tx, warehouse.id.0)
+                .await?
+                .into_iter()
+                .filter(|product_id| !self.computed.contains(product_id))
+                .filter(|product_id| requested.is_empty() || requested.contains(product_id))
+                .filter(|product_id| !args.exclude_product.contains(product_id))
+                .collect();
+            tracing::info!(stale = stale.len(), "Marking stale sink rows");
+
+            for product_id in stale {
+                if let Some(rate) = rate.as_mut() {
+                    rate.acquire(1).await;
+                }
+                let mut query = sqlx::query(&stale_stmt.0.sql);
+                for placeholder in &stale_stmt.0.placeholders {
+                    query = match placeholder {
+                        SinkPlaceholder::ProductId => query.bind(product_id),
+                        SinkPlaceholder::WarehouseId => query.bind(warehouse.id.0),
+                        _ => unreachable!("stale statements only accept key placeholders"),
+                    };
+                }
+
+                let _ = query.execute(&mut **tx).await.map_err(|source| {
+                    SinkExecutionError::Execute {
+                        product_id,
+                        warehouse_id: warehouse.id.0,
+                        source,
+                    }
+                })?;
+            }
+        }
+
+        Ok(written)
+    }
+
+    async fn commit(&mut self) -> anyhow::Result<()> {
+        let tx = self
+            .tx
+            .take()
+            .context("sink committed outside of a transaction")?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// The `--sink-db-stmt-<class>` statements given, with their flag.
+fn class_templates(args: &Args) -> impl Iterator<Item = (&'static str, &SinkStmtTemplate)> {
+    [
+        ("--sink-db-stmt-simple", args.sink_db_stmt_simple.as_ref()),
+        ("--sink-db-stmt-phantom", args.sink_db_stmt_phantom.as_ref()),
+        (
+            "--sink-db-stmt-normal-bom",
+            args.sink_db_stmt_normal_bom.as_ref(),
+        ),
+        (
+            "--sink-db-stmt-commingled",
+            args.sink_db_stmt_commingled.as_ref(),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(flag, template)| template.map(|template| (flag, template)))
+}
+
+/// The statement dedicated to the product's class, when one was given.
+pub fn class_template<'a>(
+    args: &'a Args,
+    graph: &product::Graph,
+    product: &ProductId,
+) -> Option<&'a SinkStmtTemplate> {
+    match graph.catalogue.get(product)? {
+        Product::Simple(_) => args.sink_db_stmt_simple.as_ref(),
+        Product::MrpPhantom(_, _) => args.sink_db_stmt_phantom.as_ref(),
+        Product::MrpNormal(_, _) => args.sink_db_stmt_normal_bom.as_ref(),
+        Product::Commingled(_) => args.sink_db_stmt_commingled.as_ref(),
+    }
+}
+
+/// Bind a placeholder's value for one row.
+fn bind_value<'q>(
+    query: Query<'q, Postgres, PgArguments>,
+    value: SinkValue<'q>,
+) -> Query<'q, Postgres, PgArguments> {
+    match value {
+        SinkValue::Integer(value) => query.bind(value),
+        SinkValue::Numeric(value) => query.bind(value),
+        SinkValue::Text(value) => query.bind(value),
+    }
+}
+
+/// A placeholder's values across the rows of an array batch, bound as one array.
+#[derive(Debug)]
+enum SinkColumn<'a> {
+    Integer(Vec<Option<i32>>),
+    Numeric(Vec<Option<Decimal>>),
+    Text(Vec<Option<&'a str>>),
+}
+
+impl<'a> SinkColumn<'a> {
+    fn new(first: SinkValue<'a>, capacity: usize) -> Self {
+        let mut column = match first {
+            SinkValue::Integer(_) => Self::Integer(Vec::with_capacity(capacity)),
+            SinkValue::Numeric(_) => Self::Numeric(Vec::with_capacity(capacity)),
+            SinkValue::Text(_) => Self::Text(Vec::with_capacity(capacity)),
+        };
+        column.push(first);
+        column
+    }
+
+    fn push(&mut self, value: SinkValue<'a>) {
+        match (self, value) {
+            (Self::Integer(column), SinkValue::Integer(value)) => column.push(value),
+            (Self::Numeric(column), SinkValue::Numeric(value)) => column.push(value),
+            (Self::Text(column), SinkValue::Text(value)) => column.push(value),
+            (column, value) => unreachable!("{value:?} bound into {column:?}"),
+        }
+    }
+
+    fn bind(self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+        match self {
+            Self::Integer(column) => query.bind(column),
+            Self::Numeric(column) => query.bind(column),
+            Self::Text(column) => query.bind(column),
+        }
+    }
+}
+
+/// Execute a per-row sink statement for one product.
+async fn execute_sink_row(
+    conn: &mut PgConnection,
+    template: &SinkStmtTemplate,
+    graph: &product::Graph,
+    warehouse: &warehouse::Warehouse,
+    product: ProductId,
+    output_mode: AvailabilityOutputMode,
+    identity: &SinkRunIdentity,
+) -> anyhow::Result<u64> {
+    let availability = graph
+        .get(&product)
+        .with_context(|| format!("missing availability for product_id={}", product.0))?;
+    let output = availability.output(output_mode, graph.free_formula);
+    let metrics = graph.metrics(&product, output_mode);
+    let row = SinkRow {
+        product,
+        warehouse,
+        output: &output,
+        metrics: &metrics,
+        identity,
+    };
+
+    let mut query = sqlx::query(&template.sql);
+    for placeholder in &template.placeholders {
+        query = bind_value(query, placeholder.value(&row));
+    }
+
+    let result = query
+        .execute(conn)
+        .await
+        .map_err(|source| SinkExecutionError::Execute {
+            product_id: product.0,
+            warehouse_id: warehouse.id.0,
+            source,
+        })?;
+
+    Ok(result.rows_affected())
+}
+
+/// Execute one `{rows_json}` statement for a set of products; the target unpacks the rows
+/// itself.
+async fn execute_rows_json(
+    conn: &mut PgConnection,
+    template: &SinkStmtTemplate,
+    graph: &product::Graph,
+    products: &[ProductId],
+    output_mode: AvailabilityOutputMode,
+    identity: &SinkRunIdentity,
+) -> anyhow::Result<u64> {
+    let warehouse = &graph.warehouse;
+    let mut rows = Vec::with_capacity(products.len());
+    for product in products {
+        let availability = graph
+            .get(product)
+            .with_context(|| format!("missing availability for product_id={}", product.0))?;
+        rows.push(jsonl_row(
+            *product,
+            warehouse,
+            &identity.run_id,
+            &availability.output(output_mode, graph.free_formula),
+            &graph.metrics(product, output_mode),
+            true,
+        )?);
+    }
+
+    let mut query = sqlx::query(&template.sql);
+    for placeholder in &template.placeholders {
+        query = match placeholder.run_value(warehouse, identity) {
+            Some(value) => bind_value(query, value),
+            None => query.bind(Json(&rows)),
+        };
+    }
+
+    let result =
+        query
+            .execute(conn)
+            .await
+            .map_err(|source| SinkExecutionError::ExecuteRowsJson {
+                rows: rows.len(),
+                warehouse_id: warehouse.id.0,
+                source,
+            })?;
+
+    Ok(result.rows_affected())
+}
+
+/// Execute one `--sink-array-batch` statement for a chunk of products, binding one array per
+/// placeholder.
+async fn write_sink_array_batch(
+    conn: &mut PgConnection,
+    batch_stmt: &SinkStmtTemplate,
+    graph: &product::Graph,
+    warehouse: &warehouse::Warehouse,
+    products: &[ProductId],
+    output_mode: AvailabilityOutputMode,
+    identity: &SinkRunIdentity,
+) -> anyhow::Result<u64> {
+    let mut computed = Vec::with_capacity(products.len());
+    for product in products {
+        let availability = graph
+            .get(product)
+            .with_context(|| format!("missing availability for product_id={}", product.0))?;
+        computed.push((
+            *product,
+            availability.output(output_mode, graph.free_formula),
+            graph.metrics(product, output_mode),
+        ));
+    }
+    let rows: Vec<SinkRow> = computed
+        .iter()
+        .map(|(product, output, metrics)| SinkRow {
+            product: *product,
+            warehouse,
+            output,
+            metrics,
+            identity,
+        })
+        .collect();
+
+    let mut query = sqlx::query(&batch_stmt.sql);
+    for placeholder in &batch_stmt.placeholders {
+        let mut values = rows.iter().map(|row| placeholder.value(row));
+        let Some(first) = values.next() else {
+            return Ok(0);
+        };
+        let mut column = SinkColumn::new(first, rows.len());
+        for value in values {
+            column.push(value);
+        }
+        query = column.bind(query);
+    }
+
+    let result = query
+        .execute(conn)
+        .await
+        .map_err(|source| SinkExecutionError::ExecuteBatch {
+            first_product_id: products.first().map_or(0, |product| product.0),
+            rows: rows.len(),
+            warehouse_id: warehouse.id.0,
+            source,
+        })?;
+
+    Ok(result.rows_affected())
+}
+
+/// The default sink statement: `--sink-db-stmt`, the resolved `--sink-call`, or the upsert
+/// generated from `--sink-table`, which is first created or migrated when `create` is set.
+pub async fn sink_template(
+    args: &Args,
+    sink_pool: &PgPool,
+    create: bool,
+) -> anyhow::Result<Option<SinkStmtTemplate>> {
+    Ok(
+        match (
+            args.sink_db_stmt.as_ref(),
+            args.sink_table.as_ref(),
+            args.sink_call.as_ref(),
+        ) {
+            (Some(template), _, _) => Some(template.clone()),
+            (None, None, Some(call)) => {
+                let template = call.resolve(sink_pool).await?;
+                tracing::debug!(sql = template.sql, "Resolved sink call statement");
+                Some(template)
+            }
+            (None, Some(table), _) => {
+                if create {
+                    table.create_or_migrate(sink_pool, &args.sink_key).await?;
+                    if let Some(runs_table) = args.sink_runs_table.as_ref() {
+                        for statement in RunRecord::create_or_migrate_sql(runs_table) {
+                            let _ = sqlx::query(&statement).execute(sink_pool).await?;
+                        }
+                    }
+                }
+                let columns = table.columns(sink_pool).await?;
+                let template = table.upsert_template(&columns, &args.sink_key)?;
+                tracing::debug!(sql = template.sql, "Generated sink upsert statement");
+                Some(template)
+            }
+            (None, None, None) => None,
+        },
+    )
+}
+
+/// `--validate-sink`: prepare every statement the sink will run, as it will run it.
+pub async fn validate_sink(
+    args: &Args,
+    sink_pool: &PgPool,
+    sink_stmt_template: Option<&SinkStmtTemplate>,
+) -> anyhow::Result<()> {
+    let mut conn = sink_pool.acquire().await?;
+    if let Some(template) = sink_stmt_template {
+        let flag = match (args.sink_db_stmt.is_some(), args.sink_table.is_some()) {
+            (true, _) => "--sink-db-stmt",
+            (false, true) => "--sink-table",
+            (false, false) => "--sink-call",
+        };
+        match args.sink_array_batch {
+            Some(_) if !template.is_set_based() => {
+                template
+                    .array_batch()?
+                    .validate(&mut conn, flag, true)
+                    .await?;
+            }
+            _ => template.validate(&mut conn, flag, false).await?,
+        }
+    }
+    for (flag, template) in class_templates(args) {
+        template.validate(&mut conn, flag, false).await?;
+    }
+    if let Some(stale_stmt) = args.sink_stale_stmt.as_ref() {
+        stale_stmt
+            .0
+            .validate(&mut conn, "--sink-stale-stmt", false)
+            .await?;
+    }
+    tracing::info!("Validated the sink statements");
+    Ok(())
+}
@@ -1,17 +1,37 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
+    time::{Duration, Instant},
 };
 
+use async_trait::async_trait;
+use futures::Stream;
 use petgraph::visit::EdgeRef;
 use rust_decimal::RoundingStrategy;
-use sqlx::{PgPool, types::Decimal};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool, Postgres, Transaction, types::Decimal};
 
-use crate::dialect::OdooAdapter;
+use crate::dialect::{OdooAdapter, QuantOptions, is_transient};
+use crate::shutdown::{self, Interrupted};
 use crate::warehouse::Warehouse;
 
-#[derive(sqlx::Type, sqlx::FromRow, Debug, Eq, PartialEq, PartialOrd, Hash, Ord, Clone, Copy)]
+/// An Odoo `product.product` id, serialized as the bare integer.
+#[derive(
+    sqlx::Type,
+    sqlx::FromRow,
+    Serialize,
+    Deserialize,
+    Debug,
+    Eq,
+    PartialEq,
+    PartialOrd,
+    Hash,
+    Ord,
+    Clone,
+    Copy,
+)]
 #[sqlx(transparent)]
+#[serde(transparent)]
 pub struct ProductId(pub i32);
 
 #[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Hash, Ord, Copy)]
@@ -36,6 +56,12 @@ impl Product {
         }
     }
 
+    /// Serial tracked products and units rounded to 1 are reported with no decimal places, and
+    /// must never report fractional availability.
+    pub fn is_integral(&self) -> bool {
+        self.dp() == 0
+    }
+
     pub fn dp(&self) -> u32 {
         *match self {
             Product::Simple(dp) => dp,
@@ -46,7 +72,9 @@ impl Product {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Raw availability as computed, before any clamping; serialized with the field names below and
+/// quantities as exact decimal strings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Availability {
     /// on-hand quantity
     pub quantity: Decimal,
@@ -64,6 +92,73 @@ pub struct Availability {
     pub buildable: Decimal,
 }
 
+/// Definition of the published `free_immediately`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FreeFormula {
+    /// `quantity - reserved`
+    #[default]
+    Unreserved,
+    /// `quantity - reserved - outgoing_unreserved`, where `outgoing_unreserved` is the outgoing
+    /// quantity not yet reserved: stock already promised to open moves is not free either
+    Uncommitted,
+}
+
+impl FreeFormula {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Unreserved => "unreserved",
+            Self::Uncommitted => "uncommitted",
+        }
+    }
+}
+
+impl std::str::FromStr for FreeFormula {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.trim() {
+            "unreserved" => Ok(Self::Unreserved),
+            "uncommitted" => Ok(Self::Uncommitted),
+            other => Err(format!(
+                "unknown free formula '{other}' (expected unreserved or uncommitted)"
+            )),
+        }
+    }
+}
+
+/// How kits are aggregated from their components besides the per-field minima.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum KitSemantics {
+    /// Each availability field is the minimum over the components, partial kits included
+    #[default]
+    PerField,
+    /// Also publish `kit_sets_available`: the complete sets the components' free stock makes up
+    CompleteSets,
+}
+
+impl KitSemantics {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PerField => "per-field",
+            Self::CompleteSets => "complete-sets",
+        }
+    }
+}
+
+impl std::str::FromStr for KitSemantics {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.trim() {
+            "per-field" => Ok(Self::PerField),
+            "complete-sets" => Ok(Self::CompleteSets),
+            other => Err(format!(
+                "unknown kit semantics '{other}' (expected per-field or complete-sets)"
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AvailabilityOutputMode {
     ClampToZero,
@@ -87,7 +182,10 @@ impl AvailabilityOutputMode {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Availability as published, serialized like the `jsonl` output: `quantity`, `reserved`,
+/// `incoming`, `outgoing`, `buildable`, `free_immediately`, `virtual_available` and
+/// `net_on_order`, as exact decimal strings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OutputAvailability {
     pub quantity: Decimal,
     pub reserved: Decimal,
@@ -96,6 +194,8 @@ pub struct OutputAvailability {
     pub buildable: Decimal,
     pub free_immediately: Decimal,
     pub virtual_available: Decimal,
+    /// The published `incoming - outgoing`, never clamped
+    pub net_on_order: Decimal,
 }
 
 impl Availability {
@@ -103,39 +203,91 @@ impl Availability {
         self.quantity - self.reserved
     }
 
+    /// Free quantity as defined by `formula`. Kits are still exploded from the unreserved
+    /// stock of their components.
+    pub fn free(&self, formula: FreeFormula) -> Decimal {
+        match formula {
+            FreeFormula::Unreserved => self.free_immediately(),
+            FreeFormula::Uncommitted => {
+                let outgoing_unreserved = (self.outgoing - self.reserved).max(Decimal::ZERO);
+                self.free_immediately() - outgoing_unreserved
+            }
+        }
+    }
+
     pub fn virtual_available(&self) -> Decimal {
         self.quantity - self.outgoing + self.incoming
     }
 
-    pub fn output(&self, mode: AvailabilityOutputMode) -> OutputAvailability {
-        let free_immediately = self.free_immediately();
+    /// Round every field to a whole number, for products that can only be counted in units:
+    /// supply down and demand up, so the free and forecast quantities are never overstated.
+    pub fn floor_to_integer(&mut self) {
+        self.quantity = self.quantity.floor();
+        self.reserved = self.reserved.ceil();
+        self.incoming = self.incoming.floor();
+        self.outgoing = self.outgoing.ceil();
+        self.buildable = self.buildable.floor();
+    }
+
+    pub fn output(&self, mode: AvailabilityOutputMode, formula: FreeFormula) -> OutputAvailability {
+        let free_immediately = self.free(formula);
         let virtual_available = self.virtual_available();
+        let incoming = mode.project(self.incoming);
+        let outgoing = mode.project(self.outgoing);
 
         OutputAvailability {
             quantity: mode.project(self.quantity),
             reserved: mode.project(self.reserved),
-            incoming: mode.project(self.incoming),
-            outgoing: mode.project(self.outgoing),
+            incoming,
+            outgoing,
             buildable: mode.project(self.buildable),
             free_immediately: mode.project(free_immediately),
             virtual_available: mode.project(virtual_available),
+            // Signed in every mode: a shortfall is what it reports
+            net_on_order: incoming - outgoing,
         }
     }
 }
 
+impl OutputAvailability {
+    /// `(name, value)` pairs in human output order.
+    pub fn fields(&self) -> [(&'static str, Decimal); 8] {
+        [
+            ("free", self.free_immediately),
+            ("quantity", self.quantity),
+            ("reserved", self.reserved),
+            ("incoming", self.incoming),
+            ("outgoing", self.outgoing),
+            ("buildable", self.buildable),
+            ("virtual_available", self.virtual_available),
+            ("net_on_order", self.net_on_order),
+        ]
+    }
+}
+
+/// Sums availability, e.g. of the warehouses of a group.
+impl std::ops::AddAssign<&OutputAvailability> for OutputAvailability {
+    fn add_assign(&mut self, other: &OutputAvailability) {
+        self.quantity += other.quantity;
+        self.reserved += other.reserved;
+        self.incoming += other.incoming;
+        self.outgoing += other.outgoing;
+        self.buildable += other.buildable;
+        self.free_immediately += other.free_immediately;
+        self.virtual_available += other.virtual_available;
+        self.net_on_order += other.net_on_order;
+    }
+}
+
 impl fmt::Display for OutputAvailability {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "free={}, quantity={}, reserved={}, incoming={}, outgoing={}, buildable={}, virtual_available={}",
-            self.free_immediately,
-            self.quantity,
-            self.reserved,
-            self.incoming,
-            self.outgoing,
-            self.buildable,
-            self.virtual_available,
-        )
+        for (index, (name, value)) in self.fields().into_iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{name}={value}")?;
+        }
+        Ok(())
     }
 }
 
@@ -180,6 +332,12 @@ pub struct Quant {
 
     /// outgoing quantity
     pub outgoing: Decimal,
+
+    /// free quantity in resupplying warehouses, counted as incoming and buildable
+    pub resupply: Decimal,
+
+    /// free quantity in the component warehouses, counted as buildable only
+    pub sourced: Decimal,
 }
 
 impl Quant {
@@ -188,6 +346,8 @@ impl Quant {
         reserved: Decimal::ZERO,
         incoming: Decimal::ZERO,
         outgoing: Decimal::ZERO,
+        resupply: Decimal::ZERO,
+        sourced: Decimal::ZERO,
     };
 }
 
@@ -198,6 +358,8 @@ impl Default for Quant {
             reserved: Decimal::ZERO,
             incoming: Decimal::ZERO,
             outgoing: Decimal::ZERO,
+            resupply: Decimal::ZERO,
+            sourced: Decimal::ZERO,
         }
     }
 }
@@ -221,11 +383,859 @@ pub struct Graph {
     /// Warehouse
     pub warehouse: Warehouse,
 
-    /// Cached availability for products
-    pub avail: HashMap<ProductId, Availability>,
+    /// Cached availability for products
+    pub avail: HashMap<ProductId, Availability>,
+
+    /// Products whose availability could not be computed, left out of the output
+    pub compute_errors: BTreeMap<ProductId, ComputeError>,
+
+    /// Raw quants in Odoo
+    pub raw_quants: HashMap<ProductId, Quant>,
+
+    /// Options for the quant and move aggregation
+    pub quant_options: QuantOptions,
+
+    /// Definition of the published `free_immediately`, also the free quantity behind
+    /// `free_weight`, `free_volume`, `days_of_cover`, `free_after_quotes` and `fill_rate`
+    pub free_formula: FreeFormula,
+
+    /// Whether kits also publish `kit_sets_available`
+    pub kit_semantics: KitSemantics,
+
+    /// Only use BoMs and commingled links shared between companies or owned by the warehouse's
+    /// company
+    pub company_scoped: bool,
+
+    /// How many times a collection phase is retried on transient source errors
+    pub source_retries: u32,
+
+    /// Per-phase `statement_timeout`; phases without an entry run unbounded
+    pub query_timeouts: HashMap<CollectPhase, Duration>,
+
+    /// How long each phase of the last collection took in the attempt that succeeded, in order
+    pub phase_timings: Vec<(CollectPhase, Duration)>,
+
+    /// Collect unit costs and publish valuation metrics
+    pub with_valuation: bool,
+
+    /// Average unit cost per product, only populated with `with_valuation`
+    pub unit_costs: HashMap<ProductId, Decimal>,
+
+    /// Collect unit weights/volumes and publish free stock weight/volume metrics
+    pub with_weight_volume: bool,
+
+    /// Unit weight and volume per product, only populated with `with_weight_volume`
+    pub dimensions: HashMap<ProductId, Dimensions>,
+
+    /// Collect outbound move history and publish velocity/ABC metrics
+    pub with_velocity: bool,
+
+    /// Publish days of cover from free stock and outbound history
+    pub with_days_of_cover: bool,
+
+    /// Publish the share of the outgoing demand the free stock covers
+    pub with_fill_rate: bool,
+
+    /// Lookback window for outbound move history
+    pub velocity_window: Duration,
+
+    /// Quantity moved out of the warehouse within `velocity_window`
+    pub outbound: HashMap<ProductId, Decimal>,
+
+    /// ABC class per computed product, derived from `outbound`
+    pub abc_classes: HashMap<ProductId, AbcClass>,
+
+    /// Collect draft/sent sale order quantities and publish soft reservations
+    pub with_quotations: bool,
+
+    /// Quantity on draft/sent sale orders, only populated with `with_quotations`
+    pub quoted: HashMap<ProductId, Decimal>,
+
+    /// Collect draft/sent purchase order quantities and publish unconfirmed incoming
+    pub with_draft_purchases: bool,
+
+    /// Quantity on draft/sent purchase orders, only populated with `with_draft_purchases`
+    pub draft_purchases: HashMap<ProductId, Decimal>,
+
+    /// Collect the quantity in hold locations and publish it as `on_hold`
+    pub with_on_hold: bool,
+
+    /// Quantity in the hold locations, only populated with `with_on_hold`
+    pub held: HashMap<ProductId, Decimal>,
+
+    /// Collect reservations per operation type and publish the `reserved_*` breakdown
+    pub with_reserved_breakdown: bool,
+
+    /// Reserved quantity per operation type, only populated with `with_reserved_breakdown`
+    pub reserved_breakdown: HashMap<ProductId, ReservedBreakdown>,
+
+    /// Collect the oldest open reservation per product and publish `oldest_reservation_days`
+    pub with_reservation_age: bool,
+
+    /// Whole days since the oldest open reservation, only populated with `with_reservation_age`
+    pub oldest_reservations: HashMap<ProductId, i32>,
+
+    /// Collect incoming moves per operation type and publish the `incoming_*` breakdown
+    pub with_incoming_breakdown: bool,
+
+    /// Incoming quantity per operation type, only populated with `with_incoming_breakdown`
+    pub incoming_breakdown: HashMap<ProductId, IncomingBreakdown>,
+
+    /// Collect free stock in the warehouses resupplying this one and publish `resuppliable`
+    pub with_resupply: bool,
+
+    /// Count `resuppliable` towards `incoming` and `buildable`, as if already on its way
+    pub include_resupply: bool,
+
+    /// Free quantity in the resupplying warehouses, only populated with `with_resupply`
+    pub resuppliable: HashMap<ProductId, Decimal>,
+
+    /// Longest delay of the rules on the warehouse's resupply routes, in days
+    pub resupply_lead_days: Option<i32>,
+
+    /// Warehouses whose free stock also counts towards the `buildable` of the kits and BoM
+    /// products using it as a component, while on-hand and reserved stay with this warehouse
+    pub component_warehouses: Vec<i32>,
+
+    /// Free quantity in `component_warehouses` other than this one
+    pub component_stock: HashMap<ProductId, Decimal>,
+
+    /// Publish the best `buildable` across a product's BoMs rather than the first one's
+    pub buildable_from_alternatives: bool,
+
+    /// Cap `buildable` by what the work centers of the first BoM's operations can make over this
+    /// horizon, published as `buildable_capacity_limited`
+    pub capacity_horizon: Option<Duration>,
+
+    /// Quantity the work centers can make over `capacity_horizon`, only populated with it, for
+    /// products whose first BoM has timed operations
+    pub capacity: HashMap<ProductId, Decimal>,
+
+    /// BoM each manufactured product is computed from, as picked by `products`
+    pub first_boms: HashMap<ProductId, i32>,
+
+    /// BoMs after the first per product, only populated with `buildable_from_alternatives`.
+    /// Their components are linked to the product by edges of quantity zero, so they are
+    /// computed first without counting towards the first BoM.
+    pub alternative_boms: HashMap<ProductId, Vec<AlternativeBom>>,
+
+    /// First BoM per product with a line in a UoM that cannot be converted to the component's.
+    /// The BoM's edges are left out and the product fails to compute rather than being built
+    /// from its other components.
+    pub invalid_boms: HashMap<ProductId, i32>,
+
+    /// Products asked for by the last `collect`, empty when every product was computed
+    pub requested_products: Vec<ProductId>,
+}
+
+/// Pareto classification by outbound quantity: `A` products make up the first 80% of the
+/// outbound volume, `B` the next 15% and `C` the remainder (including products with no outbound).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum AbcClass {
+    A,
+    B,
+    C,
+}
+
+impl AbcClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::A => "A",
+            Self::B => "B",
+            Self::C => "C",
+        }
+    }
+
+    pub fn classify(
+        products: &[ProductId],
+        outbound: &HashMap<ProductId, Decimal>,
+    ) -> HashMap<ProductId, AbcClass> {
+        let mut ranked: Vec<(ProductId, Decimal)> = products
+            .iter()
+            .map(|product| {
+                let quantity = outbound.get(product).copied().unwrap_or(Decimal::ZERO);
+                (*product, quantity.max(Decimal::ZERO))
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let total: Decimal = ranked.iter().map(|(_, quantity)| *quantity).sum();
+        let a_limit = total * Decimal::new(80, 2);
+        let b_limit = total * Decimal::new(95, 2);
+
+        let mut classes = HashMap::with_capacity(ranked.len());
+        let mut cumulative = Decimal::ZERO;
+        for (product, quantity) in ranked {
+            let class = if quantity.is_zero() {
+                AbcClass::C
+            } else if cumulative < a_limit {
+                AbcClass::A
+            } else if cumulative < b_limit {
+                AbcClass::B
+            } else {
+                AbcClass::C
+            };
+            cumulative += quantity;
+            let _ = classes.insert(product, class);
+        }
+
+        classes
+    }
+}
+
+impl fmt::Display for AbcClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dimensions {
+    /// weight of one unit, in the database's weight UoM
+    pub weight: Decimal,
+
+    /// volume of one unit, in the database's volume UoM
+    pub volume: Decimal,
+}
+
+/// Reserved quantity split by the operation type of the move it is reserved for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReservedBreakdown {
+    /// reserved for delivery orders
+    pub delivery: Decimal,
+
+    /// reserved for manufacturing orders' components
+    pub manufacturing: Decimal,
+
+    /// reserved for internal transfers and any other operation type
+    pub internal: Decimal,
+}
+
+/// Incoming quantity split by the operation type of the move bringing it in.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IncomingBreakdown {
+    /// receipts, i.e. on order from suppliers
+    pub purchase: Decimal,
+
+    /// finished products of manufacturing orders, i.e. in production
+    pub manufacturing: Decimal,
+
+    /// internal and inter-company transfers and any other operation type
+    pub internal: Decimal,
+}
+
+/// Whether a move brings stock into the warehouse or takes it out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveDirection {
+    /// counted as `incoming`
+    Incoming,
+    /// counted as `outgoing`
+    Outgoing,
+}
+
+impl MoveDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Incoming => "in",
+            Self::Outgoing => "out",
+        }
+    }
+}
+
+/// One open move contributing to a product's `incoming` or `outgoing`, for `--detail moves`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveDetail {
+    pub direction: MoveDirection,
+
+    /// the move's reference, i.e. its picking's name, falling back to the move's name
+    pub reference: String,
+
+    /// the picking's partner, falling back to the move's
+    pub partner: Option<String>,
+
+    /// scheduled date, RFC 3339 in UTC
+    pub date: String,
+
+    /// in the product's UoM
+    pub quantity: Decimal,
+}
+
+/// Optional per-product output columns, each only present when the feature producing it is
+/// enabled and the product has the underlying data.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProductMetrics {
+    /// on-hand quantity valued at the average unit cost
+    pub value: Option<Decimal>,
+
+    /// average unit cost from the stock valuation layers
+    pub unit_cost: Option<Decimal>,
+
+    /// total weight of the free stock
+    pub free_weight: Option<Decimal>,
+
+    /// total volume of the free stock
+    pub free_volume: Option<Decimal>,
+
+    /// average quantity moved out of the warehouse per day
+    pub outbound_velocity: Option<Decimal>,
+
+    /// ABC class by outbound quantity
+    pub abc_class: Option<AbcClass>,
+
+    /// days the free stock lasts at the average outbound rate
+    pub days_of_cover: Option<Decimal>,
+
+    /// quantity on draft/sent quotations
+    pub soft_reserved: Option<Decimal>,
+
+    /// free stock net of quotations
+    pub free_after_quotes: Option<Decimal>,
+
+    /// quantity on draft/sent purchase orders, kept out of `incoming`
+    pub incoming_unconfirmed: Option<Decimal>,
+
+    /// quantity in hold locations, kept out of `quantity`
+    pub on_hold: Option<Decimal>,
+
+    /// part of `reserved` for delivery orders
+    pub reserved_delivery: Option<Decimal>,
+
+    /// part of `reserved` for manufacturing orders
+    pub reserved_manufacturing: Option<Decimal>,
+
+    /// part of `reserved` for internal transfers and other operations
+    pub reserved_internal: Option<Decimal>,
+
+    /// part of `incoming` from receipts
+    pub incoming_purchase: Option<Decimal>,
+
+    /// part of `incoming` from manufacturing orders
+    pub incoming_manufacturing: Option<Decimal>,
+
+    /// part of `incoming` from internal transfers and other operations
+    pub incoming_internal: Option<Decimal>,
+
+    /// free quantity in the warehouses resupplying this one
+    pub resuppliable: Option<Decimal>,
+
+    /// longest delay in days of the rules on the warehouse's resupply routes
+    pub resupply_lead_days: Option<i32>,
+
+    /// complete kits the components' free stock makes up, for kits only
+    pub kit_sets_available: Option<Decimal>,
+
+    /// buildable, capped by what the work centers can make over the capacity horizon
+    pub buildable_capacity_limited: Option<Decimal>,
+
+    /// share of the outgoing demand the free stock covers, from 0 to 1
+    pub fill_rate: Option<Decimal>,
+
+    /// whole days since the oldest open reservation of the product's stock
+    pub oldest_reservation_days: Option<i32>,
+}
+
+impl ProductMetrics {
+    pub fn set_valuation(&mut self, quantity: Decimal, unit_cost: Decimal) {
+        self.unit_cost = Some(unit_cost);
+        self.value = Some(
+            (quantity * unit_cost)
+                .round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero),
+        );
+    }
+}
+
+impl ProductMetrics {
+    /// The metrics that were computed, as `(name, value)` pairs in output order.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        let decimals = [
+            ("value", self.value),
+            ("unit_cost", self.unit_cost),
+            ("free_weight", self.free_weight),
+            ("free_volume", self.free_volume),
+            ("outbound_velocity", self.outbound_velocity),
+        ];
+        for (name, value) in decimals {
+            if let Some(value) = value {
+                fields.push((name, value.to_string()));
+            }
+        }
+        if let Some(abc_class) = self.abc_class {
+            fields.push(("abc_class", abc_class.to_string()));
+        }
+        let decimals = [
+            ("days_of_cover", self.days_of_cover),
+            ("soft_reserved", self.soft_reserved),
+            ("free_after_quotes", self.free_after_quotes),
+            ("incoming_unconfirmed", self.incoming_unconfirmed),
+            ("on_hold", self.on_hold),
+            ("reserved_delivery", self.reserved_delivery),
+            ("reserved_manufacturing", self.reserved_manufacturing),
+            ("reserved_internal", self.reserved_internal),
+            ("incoming_purchase", self.incoming_purchase),
+            ("incoming_manufacturing", self.incoming_manufacturing),
+            ("incoming_internal", self.incoming_internal),
+            ("resuppliable", self.resuppliable),
+        ];
+        for (name, value) in decimals {
+            if let Some(value) = value {
+                fields.push((name, value.to_string()));
+            }
+        }
+        if let Some(lead_days) = self.resupply_lead_days {
+            fields.push(("resupply_lead_days", lead_days.to_string()));
+        }
+        if let Some(sets) = self.kit_sets_available {
+            fields.push(("kit_sets_available", sets.to_string()));
+        }
+        if let Some(buildable) = self.buildable_capacity_limited {
+            fields.push(("buildable_capacity_limited", buildable.to_string()));
+        }
+        if let Some(fill_rate) = self.fill_rate {
+            fields.push(("fill_rate", fill_rate.to_string()));
+        }
+        if let Some(days) = self.oldest_reservation_days {
+            fields.push(("oldest_reservation_days", days.to_string()));
+        }
+        fields
+    }
+}
+
+impl fmt::Display for ProductMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.fields() {
+            write!(f, ", {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The source reads performed by [`Graph::collect`], in order.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum CollectPhase {
+    Products,
+    Relations,
+    Quants,
+    Valuation,
+    Dimensions,
+    Velocity,
+    Quotations,
+    DraftPurchases,
+    OnHold,
+    ReservedBreakdown,
+    IncomingBreakdown,
+    Resupply,
+    ComponentStock,
+    Capacity,
+    ReservationAge,
+}
+
+impl CollectPhase {
+    pub const ALL: [Self; 15] = [
+        Self::Products,
+        Self::Relations,
+        Self::Quants,
+        Self::Valuation,
+        Self::Dimensions,
+        Self::Velocity,
+        Self::Quotations,
+        Self::DraftPurchases,
+        Self::OnHold,
+        Self::ReservedBreakdown,
+        Self::IncomingBreakdown,
+        Self::Resupply,
+        Self::ComponentStock,
+        Self::Capacity,
+        Self::ReservationAge,
+    ];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "products" => Some(Self::Products),
+            "relations" => Some(Self::Relations),
+            "quants" => Some(Self::Quants),
+            "valuation" => Some(Self::Valuation),
+            "dimensions" => Some(Self::Dimensions),
+            "velocity" => Some(Self::Velocity),
+            "quotations" => Some(Self::Quotations),
+            "draft_purchases" => Some(Self::DraftPurchases),
+            "on_hold" => Some(Self::OnHold),
+            "reserved_breakdown" => Some(Self::ReservedBreakdown),
+            "incoming_breakdown" => Some(Self::IncomingBreakdown),
+            "resupply" => Some(Self::Resupply),
+            "component_stock" => Some(Self::ComponentStock),
+            "capacity" => Some(Self::Capacity),
+            "reservation_age" => Some(Self::ReservationAge),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Products => "products",
+            Self::Relations => "relations",
+            Self::Quants => "quants",
+            Self::Valuation => "valuation",
+            Self::Dimensions => "dimensions",
+            Self::Velocity => "velocity",
+            Self::Quotations => "quotations",
+            Self::DraftPurchases => "draft_purchases",
+            Self::OnHold => "on_hold",
+            Self::ReservedBreakdown => "reserved_breakdown",
+            Self::IncomingBreakdown => "incoming_breakdown",
+            Self::Resupply => "resupply",
+            Self::ComponentStock => "component_stock",
+            Self::Capacity => "capacity",
+            Self::ReservationAge => "reservation_age",
+        }
+    }
+}
+
+/// Free stock divided by the average daily outbound quantity, rounded to one decimal place.
+/// Products with no outbound movement have no meaningful cover and yield `None`.
+pub fn days_of_cover(free: Decimal, daily_outbound: Decimal) -> Option<Decimal> {
+    if daily_outbound <= Decimal::ZERO {
+        return None;
+    }
+
+    Some(
+        (free / daily_outbound)
+            .round_dp_with_strategy(1, RoundingStrategy::ToZero)
+            .normalize(),
+    )
+}
+
+/// Quantity a work center can make over `horizon`, from its weekly working hours and the
+/// minutes the operations on it take per unit; `None` when they take no time.
+pub fn capacity_units(
+    weekly_hours: Decimal,
+    minutes_per_unit: Decimal,
+    horizon: Duration,
+    dp: u32,
+) -> Option<Decimal> {
+    if minutes_per_unit <= Decimal::ZERO {
+        return None;
+    }
+    let weeks = Decimal::from(horizon.as_secs()) / Decimal::from(7 * 86_400);
+    Some(
+        (weekly_hours.max(Decimal::ZERO) * Decimal::from(60) * weeks / minutes_per_unit)
+            .round_dp_with_strategy(dp, RoundingStrategy::ToZero)
+            .normalize(),
+    )
+}
+
+/// Whole BoM sets the components' free stock makes up, times the BoM quantity: unlike the
+/// per-field kit availability, a set short of any component does not count.
+pub fn complete_sets(terms: &[DependencyTerms], bom_qty: Decimal) -> Decimal {
+    let sets = terms
+        .iter()
+        .map(|terms| terms.free_immediately)
+        .min()
+        .unwrap_or(Decimal::ZERO);
+    (sets.floor() * bom_qty).normalize()
+}
+
+/// Replace the kits used by normal BoMs with their components, as Odoo explodes phantom BoMs
+/// into the lines of a manufacturing order: a component used both directly and through a kit
+/// then counts once with the total quantity. Kits without components, or within a cycle, stay
+/// as they are.
+pub fn explode_kits(
+    graph: &mut petgraph::graphmap::DiGraphMap<ProductId, Decimal>,
+    catalogue: &HashMap<ProductId, Product>,
+) {
+    let parents: Vec<ProductId> = graph
+        .nodes()
+        .filter(|product| matches!(catalogue.get(product), Some(Product::MrpNormal(_, _))))
+        .collect();
+
+    for parent in parents {
+        let kits: Vec<(ProductId, Decimal)> = graph
+            .edges_directed(parent, petgraph::Incoming)
+            .filter(|edge| {
+                *edge.weight() > Decimal::ZERO
+                    && matches!(
+                        catalogue.get(&edge.source()),
+                        Some(Product::MrpPhantom(_, _))
+                    )
+            })
+            .map(|edge| (edge.source(), *edge.weight()))
+            .collect();
+
+        for (kit, required_qty) in kits {
+            let mut path = vec![parent];
+            let Some(components) = kit_components(graph, catalogue, kit, required_qty, &mut path)
+            else {
+                continue;
+            };
+            let _ = graph.remove_edge(kit, parent);
+            for (component, required_qty) in components {
+                let weight = graph
+                    .edge_weight(component, parent)
+                    .copied()
+                    .unwrap_or(Decimal::ZERO);
+                let _ = graph.add_edge(component, parent, (weight + required_qty).normalize());
+            }
+        }
+    }
+}
+
+/// The components making up `required_qty` of `kit`, nested kits exploded in turn; `None` when
+/// the kit has no components or is already on `path`.
+fn kit_components(
+    graph: &petgraph::graphmap::DiGraphMap<ProductId, Decimal>,
+    catalogue: &HashMap<ProductId, Product>,
+    kit: ProductId,
+    required_qty: Decimal,
+    path: &mut Vec<ProductId>,
+) -> Option<Vec<(ProductId, Decimal)>> {
+    let Some(Product::MrpPhantom(bom_qty, _)) = catalogue.get(&kit) else {
+        return None;
+    };
+    if path.contains(&kit) || *bom_qty <= Decimal::ZERO {
+        return None;
+    }
+
+    path.push(kit);
+    let mut components = Vec::new();
+    for edge in graph.edges_directed(kit, petgraph::Incoming) {
+        if *edge.weight() <= Decimal::ZERO {
+            continue;
+        }
+        let component = edge.source();
+        let quantity = required_qty * *edge.weight() / *bom_qty;
+        match kit_components(graph, catalogue, component, quantity, path) {
+            Some(nested) => components.extend(nested),
+            None => components.push((component, quantity)),
+        }
+    }
+    let _ = path.pop();
+
+    (!components.is_empty()).then_some(components)
+}
+
+/// Free stock over the outgoing demand, capped at 1 and rounded to four decimal places. Products
+/// without outgoing demand have nothing to fill and yield `None`.
+pub fn fill_rate(free: Decimal, outgoing: Decimal) -> Option<Decimal> {
+    if outgoing <= Decimal::ZERO {
+        return None;
+    }
+
+    Some(
+        (free.max(Decimal::ZERO) / outgoing)
+            .min(Decimal::ONE)
+            .round_dp_with_strategy(4, RoundingStrategy::ToZero)
+            .normalize(),
+    )
+}
+
+/// Groups of products depending on each other through BoMs or commingled links, each as the
+/// shortest cycle through its lowest product id, in BoM order (each product uses the next).
+pub fn cycles(graph: &petgraph::graphmap::DiGraphMap<ProductId, Decimal>) -> Vec<Vec<ProductId>> {
+    let mut cycles: Vec<Vec<ProductId>> = petgraph::algo::tarjan_scc(graph)
+        .into_iter()
+        .filter_map(|mut group| {
+            group.sort_unstable();
+            let start = *group.first()?;
+            if group.len() == 1 && !graph.contains_edge(start, start) {
+                return None;
+            }
+            shortest_cycle(graph, start, &group)
+        })
+        .collect();
+    cycles.sort();
+    cycles
+}
+
+/// Breadth-first search for the shortest path from `start` back to itself, staying within
+/// `group` and walking from kits to their components.
+fn shortest_cycle(
+    graph: &petgraph::graphmap::DiGraphMap<ProductId, Decimal>,
+    start: ProductId,
+    group: &[ProductId],
+) -> Option<Vec<ProductId>> {
+    let mut previous: HashMap<ProductId, ProductId> = HashMap::new();
+    let mut queue = std::collections::VecDeque::from([start]);
+
+    while let Some(product) = queue.pop_front() {
+        for component in graph.neighbors_directed(product, petgraph::Incoming) {
+            if component == start {
+                let mut cycle = vec![product];
+                while let Some(kit) = previous.get(cycle.last()?) {
+                    cycle.push(*kit);
+                }
+                cycle.reverse();
+                return Some(cycle);
+            }
+            if group.binary_search(&component).is_ok() && !previous.contains_key(&component) {
+                let _ = previous.insert(component, product);
+                queue.push_back(component);
+            }
+        }
+    }
+
+    None
+}
+
+/// Why the availability of one product could not be computed. Only that product and the products
+/// built from it are left out; the rest of the run carries on.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ComputeError {
+    #[error("product {0} is in the BoM graph but not in the catalogue")]
+    NotInCatalogue(i32),
+    #[error("product {product_id} is of type {product_type}, which has no dependencies to combine")]
+    UnexpectedType {
+        product_id: i32,
+        product_type: &'static str,
+    },
+    #[error(
+        "product {product_id} uses BoM {bom_id}, which has a line in a UoM of another category than its component's"
+    )]
+    BomUomMismatch { product_id: i32, bom_id: i32 },
+    #[error("product {product_id} depends on product {dependency}, which could not be computed")]
+    Dependency { product_id: i32, dependency: i32 },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CollectError {
+    #[error("{phase} phase exceeded its query timeout of {timeout:?}")]
+    Timeout {
+        phase: &'static str,
+        timeout: Duration,
+    },
+    #[error(
+        "the product graph has {0} BoM/commingled cycles; run detect-cycles to list the products involved"
+    )]
+    Cycles(usize),
+    #[error(transparent)]
+    Interrupted(#[from] Interrupted),
+    #[error(transparent)]
+    Sql(#[from] sqlx::Error),
+}
+
+/// The products a collection covers, worked out once its relations are known.
+#[derive(Debug)]
+struct CollectionScope {
+    /// Every product, components before the products using them
+    sorted_nodes: Vec<ProductId>,
+
+    /// Requested products and their dependencies, `None` for every product
+    products: Option<HashSet<ProductId>>,
+
+    /// Ids of `products`, as the phase queries take them
+    product_ids: Option<Vec<i32>>,
+}
+
+/// A collection run phase by phase in one snapshot, which `collect_with_retry` restarts from its
+/// first phase when the snapshot breaks.
+#[async_trait]
+trait PhaseCollection: Send {
+    /// Opens a fresh snapshot, dropping the one of an earlier attempt.
+    async fn begin(&mut self) -> Result<(), CollectError>;
+
+    async fn run(&mut self, phase: CollectPhase) -> Result<(), CollectError>;
+
+    async fn commit(&mut self) -> Result<(), CollectError>;
+}
+
+/// Runs `phases` in order, restarting from the first one in a fresh snapshot up to `retries`
+/// times on transient errors, after `backoff` doubled per attempt.
+async fn collect_with_retry(
+    collection: &mut dyn PhaseCollection,
+    phases: &[CollectPhase],
+    retries: u32,
+    backoff: Duration,
+) -> Result<(), CollectError> {
+    let mut attempt = 0;
+    loop {
+        shutdown::check()?;
+        let mut result = collection.begin().await;
+        for phase in phases {
+            if result.is_err() {
+                break;
+            }
+            result = collection.run(*phase).await;
+        }
+        if result.is_ok() {
+            result = collection.commit().await;
+        }
+
+        match result {
+            Err(CollectError::Sql(err)) if attempt < retries && is_transient(&err) => {
+                attempt += 1;
+                let delay = backoff * (1 << attempt.min(5));
+                tracing::warn!(
+                    attempt,
+                    "Transient source error, restarting the collection in {delay:?}: {err}"
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The collection of a graph's phases, all in the same `REPEATABLE READ` snapshot.
+struct SnapshotCollection<'a> {
+    graph: &'a mut Graph,
+    snapshot: Option<Transaction<'static, Postgres>>,
+    scope: Option<CollectionScope>,
+}
+
+#[async_trait]
+impl PhaseCollection for SnapshotCollection<'_> {
+    async fn begin(&mut self) -> Result<(), CollectError> {
+        self.snapshot = None;
+        self.scope = None;
+        self.graph.phase_timings.clear();
+        self.snapshot = Some(self.graph.begin_snapshot().await?);
+        Ok(())
+    }
+
+    async fn run(&mut self, phase: CollectPhase) -> Result<(), CollectError> {
+        shutdown::check()?;
+        let Some(snapshot) = self.snapshot.as_mut() else {
+            return Err(sqlx::Error::PoolClosed.into());
+        };
+        let scoped_products = match phase {
+            CollectPhase::Products | CollectPhase::Relations => None,
+            _ => {
+                if self.scope.is_none() {
+                    self.scope = Some(self.graph.collection_scope()?);
+                }
+                self.scope
+                    .as_ref()
+                    .and_then(|scope| scope.product_ids.as_deref())
+            }
+        };
+
+        let timeout = self.graph.query_timeouts.get(&phase).copied();
+        if !self.graph.query_timeouts.is_empty() {
+            Graph::set_statement_timeout(snapshot, timeout).await?;
+        }
+        let started = Instant::now();
+        match self.graph.run_phase(phase, snapshot, scoped_products).await {
+            Ok(()) => {
+                self.graph.phase_timings.push((phase, started.elapsed()));
+                Ok(())
+            }
+            Err(sqlx::Error::Database(db_err))
+                if db_err.code().as_deref() == Some("57014") && timeout.is_some() =>
+            {
+                Err(CollectError::Timeout {
+                    phase: phase.as_str(),
+                    timeout: timeout.unwrap_or_default(),
+                })
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
 
-    /// Raw quants in Odoo
-    pub raw_quants: HashMap<ProductId, Quant>,
+    async fn commit(&mut self) -> Result<(), CollectError> {
+        if let Some(snapshot) = self.snapshot.take() {
+            snapshot.commit().await?;
+        }
+        Ok(())
+    }
 }
 
 impl Graph {
@@ -242,9 +1252,52 @@ impl Graph {
             decimal_precision,
             graph: petgraph::graphmap::DiGraphMap::new(),
             raw_quants: HashMap::new(),
+            quant_options: QuantOptions::default(),
+            free_formula: FreeFormula::default(),
+            kit_semantics: KitSemantics::default(),
+            company_scoped: false,
             avail: HashMap::new(),
+            compute_errors: BTreeMap::new(),
             catalogue: HashMap::new(),
             warehouse,
+            source_retries: 0,
+            query_timeouts: HashMap::new(),
+            phase_timings: Vec::new(),
+            with_valuation: false,
+            unit_costs: HashMap::new(),
+            with_weight_volume: false,
+            dimensions: HashMap::new(),
+            with_velocity: false,
+            with_days_of_cover: false,
+            with_fill_rate: false,
+            velocity_window: Duration::from_secs(90 * 24 * 60 * 60),
+            outbound: HashMap::new(),
+            abc_classes: HashMap::new(),
+            with_quotations: false,
+            quoted: HashMap::new(),
+            with_draft_purchases: false,
+            draft_purchases: HashMap::new(),
+            with_on_hold: false,
+            held: HashMap::new(),
+            with_reserved_breakdown: false,
+            reserved_breakdown: HashMap::new(),
+            with_reservation_age: false,
+            oldest_reservations: HashMap::new(),
+            with_incoming_breakdown: false,
+            incoming_breakdown: HashMap::new(),
+            with_resupply: false,
+            include_resupply: false,
+            resuppliable: HashMap::new(),
+            resupply_lead_days: None,
+            component_warehouses: Vec::new(),
+            component_stock: HashMap::new(),
+            capacity_horizon: None,
+            capacity: HashMap::new(),
+            buildable_from_alternatives: false,
+            first_boms: HashMap::new(),
+            alternative_boms: HashMap::new(),
+            invalid_boms: HashMap::new(),
+            requested_products: Vec::new(),
         })
     }
 
@@ -261,6 +1314,270 @@ impl Graph {
         Ok(digits.0 as u32)
     }
 
+    /// Starts the read-only `REPEATABLE READ` transaction all source reads of a run share, so
+    /// products, relations, quants and moves are read from one consistent snapshot.
+    async fn begin_snapshot(&self) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+        let mut snapshot = self.pool.begin().await?;
+        let _ = sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut *snapshot)
+            .await?;
+        Ok(snapshot)
+    }
+
+    /// Collects `phases` in one snapshot, retrying up to `source_retries` times on transient
+    /// errors. A retry discards the broken snapshot and restarts from the first phase in a fresh
+    /// one, so every phase of a collection reads the same snapshot.
+    async fn collect_phases_with_retry(
+        &mut self,
+        phases: &[CollectPhase],
+    ) -> Result<Option<CollectionScope>, CollectError> {
+        let retries = self.source_retries;
+        let mut collection = SnapshotCollection {
+            graph: self,
+            snapshot: None,
+            scope: None,
+        };
+        collect_with_retry(&mut collection, phases, retries, Duration::from_secs(1)).await?;
+        Ok(collection.scope)
+    }
+
+    /// The products in computation order and, for a scoped run, the requested products with
+    /// their dependencies, failing on BoM/commingled cycles.
+    fn collection_scope(&self) -> Result<CollectionScope, CollectError> {
+        let sorted_nodes = petgraph::algo::toposort(&self.graph, None)
+            .map_err(|_| CollectError::Cycles(cycles(&self.graph).len()))?;
+
+        let products = if self.requested_products.is_empty() {
+            None
+        } else {
+            Some(Self::dependency_closure(
+                &self.graph,
+                &self.requested_products,
+            ))
+        };
+
+        let product_ids = products.as_ref().map(|products| {
+            let mut ids = Vec::with_capacity(products.len());
+            for product in products {
+                ids.push(product.0);
+            }
+            ids
+        });
+
+        Ok(CollectionScope {
+            sorted_nodes,
+            products,
+            product_ids,
+        })
+    }
+
+    async fn set_statement_timeout(
+        conn: &mut PgConnection,
+        timeout: Option<Duration>,
+    ) -> Result<(), sqlx::Error> {
+        let millis = timeout.map_or(0, |timeout| timeout.as_millis().max(1));
+        let _ = sqlx::query(&format!("SET LOCAL statement_timeout = {millis}"))
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Company the BoMs, commingled links and unit costs are scoped to, if any.
+    pub fn bom_company(&self) -> Option<i32> {
+        self.company_scoped.then_some(self.warehouse.company_id)
+    }
+
+    async fn run_phase(
+        &mut self,
+        phase: CollectPhase,
+        conn: &mut PgConnection,
+        scoped_products: Option<&[i32]>,
+    ) -> Result<(), sqlx::Error> {
+        match phase {
+            CollectPhase::Products => {
+                self.catalogue.clear();
+                self.first_boms.clear();
+                self.graph.clear();
+                self.adapter
+                    .products(
+                        conn,
+                        self.bom_company(),
+                        &mut self.catalogue,
+                        &mut self.first_boms,
+                        &mut self.graph,
+                    )
+                    .await
+            }
+            CollectPhase::Relations => {
+                // Exploded kits would otherwise add up again when a retry re-reads the edges
+                let nodes: Vec<ProductId> = self.graph.nodes().collect();
+                self.graph.clear();
+                for node in nodes {
+                    let _ = self.graph.add_node(node);
+                }
+                self.alternative_boms.clear();
+                self.adapter
+                    .relations(
+                        conn,
+                        self.bom_company(),
+                        &mut self.graph,
+                        &self.first_boms,
+                        self.buildable_from_alternatives
+                            .then_some(&mut self.alternative_boms),
+                        &mut self.invalid_boms,
+                    )
+                    .await?;
+                explode_kits(&mut self.graph, &self.catalogue);
+                Ok(())
+            }
+            CollectPhase::Quants => {
+                self.adapter
+                    .quants(
+                        conn,
+                        self.warehouse
+                            .stock_location_path(self.quant_options.odoo_forecast),
+                        scoped_products,
+                        self.decimal_precision,
+                        &self.quant_options,
+                        &mut self.raw_quants,
+                    )
+                    .await
+            }
+            CollectPhase::Valuation => {
+                self.adapter
+                    .unit_costs(
+                        conn,
+                        self.bom_company(),
+                        scoped_products,
+                        &mut self.unit_costs,
+                    )
+                    .await
+            }
+            CollectPhase::Dimensions => {
+                self.adapter
+                    .dimensions(conn, scoped_products, &mut self.dimensions)
+                    .await
+            }
+            CollectPhase::Velocity => {
+                self.adapter
+                    .outbound_history(
+                        conn,
+                        &self.warehouse.location_path,
+                        self.velocity_window,
+                        scoped_products,
+                        &mut self.outbound,
+                    )
+                    .await
+            }
+            CollectPhase::Quotations => {
+                self.adapter
+                    .quotations(conn, self.warehouse.id.0, scoped_products, &mut self.quoted)
+                    .await
+            }
+            CollectPhase::DraftPurchases => {
+                self.adapter
+                    .draft_purchases(
+                        conn,
+                        self.warehouse.id.0,
+                        scoped_products,
+                        &mut self.draft_purchases,
+                    )
+                    .await
+            }
+            CollectPhase::OnHold => {
+                self.adapter
+                    .held_quants(
+                        conn,
+                        self.warehouse
+                            .stock_location_path(self.quant_options.odoo_forecast),
+                        &self.quant_options.hold_locations,
+                        scoped_products,
+                        &mut self.held,
+                    )
+                    .await
+            }
+            CollectPhase::ReservedBreakdown => {
+                self.adapter
+                    .reserved_breakdown(
+                        conn,
+                        self.warehouse
+                            .stock_location_path(self.quant_options.odoo_forecast),
+                        &self.quant_options,
+                        scoped_products,
+                        &mut self.reserved_breakdown,
+                    )
+                    .await
+            }
+            CollectPhase::IncomingBreakdown => {
+                self.adapter
+                    .incoming_breakdown(
+                        conn,
+                        self.warehouse
+                            .stock_location_path(self.quant_options.odoo_forecast),
+                        scoped_products,
+                        &self.quant_options,
+                        &mut self.incoming_breakdown,
+                    )
+                    .await
+            }
+            CollectPhase::Resupply => {
+                self.resupply_lead_days = self
+                    .adapter
+                    .resupply(
+                        conn,
+                        self.warehouse.id.0,
+                        &self.quant_options.hold_locations,
+                        scoped_products,
+                        &mut self.resuppliable,
+                    )
+                    .await?;
+                Ok(())
+            }
+            CollectPhase::ComponentStock => {
+                let warehouse_id = self.warehouse.id.0;
+                let warehouse_ids: Vec<i32> = self
+                    .component_warehouses
+                    .iter()
+                    .copied()
+                    .filter(|id| *id != warehouse_id)
+                    .collect();
+                self.adapter
+                    .warehouses_free(
+                        conn,
+                        &warehouse_ids,
+                        &self.quant_options.hold_locations,
+                        scoped_products,
+                        &mut self.component_stock,
+                    )
+                    .await
+            }
+            CollectPhase::Capacity => {
+                let horizon = self.capacity_horizon.unwrap_or_default();
+                self.adapter
+                    .work_center_capacity(
+                        conn,
+                        &self.first_boms,
+                        horizon,
+                        scoped_products,
+                        &mut self.capacity,
+                    )
+                    .await
+            }
+            CollectPhase::ReservationAge => {
+                self.adapter
+                    .oldest_reservations(
+                        conn,
+                        self.warehouse
+                            .stock_location_path(self.quant_options.odoo_forecast),
+                        &self.quant_options,
+                        scoped_products,
+                        &mut self.oldest_reservations,
+                    )
+                    .await
+            }
+        }
+    }
+
     fn dependency_closure(
         graph: &petgraph::graphmap::DiGraphMap<ProductId, Decimal>,
         requested_products: &[ProductId],
@@ -285,68 +1602,132 @@ impl Graph {
         closure
     }
 
-    pub async fn collect(&mut self, requested_products: &[ProductId]) -> Result<(), sqlx::Error> {
+    /// Only build the product graph, without collecting quants or computing availability.
+    pub async fn collect_relations(&mut self) -> Result<(), CollectError> {
         tracing::info!("Building graph");
 
-        self.catalogue.clear();
-        self.graph.clear();
-
-        self.adapter
-            .products(&self.pool, &mut self.catalogue, &mut self.graph)
+        let _ = self
+            .collect_phases_with_retry(&[CollectPhase::Products, CollectPhase::Relations])
             .await?;
-        self.adapter.relations(&self.pool, &mut self.graph).await?;
 
-        let sorted_nodes = petgraph::algo::toposort(&self.graph, None).expect("Graph has cycles!");
+        Ok(())
+    }
+
+    pub async fn collect(&mut self, requested_products: &[ProductId]) -> Result<(), CollectError> {
+        self.requested_products = requested_products.to_vec();
+        self.collect_phases(true).await
+    }
 
-        let scope = if requested_products.is_empty() {
-            None
-        } else {
-            Some(Self::dependency_closure(&self.graph, requested_products))
+    /// Recompute availability of the same products for another warehouse or location, reusing
+    /// the graph unless `company_scoped` calls for the BoMs of another company.
+    pub async fn rescope(&mut self, warehouse: Warehouse) -> Result<(), CollectError> {
+        let relations = self.company_scoped && warehouse.company_id != self.warehouse.company_id;
+        self.warehouse = warehouse;
+        self.collect_phases(relations).await
+    }
+
+    /// The phases a collection runs, in order.
+    fn collect_plan(&self, relations: bool) -> Vec<CollectPhase> {
+        let mut phases = Vec::new();
+        if relations {
+            phases.extend([CollectPhase::Products, CollectPhase::Relations]);
+        }
+        phases.push(CollectPhase::Quants);
+        let optional = [
+            (self.with_valuation, CollectPhase::Valuation),
+            (self.with_weight_volume, CollectPhase::Dimensions),
+            (
+                self.with_velocity || self.with_days_of_cover,
+                CollectPhase::Velocity,
+            ),
+            (self.with_quotations, CollectPhase::Quotations),
+            (self.with_draft_purchases, CollectPhase::DraftPurchases),
+            (self.with_on_hold, CollectPhase::OnHold),
+            (
+                self.with_reserved_breakdown,
+                CollectPhase::ReservedBreakdown,
+            ),
+            (self.with_reservation_age, CollectPhase::ReservationAge),
+            (
+                self.with_incoming_breakdown,
+                CollectPhase::IncomingBreakdown,
+            ),
+            (self.with_resupply, CollectPhase::Resupply),
+            (
+                !self.component_warehouses.is_empty(),
+                CollectPhase::ComponentStock,
+            ),
+            (self.capacity_horizon.is_some(), CollectPhase::Capacity),
+        ];
+        for (enabled, phase) in optional {
+            if enabled {
+                phases.push(phase);
+            }
+        }
+        phases
+    }
+
+    async fn collect_phases(&mut self, relations: bool) -> Result<(), CollectError> {
+        if relations {
+            tracing::info!("Building graph");
+        }
+        let phases = self.collect_plan(relations);
+        let scope = match self.collect_phases_with_retry(&phases).await? {
+            Some(scope) => scope,
+            None => self.collection_scope()?,
         };
 
-        let scoped_product_ids = scope.as_ref().map(|products| {
-            let mut ids = Vec::with_capacity(products.len());
-            for product in products {
-                ids.push(product.0);
+        if self.with_resupply && self.include_resupply {
+            for (product_id, quantity) in &self.resuppliable {
+                if *quantity > Decimal::ZERO {
+                    self.raw_quants.entry(*product_id).or_default().resupply = *quantity;
+                }
             }
-            ids
-        });
+        }
 
-        self.adapter
-            .quants(
-                &self.pool,
-                &self.warehouse.location_path,
-                scoped_product_ids.as_deref(),
-                self.decimal_precision,
-                &mut self.raw_quants,
-            )
-            .await?;
+        if !self.component_warehouses.is_empty() {
+            for (product_id, quantity) in &self.component_stock {
+                if *quantity > Decimal::ZERO {
+                    self.raw_quants.entry(*product_id).or_default().sourced = *quantity;
+                }
+            }
+        }
 
         tracing::info!("Pre-computing stock levels");
         self.avail.clear();
-        Self::compute_stock_levels(
+        self.compute_errors = Self::compute_stock_levels(
             &self.graph,
             &self.catalogue,
+            &self.alternative_boms,
+            &self.invalid_boms,
             &mut self.avail,
             &self.raw_quants,
-            &sorted_nodes,
-            scope.as_ref(),
+            &scope.sorted_nodes,
+            scope.products.as_ref(),
             self.decimal_precision,
         );
         tracing::info!("Pre-computing done");
 
+        if self.with_velocity {
+            self.abc_classes = AbcClass::classify(&self.computed_products(), &self.outbound);
+        }
+
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn compute_stock_levels(
         graph: &petgraph::graphmap::DiGraphMap<ProductId, Decimal>,
         catalogue: &HashMap<ProductId, Product>,
+        alternative_boms: &HashMap<ProductId, Vec<AlternativeBom>>,
+        invalid_boms: &HashMap<ProductId, i32>,
         stock_cache: &mut HashMap<ProductId, Availability>,
         raw_quants: &HashMap<ProductId, Quant>,
         sorted_nodes: &[ProductId],
         scope: Option<&HashSet<ProductId>>,
         default_dp: u32,
-    ) {
+    ) -> BTreeMap<ProductId, ComputeError> {
+        let mut errors = BTreeMap::new();
         let zero = Decimal::ZERO.round_dp_with_strategy(default_dp, RoundingStrategy::ToZero);
 
         // Iterate in topological order
@@ -366,12 +1747,20 @@ impl Graph {
                 continue;
             }
 
-            let info = catalogue.get(&product).unwrap_or_else(|| {
-                panic!(
-                    "Somehow we have a product in the graph not in the catalogue?!: {:?}",
-                    product
-                )
-            });
+            let Some(info) = catalogue.get(&product) else {
+                let _ = errors.insert(product, ComputeError::NotInCatalogue(product.0));
+                continue;
+            };
+            if let Some(bom_id) = invalid_boms.get(&product) {
+                let _ = errors.insert(
+                    product,
+                    ComputeError::BomUomMismatch {
+                        product_id: product.0,
+                        bom_id: *bom_id,
+                    },
+                );
+                continue;
+            }
             if info.is_simple() {
                 let dp = info.dp();
                 let mut avail = Availability::default();
@@ -384,29 +1773,36 @@ impl Graph {
                         .reserved
                         .round_dp_with_strategy(dp, RoundingStrategy::ToZero);
 
+                    let resupply = quant
+                        .resupply
+                        .round_dp_with_strategy(dp, RoundingStrategy::ToZero);
                     avail.incoming = quant
                         .incoming
-                        .round_dp_with_strategy(dp, RoundingStrategy::ToZero);
+                        .round_dp_with_strategy(dp, RoundingStrategy::ToZero)
+                        + resupply;
                     avail.outgoing = quant
                         .outgoing
                         .round_dp_with_strategy(dp, RoundingStrategy::ToZero);
                     // Required to seed the buildable for future things
                     // Realistically this isn't actually helpful as a figure for a simple, but this
                     // is the least impactful solution here
-                    avail.buildable = avail.free_immediately();
+                    avail.buildable = avail.free_immediately()
+                        + resupply
+                        + quant
+                            .sourced
+                            .round_dp_with_strategy(dp, RoundingStrategy::ToZero);
+                }
+
+                if info.is_integral() {
+                    avail.floor_to_integer();
                 }
 
                 let _ = stock_cache.insert(product, avail);
                 continue;
             }
 
-            let mut quantity = Vec::new();
-            let mut reserved = Vec::new();
-            let mut incoming = Vec::new();
-            let mut outgoing = Vec::new();
-            let mut buildable = Vec::new();
-            let mut free_imm = Vec::new();
-            let mut virtual_avail = Vec::new();
+            let mut terms = Vec::new();
+            let mut failed_dependency = None;
 
             // Iterate dependencies (incoming edges)
             for edge in graph.edges_directed(product, petgraph::Incoming) {
@@ -414,48 +1810,35 @@ impl Graph {
                 if required_qty <= Decimal::ZERO {
                     continue;
                 }
+                if errors.contains_key(&dependency) {
+                    failed_dependency = Some(dependency);
+                    break;
+                }
                 if let Some(dependency_stock) = stock_cache.get(&dependency) {
-                    let dependency_dp = catalogue.get(&product).unwrap_or_else(|| {
-                        panic!(
-                            "Somehow we have a product in the graph not in the catalogue?!: {:?}",
-                            product
-                        )
-                    }).dp();
-
-                    // only do this work if we need to
-                    quantity.push(
-                        (dependency_stock.quantity / required_qty)
-                            .round_dp_with_strategy(dependency_dp, RoundingStrategy::ToZero),
-                    );
-                    reserved.push(
-                        (dependency_stock.reserved / required_qty)
-                            .round_dp_with_strategy(dependency_dp, RoundingStrategy::ToZero),
-                    );
-
-                    incoming.push(
-                        (dependency_stock.incoming / required_qty)
-                            .round_dp_with_strategy(dependency_dp, RoundingStrategy::ToZero),
-                    );
-                    outgoing.push(
-                        (dependency_stock.outgoing / required_qty)
-                            .round_dp_with_strategy(dependency_dp, RoundingStrategy::ToZero),
-                    );
-
-                    free_imm.push(
-                        (dependency_stock.free_immediately() / required_qty)
-                            .round_dp_with_strategy(dependency_dp, RoundingStrategy::ToZero),
-                    );
-                    virtual_avail.push(
-                        (dependency_stock.virtual_available() / required_qty)
-                            .round_dp_with_strategy(dependency_dp, RoundingStrategy::ToZero),
-                    );
-
-                    buildable.push(
-                        (dependency_stock.buildable / required_qty)
-                            .round_dp_with_strategy(dependency_dp, RoundingStrategy::ToZero),
-                    );
+                    terms.push(DependencyTerms::new(
+                        dependency_stock,
+                        required_qty,
+                        info.dp(),
+                    ));
                 }
             }
+            // Computing without the failed input would overstate what can be built
+            if let Some(dependency) = failed_dependency {
+                let _ = errors.insert(
+                    product,
+                    ComputeError::Dependency {
+                        product_id: product.0,
+                        dependency: dependency.0,
+                    },
+                );
+                continue;
+            }
+            let min = |field: fn(&DependencyTerms) -> Decimal| {
+                terms.iter().map(field).min().unwrap_or(zero)
+            };
+            let sum = |field: fn(&DependencyTerms) -> Decimal| {
+                terms.iter().map(field).fold(zero, |acc, x| acc + x)
+            };
 
             match info {
                 Product::MrpPhantom(decimal, dp) => {
@@ -465,13 +1848,13 @@ impl Graph {
                     // Taking min(reserved) and min(outgoing) independently is wrong because the
                     // minima can come from different dependencies, making quantity - reserved
                     // meaningless.
-                    let qty = (*quantity.iter().min().unwrap_or(&zero) * decimal)
+                    let qty = (min(|terms| terms.quantity) * decimal)
                         .round_dp_with_strategy(*dp, RoundingStrategy::ToZero);
-                    let inc = (*incoming.iter().min().unwrap_or(&zero) * decimal)
+                    let inc = (min(|terms| terms.incoming) * decimal)
                         .round_dp_with_strategy(*dp, RoundingStrategy::ToZero);
-                    let free = (*free_imm.iter().min().unwrap_or(&zero) * decimal)
+                    let free = (min(|terms| terms.free_immediately) * decimal)
                         .round_dp_with_strategy(*dp, RoundingStrategy::ToZero);
-                    let virt = (*virtual_avail.iter().min().unwrap_or(&zero) * decimal)
+                    let virt = (min(|terms| terms.virtual_available) * decimal)
                         .round_dp_with_strategy(*dp, RoundingStrategy::ToZero);
 
                     // If it has dependencies, store the calculated stock
@@ -482,7 +1865,7 @@ impl Graph {
                             reserved: qty - free,
                             incoming: inc,
                             outgoing: qty + inc - virt,
-                            buildable: *buildable.iter().min().unwrap_or(&zero)
+                            buildable: min(|terms| terms.buildable)
                                 * decimal.round_dp_with_strategy(*dp, RoundingStrategy::ToZero),
                         },
                     );
@@ -502,7 +1885,7 @@ impl Graph {
                             reserved: raw.reserved,
                             incoming: raw.incoming,
                             outgoing: raw.outgoing,
-                            buildable: *buildable.iter().min().unwrap_or(&zero)
+                            buildable: min(|terms| terms.buildable)
                                 * decimal.round_dp_with_strategy(*dp, RoundingStrategy::ToZero),
                         },
                     );
@@ -511,40 +1894,272 @@ impl Graph {
                     let _ = stock_cache.insert(
                         product,
                         Availability {
-                            quantity: (quantity.iter().fold(zero, |acc, x: &Decimal| acc + x))
+                            quantity: sum(|terms| terms.quantity)
                                 .round_dp_with_strategy(*dp, RoundingStrategy::ToZero),
-                            reserved: (reserved.iter().fold(zero, |acc, x: &Decimal| acc + x))
+                            reserved: sum(|terms| terms.reserved)
                                 .round_dp_with_strategy(*dp, RoundingStrategy::ToZero),
-                            incoming: (incoming.iter().fold(zero, |acc, x: &Decimal| acc + x))
+                            incoming: sum(|terms| terms.incoming)
                                 .round_dp_with_strategy(*dp, RoundingStrategy::ToZero),
-                            outgoing: (outgoing.iter().fold(zero, |acc, x: &Decimal| acc + x))
+                            outgoing: sum(|terms| terms.outgoing)
                                 .round_dp_with_strategy(*dp, RoundingStrategy::ToZero),
-                            buildable: (buildable.iter().fold(zero, |acc, x: &Decimal| acc + x))
+                            buildable: sum(|terms| terms.buildable)
                                 .round_dp_with_strategy(*dp, RoundingStrategy::ToZero),
                         },
                     );
                 }
-                _ => unimplemented!(),
+                Product::Simple(_) => {
+                    let _ = errors.insert(
+                        product,
+                        ComputeError::UnexpectedType {
+                            product_id: product.0,
+                            product_type: info.type_label(),
+                        },
+                    );
+                    continue;
+                }
+            }
+
+            if let Some(boms) = alternative_boms.get(&product) {
+                let best = boms
+                    .iter()
+                    .filter_map(|bom| bom.buildable(stock_cache, info.dp()))
+                    .max();
+                if let (Some(best), Some(avail)) = (best, stock_cache.get_mut(&product)) {
+                    avail.buildable = avail.buildable.max(best);
+                }
+            }
+
+            // Kit divisions can leave fractions behind even after rounding each input
+            if info.is_integral() {
+                if let Some(avail) = stock_cache.get_mut(&product) {
+                    avail.floor_to_integer();
+                }
             }
         }
+
+        errors
     }
 
     pub fn get(&self, product_id: &ProductId) -> Option<&Availability> {
         self.avail.get(product_id)
     }
 
+    pub fn metrics(&self, product_id: &ProductId, mode: AvailabilityOutputMode) -> ProductMetrics {
+        let mut metrics = ProductMetrics::default();
+
+        if let (Some(availability), Some(unit_cost)) =
+            (self.avail.get(product_id), self.unit_costs.get(product_id))
+        {
+            metrics.set_valuation(mode.project(availability.quantity), *unit_cost);
+        }
+
+        if let (Some(availability), Some(dimensions)) =
+            (self.avail.get(product_id), self.dimensions.get(product_id))
+        {
+            let free = mode.project(availability.free(self.free_formula));
+            metrics.free_weight = Some((free * dimensions.weight).normalize());
+            metrics.free_volume = Some((free * dimensions.volume).normalize());
+        }
+
+        if let Some(availability) = self.avail.get(product_id) {
+            let outbound = self
+                .outbound
+                .get(product_id)
+                .copied()
+                .unwrap_or(Decimal::ZERO);
+            let velocity = outbound / self.velocity_window_days();
+
+            if self.with_velocity {
+                metrics.outbound_velocity = Some(
+                    velocity
+                        .round_dp_with_strategy(4, RoundingStrategy::MidpointAwayFromZero)
+                        .normalize(),
+                );
+                metrics.abc_class = self.abc_classes.get(product_id).copied();
+            }
+
+            if self.with_days_of_cover {
+                metrics.days_of_cover =
+                    days_of_cover(mode.project(availability.free(self.free_formula)), velocity);
+            }
+
+            if self.with_fill_rate {
+                metrics.fill_rate =
+                    fill_rate(availability.free(self.free_formula), availability.outgoing);
+            }
+
+            if self.with_quotations {
+                let quoted = self
+                    .quoted
+                    .get(product_id)
+                    .copied()
+                    .unwrap_or(Decimal::ZERO);
+                metrics.soft_reserved = Some(mode.project(quoted));
+                metrics.free_after_quotes =
+                    Some(mode.project(availability.free(self.free_formula) - quoted));
+            }
+
+            if self.with_draft_purchases {
+                metrics.incoming_unconfirmed = Some(
+                    mode.project(
+                        self.draft_purchases
+                            .get(product_id)
+                            .copied()
+                            .unwrap_or(Decimal::ZERO),
+                    ),
+                );
+            }
+        }
+
+        if self.with_resupply {
+            metrics.resuppliable = Some(
+                mode.project(
+                    self.resuppliable
+                        .get(product_id)
+                        .copied()
+                        .unwrap_or(Decimal::ZERO),
+                ),
+            );
+            metrics.resupply_lead_days = self.resupply_lead_days;
+        }
+
+        if self.with_on_hold {
+            metrics.on_hold =
+                Some(mode.project(self.held.get(product_id).copied().unwrap_or(Decimal::ZERO)));
+        }
+
+        if self.with_reserved_breakdown {
+            let breakdown = self
+                .reserved_breakdown
+                .get(product_id)
+                .copied()
+                .unwrap_or_default();
+            metrics.reserved_delivery = Some(mode.project(breakdown.delivery));
+            metrics.reserved_manufacturing = Some(mode.project(breakdown.manufacturing));
+            metrics.reserved_internal = Some(mode.project(breakdown.internal));
+        }
+
+        if self.with_reservation_age {
+            metrics.oldest_reservation_days = self.oldest_reservations.get(product_id).copied();
+        }
+
+        if self.with_incoming_breakdown {
+            let breakdown = self
+                .incoming_breakdown
+                .get(product_id)
+                .copied()
+                .unwrap_or_default();
+            metrics.incoming_purchase = Some(mode.project(breakdown.purchase));
+            metrics.incoming_manufacturing = Some(mode.project(breakdown.manufacturing));
+            metrics.incoming_internal = Some(mode.project(breakdown.internal));
+        }
+
+        if let (Some(_), Some(availability)) = (self.capacity_horizon, self.avail.get(product_id)) {
+            let buildable = match self.capacity.get(product_id) {
+                Some(capacity) => availability.buildable.min(*capacity),
+                None => availability.buildable,
+            };
+            metrics.buildable_capacity_limited = Some(mode.project(buildable));
+        }
+
+        if self.kit_semantics == KitSemantics::CompleteSets {
+            metrics.kit_sets_available = self
+                .kit_sets_available(*product_id)
+                .map(|sets| mode.project(sets));
+        }
+
+        metrics
+    }
+
+    /// Complete sets of a kit's BoM the free stock of its components makes up, in kit units;
+    /// `None` for products other than kits.
+    fn kit_sets_available(&self, product_id: ProductId) -> Option<Decimal> {
+        let Some(Product::MrpPhantom(bom_qty, dp)) = self.catalogue.get(&product_id) else {
+            return None;
+        };
+        let terms: Vec<DependencyTerms> = self
+            .graph
+            .edges_directed(product_id, petgraph::Incoming)
+            .filter(|edge| *edge.weight() > Decimal::ZERO)
+            .filter_map(|edge| {
+                let dependency = self.avail.get(&edge.source())?;
+                Some(DependencyTerms::new(dependency, *edge.weight(), *dp))
+            })
+            .collect();
+        Some(complete_sets(&terms, *bom_qty))
+    }
+
+    fn velocity_window_days(&self) -> Decimal {
+        let days = Decimal::from(self.velocity_window.as_secs()) / Decimal::from(86_400);
+        days.max(Decimal::new(1, 3))
+    }
+
+    /// Availability of `products` (every computed product when empty, in id order) as a stream,
+    /// each converted to its output form only when polled, so embedding services can consume
+    /// results incrementally and apply backpressure. Products without availability are skipped.
+    pub fn stream_availability(
+        &self,
+        products: &[ProductId],
+        mode: AvailabilityOutputMode,
+    ) -> impl Stream<Item = (ProductId, OutputAvailability)> + '_ {
+        let products = if products.is_empty() {
+            self.computed_products()
+        } else {
+            products.to_vec()
+        };
+        futures::stream::iter(products.into_iter().filter_map(move |product| {
+            self.avail
+                .get(&product)
+                .map(|availability| (product, availability.output(mode, self.free_formula)))
+        }))
+    }
+
     pub fn computed_products(&self) -> Vec<ProductId> {
         let mut products: Vec<ProductId> = self.avail.keys().copied().collect();
         products.sort_unstable();
         products
     }
 
+    /// The inputs of a computed product's availability, for `--explain`.
+    pub fn explain(&self, product_id: ProductId) -> Option<Explanation> {
+        let product = *self.catalogue.get(&product_id)?;
+        let availability = self.avail.get(&product_id)?.clone();
+
+        let mut dependencies: Vec<ExplainedDependency> = self
+            .graph
+            .edges_directed(product_id, petgraph::Incoming)
+            .filter_map(|edge| {
+                let (dependency, required_qty) = (edge.source(), *edge.weight());
+                let dependency_availability = self.avail.get(&dependency)?.clone();
+                Some(ExplainedDependency {
+                    product_id: dependency,
+                    product: *self.catalogue.get(&dependency)?,
+                    required_qty,
+                    // Mirrors compute_stock_levels, which rounds to the explained product's dp
+                    terms: (required_qty > Decimal::ZERO).then(|| {
+                        DependencyTerms::new(&dependency_availability, required_qty, product.dp())
+                    }),
+                    availability: dependency_availability,
+                })
+            })
+            .collect();
+        dependencies.sort_by_key(|dependency| dependency.product_id);
+
+        Some(Explanation {
+            product_id,
+            product,
+            raw_quant: self.raw_quants.get(&product_id).cloned(),
+            dependencies,
+            availability,
+        })
+    }
+
     pub fn diagnostic_tree(
         &self,
         product_id: ProductId,
         required_qty: Option<Decimal>,
     ) -> Option<DiagnosticNode> {
-        let product = self.catalogue.get(&product_id)?.clone();
+        let product = *self.catalogue.get(&product_id)?;
         let availability = self.avail.get(&product_id)?.clone();
         let raw_quant = self.raw_quants.get(&product_id).cloned();
 
@@ -566,6 +2181,106 @@ impl Graph {
     }
 }
 
+/// How a product's availability was derived from its raw quants and dependencies.
+#[derive(Debug)]
+pub struct Explanation {
+    pub product_id: ProductId,
+    pub product: Product,
+    pub raw_quant: Option<Quant>,
+    pub dependencies: Vec<ExplainedDependency>,
+    pub availability: Availability,
+}
+
+/// A dependency of an explained product, i.e. a BoM component or commingled source.
+#[derive(Debug)]
+pub struct ExplainedDependency {
+    pub product_id: ProductId,
+    pub product: Product,
+
+    /// quantity of the dependency per unit of the explained product
+    pub required_qty: Decimal,
+    pub availability: Availability,
+
+    /// `None` when the dependency is ignored for a required quantity of zero or less
+    pub terms: Option<DependencyTerms>,
+}
+
+/// A dependency's availability in units of the product it feeds: each value divided by the
+/// required quantity and rounded towards zero. Kits take the minimum of these over their
+/// components, commingled products the sum over their sources.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DependencyTerms {
+    pub quantity: Decimal,
+    pub reserved: Decimal,
+    pub incoming: Decimal,
+    pub outgoing: Decimal,
+    pub free_immediately: Decimal,
+    pub virtual_available: Decimal,
+    pub buildable: Decimal,
+}
+
+impl DependencyTerms {
+    pub fn new(dependency: &Availability, required_qty: Decimal, dp: u32) -> Self {
+        let per_unit = |value: Decimal| {
+            (value / required_qty).round_dp_with_strategy(dp, RoundingStrategy::ToZero)
+        };
+
+        Self {
+            quantity: per_unit(dependency.quantity),
+            reserved: per_unit(dependency.reserved),
+            incoming: per_unit(dependency.incoming),
+            outgoing: per_unit(dependency.outgoing),
+            free_immediately: per_unit(dependency.free_immediately()),
+            virtual_available: per_unit(dependency.virtual_available()),
+            buildable: per_unit(dependency.buildable),
+        }
+    }
+
+    /// Named terms, in the order of `OutputAvailability::fields` plus `buildable`.
+    pub fn fields(&self) -> [(&'static str, Decimal); 7] {
+        [
+            ("quantity", self.quantity),
+            ("reserved", self.reserved),
+            ("incoming", self.incoming),
+            ("outgoing", self.outgoing),
+            ("free", self.free_immediately),
+            ("virtual_available", self.virtual_available),
+            ("buildable", self.buildable),
+        ]
+    }
+}
+
+/// A BoM of a product other than the one its graph edges come from: same product, higher
+/// sequence, used when the first BoM's components run out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlternativeBom {
+    pub bom_id: i32,
+
+    /// quantity of the product the BoM makes, in the product's unit of measure
+    pub quantity: Decimal,
+
+    /// components and their quantity per BoM, in the component's unit of measure
+    pub components: Vec<(ProductId, Decimal)>,
+}
+
+impl AlternativeBom {
+    /// How much of the product this BoM can make from the buildable stock of its components,
+    /// or `None` when a component has no availability.
+    pub fn buildable(&self, stock: &HashMap<ProductId, Availability>, dp: u32) -> Option<Decimal> {
+        let mut sets: Option<Decimal> = None;
+        for (component, required_qty) in &self.components {
+            let terms = DependencyTerms::new(stock.get(component)?, *required_qty, dp);
+            sets = Some(sets.map_or(terms.buildable, |sets| sets.min(terms.buildable)));
+        }
+        Some(
+            sets?
+                * self
+                    .quantity
+                    .round_dp_with_strategy(dp, RoundingStrategy::ToZero),
+        )
+    }
+}
+
 pub struct DiagnosticNode {
     pub product_id: ProductId,
     pub product: Product,
@@ -582,7 +2297,15 @@ mod tests {
     use petgraph::graphmap::DiGraphMap;
     use rust_decimal::Decimal;
 
-    use super::{Availability, AvailabilityOutputMode, Graph, Product, ProductId, Quant};
+    use super::{
+        AbcClass, AlternativeBom, Availability, AvailabilityOutputMode, ComputeError,
+        DependencyTerms, FreeFormula, Graph, KitSemantics, OutputAvailability, Product, ProductId,
+        ProductMetrics, Quant, capacity_units, complete_sets, cycles, days_of_cover, explode_kits,
+        fill_rate,
+    };
+    use super::{CollectError, CollectPhase, PhaseCollection, collect_with_retry};
+    use async_trait::async_trait;
+    use std::time::Duration;
 
     fn d(value: &str) -> Decimal {
         Decimal::from_str_exact(value).expect("test decimal must parse")
@@ -594,6 +2317,8 @@ mod tests {
             reserved: d(reserved),
             incoming: d(incoming),
             outgoing: d(outgoing),
+            resupply: Decimal::ZERO,
+            sourced: Decimal::ZERO,
         }
     }
 
@@ -610,6 +2335,8 @@ mod tests {
         Graph::compute_stock_levels(
             graph,
             catalogue,
+            &HashMap::new(),
+            &HashMap::new(),
             &mut stock_cache,
             raw_quants,
             sorted_nodes,
@@ -621,32 +2348,111 @@ mod tests {
     }
 
     #[test]
-    fn availability_calculates_free_and_virtual() {
-        // Sanity check helper formulas used in output and buildable math:
-        // free_immediately = quantity - reserved, virtual_available = quantity - outgoing + incoming.
+    fn availability_calculates_free_and_virtual() {
+        // Sanity check helper formulas used in output and buildable math:
+        // free_immediately = quantity - reserved, virtual_available = quantity - outgoing + incoming.
+        let availability = Availability {
+            quantity: d("10"),
+            reserved: d("2"),
+            incoming: d("5"),
+            outgoing: d("3"),
+            buildable: d("0"),
+        };
+
+        assert_eq!(availability.free_immediately(), d("8"));
+        assert_eq!(availability.virtual_available(), d("12"));
+    }
+
+    #[test]
+    fn availability_allows_negative_virtual_available() {
+        let availability = Availability {
+            quantity: d("2"),
+            reserved: d("0"),
+            incoming: d("1"),
+            outgoing: d("5"),
+            buildable: d("0"),
+        };
+
+        assert_eq!(availability.virtual_available(), d("-2"));
+    }
+
+    #[test]
+    fn uncommitted_free_also_subtracts_unreserved_outgoing() {
         let availability = Availability {
             quantity: d("10"),
             reserved: d("2"),
-            incoming: d("5"),
-            outgoing: d("3"),
+            incoming: d("0"),
+            outgoing: d("5"),
             buildable: d("0"),
         };
+        assert_eq!(availability.free(FreeFormula::Unreserved), d("8"));
+        assert_eq!(availability.free(FreeFormula::Uncommitted), d("5"));
 
-        assert_eq!(availability.free_immediately(), d("8"));
-        assert_eq!(availability.virtual_available(), d("12"));
+        // Reservations beyond the outgoing moves (e.g. for internal transfers) count once
+        let availability = Availability {
+            outgoing: d("1"),
+            ..availability
+        };
+        assert_eq!(availability.free(FreeFormula::Uncommitted), d("8"));
+        assert_eq!(
+            "uncommitted".parse::<FreeFormula>(),
+            Ok(FreeFormula::Uncommitted)
+        );
     }
 
     #[test]
-    fn availability_allows_negative_virtual_available() {
+    fn output_availability_serializes_with_exact_decimal_strings() {
         let availability = Availability {
-            quantity: d("2"),
-            reserved: d("0"),
+            quantity: d("10.50"),
+            reserved: d("2"),
+            incoming: d("0"),
+            outgoing: d("3"),
+            buildable: d("0"),
+        };
+        let output = availability.output(AvailabilityOutputMode::Signed, FreeFormula::Unreserved);
+
+        let json = serde_json::to_string(&(ProductId(7), &output)).expect("output must serialize");
+        assert_eq!(
+            json,
+            r#"[7,{"quantity":"10.50","reserved":"2","incoming":"0","outgoing":"3","buildable":"0","free_immediately":"8.50","virtual_available":"7.50","net_on_order":"-3"}]"#
+        );
+        let (product, parsed): (ProductId, OutputAvailability) =
+            serde_json::from_str(&json).expect("output must deserialize");
+        assert_eq!(product, ProductId(7));
+        assert_eq!(parsed, output);
+    }
+
+    #[test]
+    fn output_availability_sums_every_field() {
+        let mut total = OutputAvailability {
+            quantity: d("10"),
+            reserved: d("2"),
             incoming: d("1"),
-            outgoing: d("5"),
+            outgoing: d("3"),
             buildable: d("0"),
+            free_immediately: d("8"),
+            virtual_available: d("8"),
+            net_on_order: d("-2"),
+        };
+        total += &OutputAvailability {
+            quantity: d("0.5"),
+            reserved: d("0"),
+            incoming: d("4"),
+            outgoing: d("0"),
+            buildable: d("2"),
+            free_immediately: d("0.5"),
+            virtual_available: d("4.5"),
+            net_on_order: d("4"),
         };
 
-        assert_eq!(availability.virtual_available(), d("-2"));
+        assert_eq!(total.quantity, d("10.5"));
+        assert_eq!(total.reserved, d("2"));
+        assert_eq!(total.incoming, d("5"));
+        assert_eq!(total.outgoing, d("3"));
+        assert_eq!(total.buildable, d("2"));
+        assert_eq!(total.free_immediately, d("8.5"));
+        assert_eq!(total.virtual_available, d("12.5"));
+        assert_eq!(total.net_on_order, d("2"));
     }
 
     #[test]
@@ -662,7 +2468,7 @@ mod tests {
     }
 
     #[test]
-    fn output_clamps_negative_values_in_all_fields() {
+    fn output_clamps_negative_values_but_net_on_order() {
         let availability = Availability {
             quantity: d("-5"),
             reserved: d("-2"),
@@ -671,7 +2477,8 @@ mod tests {
             buildable: d("-4"),
         };
 
-        let output = availability.output(AvailabilityOutputMode::ClampToZero);
+        let output =
+            availability.output(AvailabilityOutputMode::ClampToZero, FreeFormula::Unreserved);
 
         assert_eq!(output.quantity, d("0"));
         assert_eq!(output.reserved, d("0"));
@@ -680,6 +2487,19 @@ mod tests {
         assert_eq!(output.buildable, d("0"));
         assert_eq!(output.free_immediately, d("0"));
         assert_eq!(output.virtual_available, d("0"));
+        // From the published incoming and outgoing, both clamped
+        assert_eq!(output.net_on_order, d("0"));
+
+        let short = Availability {
+            quantity: d("1"),
+            reserved: d("0"),
+            incoming: d("2"),
+            outgoing: d("5"),
+            buildable: d("0"),
+        };
+        let output = short.output(AvailabilityOutputMode::ClampToZero, FreeFormula::Unreserved);
+        assert_eq!(output.virtual_available, d("0"));
+        assert_eq!(output.net_on_order, d("-3"));
     }
 
     #[test]
@@ -692,7 +2512,7 @@ mod tests {
             buildable: d("-4"),
         };
 
-        let output = availability.output(AvailabilityOutputMode::Signed);
+        let output = availability.output(AvailabilityOutputMode::Signed, FreeFormula::Unreserved);
 
         assert_eq!(output.quantity, d("-5"));
         assert_eq!(output.reserved, d("-2"));
@@ -701,6 +2521,8 @@ mod tests {
         assert_eq!(output.buildable, d("-4"));
         assert_eq!(output.free_immediately, d("-3"));
         assert_eq!(output.virtual_available, d("-3"));
+        // The published incoming minus the published outgoing
+        assert_eq!(output.net_on_order, d("2"));
     }
 
     #[test]
@@ -777,6 +2599,85 @@ mod tests {
         assert_eq!(availability.buildable, d("6"));
     }
 
+    #[test]
+    fn resupply_counts_toward_incoming_and_kit_buildable() {
+        let component = ProductId(1);
+        let kit = ProductId(2);
+
+        let mut graph = DiGraphMap::new();
+        graph.add_edge(component, kit, d("2"));
+
+        let mut catalogue = HashMap::new();
+        catalogue.insert(component, Product::Simple(2));
+        catalogue.insert(kit, Product::MrpPhantom(d("1"), 2));
+
+        let mut raw_quants = HashMap::new();
+        raw_quants.insert(
+            component,
+            Quant {
+                resupply: d("6"),
+                ..quant("4", "1", "2", "0")
+            },
+        );
+
+        let stock =
+            compute_stock_levels(&graph, &catalogue, &raw_quants, &[component, kit], None, 2);
+
+        let component = stock.get(&component).expect("component must be computed");
+        assert_eq!(component.free_immediately(), d("3"));
+        assert_eq!(component.virtual_available(), d("12"));
+        assert_eq!(component.buildable, d("9"));
+
+        // Hub stock is not on hand, so the kit's free stock is unchanged while it can still
+        // be built from the resupplied components: (4 - 1 + 6) / 2 = 4.5
+        let kit = stock.get(&kit).expect("kit must be computed");
+        assert_eq!(kit.free_immediately(), d("1.5"));
+        assert_eq!(kit.buildable, d("4.5"));
+    }
+
+    #[test]
+    fn component_warehouse_stock_only_counts_toward_buildable() {
+        let component = ProductId(1);
+        let assembly = ProductId(2);
+
+        let mut graph = DiGraphMap::new();
+        graph.add_edge(component, assembly, d("2"));
+
+        let mut catalogue = HashMap::new();
+        catalogue.insert(component, Product::Simple(2));
+        catalogue.insert(assembly, Product::MrpNormal(d("1"), 2));
+
+        let mut raw_quants = HashMap::new();
+        raw_quants.insert(
+            component,
+            Quant {
+                sourced: d("10"),
+                ..quant("4", "2", "3", "0")
+            },
+        );
+
+        let stock = compute_stock_levels(
+            &graph,
+            &catalogue,
+            &raw_quants,
+            &[component, assembly],
+            None,
+            2,
+        );
+
+        // On hand and reserved stay with the assembly warehouse
+        let component = stock.get(&component).expect("component must be computed");
+        assert_eq!(component.quantity, d("4"));
+        assert_eq!(component.free_immediately(), d("2"));
+        assert_eq!(component.incoming, d("3"));
+        assert_eq!(component.buildable, d("12"));
+
+        // (4 - 2 + 10) / 2
+        let assembly = stock.get(&assembly).expect("assembly must be computed");
+        assert_eq!(assembly.quantity, d("0"));
+        assert_eq!(assembly.buildable, d("6"));
+    }
+
     #[test]
     fn normal_bom_product_uses_raw_quant_and_buildable_min() {
         // Buildable is min((dep.buildable + dep.free_immediately) / required_qty) * bom_output_qty.
@@ -860,6 +2761,63 @@ mod tests {
         assert_eq!(availability.buildable, d("2"));
     }
 
+    #[test]
+    fn integral_products_never_report_fractions() {
+        // Raw quants are only rounded to the source precision, and a BoM yielding 1.5 units per
+        // 2 components must still report whole units: 3 batches of 1 unit.
+        let component = ProductId(1);
+        let normal_bom = ProductId(2);
+
+        let mut graph = DiGraphMap::new();
+        graph.add_edge(component, normal_bom, d("2"));
+
+        let mut catalogue = HashMap::new();
+        catalogue.insert(component, Product::Simple(2));
+        catalogue.insert(normal_bom, Product::MrpNormal(d("1.5"), 0));
+
+        let mut raw_quants = HashMap::new();
+        raw_quants.insert(component, quant("7", "0", "0", "0"));
+        raw_quants.insert(normal_bom, quant("1.5", "0.5", "0", "0"));
+
+        let stock = compute_stock_levels(
+            &graph,
+            &catalogue,
+            &raw_quants,
+            &[component, normal_bom],
+            None,
+            2,
+        );
+
+        let availability = stock
+            .get(&normal_bom)
+            .expect("normal bom product must be computed");
+
+        assert_eq!(availability.quantity, d("1"));
+        // Half a reserved unit still holds a whole one
+        assert_eq!(availability.reserved, d("1"));
+        assert_eq!(availability.buildable, d("3"));
+    }
+
+    #[test]
+    fn integral_products_round_demand_up() {
+        // 2.5 units going out take 3 whole units from the forecast
+        let mut availability = Availability {
+            quantity: d("10.7"),
+            reserved: d("0.2"),
+            incoming: d("1.9"),
+            outgoing: d("2.5"),
+            buildable: d("0"),
+        };
+        availability.floor_to_integer();
+
+        assert_eq!(availability.quantity, d("10"));
+        assert_eq!(availability.reserved, d("1"));
+        assert_eq!(availability.incoming, d("1"));
+        assert_eq!(availability.outgoing, d("3"));
+        assert_eq!(availability.free_immediately(), d("9"));
+        assert_eq!(availability.virtual_available(), d("8"));
+    }
+
     #[test]
     fn scope_only_computes_requested_products() {
         // Only products present in scope are computed/cached.
@@ -893,4 +2851,452 @@ mod tests {
         assert!(stock.contains_key(&product_a));
         assert!(!stock.contains_key(&product_b));
     }
+
+    #[test]
+    fn valuation_multiplies_quantity_by_unit_cost() {
+        let mut metrics = ProductMetrics::default();
+        metrics.set_valuation(d("3"), d("1.333333"));
+
+        assert_eq!(metrics.unit_cost, Some(d("1.333333")));
+        assert_eq!(metrics.value, Some(d("4.00")));
+        assert_eq!(metrics.to_string(), ", value=4.00, unit_cost=1.333333");
+    }
+
+    #[test]
+    fn oldest_reservation_days_follow_the_other_metrics() {
+        let metrics = ProductMetrics {
+            fill_rate: Some(d("0.5")),
+            oldest_reservation_days: Some(12),
+            ..ProductMetrics::default()
+        };
+
+        assert_eq!(
+            metrics.to_string(),
+            ", fill_rate=0.5, oldest_reservation_days=12"
+        );
+    }
+
+    #[test]
+    fn abc_classes_follow_cumulative_outbound_share() {
+        // Outbound 70/20/6/4/0 of 100: A covers the first 80%, B up to 95%, C the rest.
+        let products: Vec<ProductId> = (1..=5).map(ProductId).collect();
+        let mut outbound = HashMap::new();
+        outbound.insert(ProductId(1), d("70"));
+        outbound.insert(ProductId(2), d("20"));
+        outbound.insert(ProductId(3), d("6"));
+        outbound.insert(ProductId(4), d("4"));
+
+        let classes = AbcClass::classify(&products, &outbound);
+
+        assert_eq!(classes[&ProductId(1)], AbcClass::A);
+        assert_eq!(classes[&ProductId(2)], AbcClass::A);
+        assert_eq!(classes[&ProductId(3)], AbcClass::B);
+        assert_eq!(classes[&ProductId(4)], AbcClass::C);
+        assert_eq!(classes[&ProductId(5)], AbcClass::C);
+    }
+
+    #[test]
+    fn days_of_cover_divides_free_by_daily_outbound() {
+        assert_eq!(days_of_cover(d("10"), d("3")), Some(d("3.3")));
+        assert_eq!(days_of_cover(d("-4"), d("2")), Some(d("-2")));
+        assert_eq!(days_of_cover(d("10"), d("0")), None);
+    }
+
+    #[test]
+    fn fill_rate_is_the_covered_share_of_outgoing() {
+        assert_eq!(fill_rate(d("2"), d("3")), Some(d("0.6666")));
+        assert_eq!(fill_rate(d("5"), d("3")), Some(d("1")));
+        assert_eq!(fill_rate(d("-1"), d("3")), Some(d("0")));
+        assert_eq!(fill_rate(d("5"), d("0")), None);
+    }
+
+    #[test]
+    fn cycles_are_reported_once_per_group_in_bom_order() {
+        // Edges go from component to kit: 1 uses 2, 2 uses 3, 3 uses 1; 5 uses itself
+        let mut graph = DiGraphMap::new();
+        graph.add_edge(ProductId(2), ProductId(1), Decimal::ONE);
+        graph.add_edge(ProductId(3), ProductId(2), Decimal::ONE);
+        graph.add_edge(ProductId(1), ProductId(3), Decimal::ONE);
+        graph.add_edge(ProductId(4), ProductId(3), Decimal::ONE);
+        graph.add_edge(ProductId(5), ProductId(5), Decimal::ONE);
+        graph.add_edge(ProductId(6), ProductId(7), Decimal::ONE);
+
+        assert_eq!(
+            cycles(&graph),
+            vec![
+                vec![ProductId(1), ProductId(2), ProductId(3)],
+                vec![ProductId(5)],
+            ]
+        );
+    }
+
+    #[test]
+    fn dependency_terms_divide_by_required_quantity_towards_zero() {
+        let dependency = Availability {
+            quantity: d("10"),
+            reserved: d("3"),
+            incoming: d("5"),
+            outgoing: d("1"),
+            buildable: d("7"),
+        };
+
+        let terms = DependencyTerms::new(&dependency, d("3"), 1);
+        assert_eq!(
+            terms.fields().map(|(_, value)| value),
+            [
+                d("3.3"),
+                d("1"),
+                d("1.6"),
+                d("0.3"),
+                d("2.3"),
+                d("4.6"),
+                d("2.3")
+            ]
+        );
+    }
+
+    #[test]
+    fn complete_sets_only_count_whole_kits_from_free_stock() {
+        let component_a = Availability {
+            quantity: d("9"),
+            reserved: d("2"),
+            incoming: d("0"),
+            outgoing: d("0"),
+            buildable: d("0"),
+        };
+        let component_b = Availability {
+            quantity: d("10"),
+            reserved: d("6"),
+            incoming: d("0"),
+            outgoing: d("0"),
+            buildable: d("0"),
+        };
+        let terms = [
+            DependencyTerms::new(&component_a, d("2"), 2),
+            DependencyTerms::new(&component_b, d("1"), 2),
+        ];
+
+        // Per field, 3.5 sets of two kits are free; only 3 of the sets are complete
+        assert_eq!(terms[0].free_immediately, d("3.5"));
+        assert_eq!(complete_sets(&terms, d("2")), d("6"));
+        assert_eq!(complete_sets(&[], d("2")), d("0"));
+        assert_eq!(
+            "complete-sets".parse::<KitSemantics>(),
+            Ok(KitSemantics::CompleteSets)
+        );
+    }
+
+    #[test]
+    fn alternative_boms_raise_buildable_to_the_best_bom() {
+        let scarce = ProductId(1);
+        let plentiful = ProductId(2);
+        let assembly = ProductId(3);
+
+        // The first BoM uses 1 scarce component, the alternate makes 2 from 3 plentiful ones,
+        // which only link to the assembly for ordering
+        let mut graph = DiGraphMap::new();
+        graph.add_edge(scarce, assembly, d("1"));
+        graph.add_edge(plentiful, assembly, d("0"));
+
+        let mut catalogue = HashMap::new();
+        catalogue.insert(scarce, Product::Simple(0));
+        catalogue.insert(plentiful, Product::Simple(0));
+        catalogue.insert(assembly, Product::MrpNormal(d("1"), 0));
+
+        let mut raw_quants = HashMap::new();
+        raw_quants.insert(scarce, quant("2", "0", "0", "0"));
+        raw_quants.insert(plentiful, quant("10", "1", "0", "0"));
+
+        let alternative = AlternativeBom {
+            bom_id: 7,
+            quantity: d("2"),
+            components: vec![(plentiful, d("3"))],
+        };
+        let mut alternative_boms = HashMap::new();
+        alternative_boms.insert(assembly, vec![alternative.clone()]);
+
+        let mut stock = HashMap::new();
+        Graph::compute_stock_levels(
+            &graph,
+            &catalogue,
+            &alternative_boms,
+            &HashMap::new(),
+            &mut stock,
+            &raw_quants,
+            &[scarce, plentiful, assembly],
+            None,
+            0,
+        );
+
+        // 9 free plentiful components make 3 sets of 2
+        let assembly_stock = stock.get(&assembly).expect("assembly must be computed");
+        assert_eq!(assembly_stock.buildable, d("6"));
+        assert_eq!(alternative.buildable(&stock, 0), Some(d("6")));
+
+        // Without the alternate, only the first BoM counts
+        let stock = compute_stock_levels(
+            &graph,
+            &catalogue,
+            &raw_quants,
+            &[scarce, plentiful, assembly],
+            None,
+            0,
+        );
+        let assembly_stock = stock.get(&assembly).expect("assembly must be computed");
+        assert_eq!(assembly_stock.buildable, d("2"));
+
+        let missing = AlternativeBom {
+            components: vec![(ProductId(4), d("1"))],
+            ..alternative
+        };
+        assert_eq!(missing.buildable(&stock, 0), None);
+    }
+
+    #[test]
+    fn capacity_spreads_weekly_hours_over_the_horizon() {
+        let week = std::time::Duration::from_secs(7 * 86_400);
+
+        // 40 hours a week at 7.5 minutes per unit
+        assert_eq!(capacity_units(d("40"), d("7.5"), week, 0), Some(d("320")));
+        assert_eq!(
+            capacity_units(d("40"), d("7.5"), week / 2, 0),
+            Some(d("160"))
+        );
+        assert_eq!(capacity_units(d("40"), d("7"), week, 2), Some(d("342.85")));
+        assert_eq!(capacity_units(d("40"), d("0"), week, 0), None);
+    }
+
+    #[test]
+    fn normal_boms_use_the_components_of_their_kits() {
+        let shared = ProductId(1);
+        let other = ProductId(2);
+        let kit = ProductId(3);
+        let nested_kit = ProductId(4);
+        let assembly = ProductId(5);
+
+        // The assembly uses 1 shared component and 2 kits, each of 1 shared and 2 nested kits
+        // made of half a set of 2 other components
+        let mut graph = DiGraphMap::new();
+        graph.add_edge(shared, assembly, d("1"));
+        graph.add_edge(kit, assembly, d("2"));
+        graph.add_edge(shared, kit, d("1"));
+        graph.add_edge(nested_kit, kit, d("2"));
+        graph.add_edge(other, nested_kit, d("1"));
+
+        let mut catalogue = HashMap::new();
+        catalogue.insert(shared, Product::Simple(0));
+        catalogue.insert(other, Product::Simple(0));
+        catalogue.insert(kit, Product::MrpPhantom(d("1"), 0));
+        catalogue.insert(nested_kit, Product::MrpPhantom(d("0.5"), 0));
+        catalogue.insert(assembly, Product::MrpNormal(d("1"), 0));
+
+        explode_kits(&mut graph, &catalogue);
+
+        assert_eq!(graph.edge_weight(shared, assembly), Some(&d("3")));
+        assert_eq!(graph.edge_weight(other, assembly), Some(&d("8")));
+        assert!(!graph.contains_edge(kit, assembly));
+        // Kits keep their own components
+        assert_eq!(graph.edge_weight(nested_kit, kit), Some(&d("2")));
+
+        let mut raw_quants = HashMap::new();
+        raw_quants.insert(shared, quant("6", "0", "0", "0"));
+        raw_quants.insert(other, quant("40", "0", "0", "0"));
+
+        // Counting the kits as components, the shared stock would make 3 assemblies
+        let stock = compute_stock_levels(
+            &graph,
+            &catalogue,
+            &raw_quants,
+            &[shared, other, nested_kit, kit, assembly],
+            None,
+            0,
+        );
+        let assembly = stock.get(&assembly).expect("assembly must be computed");
+        assert_eq!(assembly.buildable, d("2"));
+    }
+
+    /// Records the steps of each attempt, failing the first run of `fail_once`.
+    struct RecordedCollection {
+        steps: Vec<String>,
+        fail_once: Option<CollectPhase>,
+    }
+
+    #[async_trait]
+    impl PhaseCollection for RecordedCollection {
+        async fn begin(&mut self) -> Result<(), CollectError> {
+            self.steps.push("begin".to_string());
+            Ok(())
+        }
+
+        async fn run(&mut self, phase: CollectPhase) -> Result<(), CollectError> {
+            self.steps.push(phase.as_str().to_string());
+            if self.fail_once == Some(phase) {
+                self.fail_once = None;
+                return Err(sqlx::Error::PoolTimedOut.into());
+            }
+            Ok(())
+        }
+
+        async fn commit(&mut self) -> Result<(), CollectError> {
+            self.steps.push("commit".to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_restart_the_collection_from_its_first_phase() {
+        let phases = [
+            CollectPhase::Products,
+            CollectPhase::Relations,
+            CollectPhase::Quants,
+            CollectPhase::Valuation,
+        ];
+        let mut collection = RecordedCollection {
+            steps: Vec::new(),
+            fail_once: Some(CollectPhase::Quants),
+        };
+
+        collect_with_retry(&mut collection, &phases, 1, Duration::ZERO)
+            .await
+            .expect("the retry must succeed");
+
+        assert_eq!(
+            collection.steps,
+            [
+                "begin",
+                "products",
+                "relations",
+                "quants",
+                "begin",
+                "products",
+                "relations",
+                "quants",
+                "valuation",
+                "commit",
+            ]
+        );
+
+        // Without retries the transient error is returned
+        collection.fail_once = Some(CollectPhase::Relations);
+        assert!(
+            collect_with_retry(&mut collection, &phases, 0, Duration::ZERO)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn products_failing_to_compute_only_take_their_dependents_with_them() {
+        let unknown = ProductId(1);
+        let kit = ProductId(2);
+        let assembly = ProductId(3);
+        let other = ProductId(4);
+
+        let mut graph = DiGraphMap::new();
+        graph.add_edge(unknown, kit, d("1"));
+        graph.add_edge(kit, assembly, d("2"));
+        graph.add_node(other);
+
+        let mut catalogue = HashMap::new();
+        catalogue.insert(kit, Product::MrpPhantom(d("1"), 0));
+        catalogue.insert(assembly, Product::MrpNormal(d("1"), 0));
+        catalogue.insert(other, Product::Simple(0));
+
+        let mut raw_quants = HashMap::new();
+        raw_quants.insert(other, quant("5", "1", "0", "0"));
+
+        let mut stock = HashMap::new();
+        let errors = Graph::compute_stock_levels(
+            &graph,
+            &catalogue,
+            &HashMap::new(),
+            &HashMap::new(),
+            &mut stock,
+            &raw_quants,
+            &[unknown, other, kit, assembly],
+            None,
+            0,
+        );
+
+        assert_eq!(
+            errors.into_iter().collect::<Vec<_>>(),
+            vec![
+                (unknown, ComputeError::NotInCatalogue(1)),
+                (
+                    kit,
+                    ComputeError::Dependency {
+                        product_id: 2,
+                        dependency: 1
+                    }
+                ),
+                (
+                    assembly,
+                    ComputeError::Dependency {
+                        product_id: 3,
+                        dependency: 2
+                    }
+                ),
+            ]
+        );
+        assert_eq!(stock.keys().collect::<Vec<_>>(), vec![&other]);
+        assert_eq!(stock[&other].free_immediately(), d("4"));
+    }
+
+    #[test]
+    fn boms_with_unconvertible_lines_fail_their_product_and_its_users() {
+        let component = ProductId(1);
+        let kit = ProductId(2);
+        let assembly = ProductId(3);
+
+        // The kit's edges were left out with its BoM; the assembly still uses the kit
+        let mut graph = DiGraphMap::new();
+        graph.add_node(component);
+        graph.add_node(kit);
+        graph.add_edge(kit, assembly, d("1"));
+
+        let mut catalogue = HashMap::new();
+        catalogue.insert(component, Product::Simple(0));
+        catalogue.insert(kit, Product::MrpPhantom(d("1"), 0));
+        catalogue.insert(assembly, Product::MrpNormal(d("1"), 0));
+
+        let mut raw_quants = HashMap::new();
+        raw_quants.insert(component, quant("5", "0", "0", "0"));
+
+        let mut invalid_boms = HashMap::new();
+        invalid_boms.insert(kit, 7);
+
+        let mut stock = HashMap::new();
+        let errors = Graph::compute_stock_levels(
+            &graph,
+            &catalogue,
+            &HashMap::new(),
+            &invalid_boms,
+            &mut stock,
+            &raw_quants,
+            &[component, kit, assembly],
+            None,
+            0,
+        );
+
+        assert_eq!(
+            errors.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    kit,
+                    ComputeError::BomUomMismatch {
+                        product_id: 2,
+                        bom_id: 7
+                    }
+                ),
+                (
+                    assembly,
+                    ComputeError::Dependency {
+                        product_id: 3,
+                        dependency: 2
+                    }
+                ),
+            ]
+        );
+        assert_eq!(stock.keys().collect::<Vec<_>>(), vec![&component]);
+    }
 }
@@ -0,0 +1,143 @@
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime},
+};
+
+use crate::{warehouse::StockScope, webhook};
+
+/// Pushgateway job the run metrics are grouped under.
+const JOB: &str = "odoo_rapid_quant";
+
+/// What a batch run got through, pushed to `--push-metrics` once it ends.
+#[derive(Debug, Clone)]
+pub struct RunMetrics {
+    pub scopes: usize,
+    pub products: usize,
+    pub rows_written: u64,
+    pub duration: Duration,
+    pub finished_at: SystemTime,
+    pub succeeded: bool,
+}
+
+impl RunMetrics {
+    /// The metrics in the Prometheus text exposition format.
+    fn exposition(&self) -> String {
+        let finished_at = self
+            .finished_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut gauges = vec![
+            (
+                "last_run_timestamp_seconds",
+                "When the last run ended",
+                finished_at,
+            ),
+            (
+                "last_run_duration_seconds",
+                "How long the last run took",
+                self.duration.as_secs_f64(),
+            ),
+            (
+                "last_run_success",
+                "Whether the last run succeeded (1) or failed (0)",
+                f64::from(u8::from(self.succeeded)),
+            ),
+            (
+                "last_run_scopes",
+                "Warehouses or locations written by the last run",
+                self.scopes as f64,
+            ),
+            (
+                "last_run_products",
+                "Products computed by the last run",
+                self.products as f64,
+            ),
+            (
+                "last_run_rows_written",
+                "Rows written by the last run",
+                self.rows_written as f64,
+            ),
+        ];
+        // Only pushed on success, so the Pushgateway keeps the previous one after a failure
+        if self.succeeded {
+            gauges.push((
+                "last_success_timestamp_seconds",
+                "When the last successful run ended",
+                finished_at,
+            ));
+        }
+
+        let mut body = String::new();
+        for (name, help, value) in gauges {
+            let _ = writeln!(body, "# HELP {JOB}_{name} {help}");
+            let _ = writeln!(body, "# TYPE {JOB}_{name} gauge");
+            let _ = writeln!(body, "{JOB}_{name} {value}");
+        }
+        body
+    }
+}
+
+/// The Pushgateway path grouping the metrics of a run over `scopes`, e.g.
+/// `/metrics/job/odoo_rapid_quant/scope/warehouse-1,warehouse-2`, so runs over different
+/// warehouses keep their own metrics.
+fn grouping_path(scopes: &[StockScope]) -> String {
+    let scopes: Vec<String> = scopes
+        .iter()
+        .map(|scope| match scope {
+            StockScope::Warehouse(id) => format!("warehouse-{id}"),
+            StockScope::Location(id) => format!("location-{id}"),
+        })
+        .collect();
+    format!("/metrics/job/{JOB}/scope/{}", scopes.join(","))
+}
+
+/// POST `metrics` to the Pushgateway at `url`, replacing the metrics of the same name previously
+/// pushed for `scopes`.
+pub async fn push(
+    url: &str,
+    scopes: &[StockScope],
+    metrics: &RunMetrics,
+) -> Result<(), webhook::WebhookError> {
+    let url = format!("{}{}", url.trim_end_matches('/'), grouping_path(scopes));
+    webhook::post(
+        &url,
+        "text/plain; version=0.0.4",
+        metrics.exposition().as_bytes(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{RunMetrics, grouping_path};
+    use crate::warehouse::StockScope;
+
+    #[test]
+    fn failed_runs_keep_the_last_success_timestamp() {
+        let mut metrics = RunMetrics {
+            scopes: 2,
+            products: 40,
+            rows_written: 12,
+            duration: Duration::from_millis(1500),
+            finished_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            succeeded: true,
+        };
+        let body = metrics.exposition();
+        assert!(body.contains("odoo_rapid_quant_last_run_duration_seconds 1.5\n"));
+        assert!(body.contains("odoo_rapid_quant_last_run_rows_written 12\n"));
+        assert!(body.contains("odoo_rapid_quant_last_success_timestamp_seconds 1700000000\n"));
+
+        metrics.succeeded = false;
+        let body = metrics.exposition();
+        assert!(body.contains("odoo_rapid_quant_last_run_success 0\n"));
+        assert!(!body.contains("last_success_timestamp_seconds"));
+
+        assert_eq!(
+            grouping_path(&[StockScope::Warehouse(1), StockScope::Location(8)]),
+            "/metrics/job/odoo_rapid_quant/scope/warehouse-1,location-8"
+        );
+    }
+}
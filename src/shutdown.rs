@@ -0,0 +1,83 @@
+use std::sync::OnceLock;
+
+/// A signal asking the run to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+impl Signal {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Interrupt => "SIGINT",
+            Self::Terminate => "SIGTERM",
+        }
+    }
+
+    /// Exit status of a run stopped by this signal, 128 plus the signal number as shells report
+    /// it.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Interrupt => 130,
+            Self::Terminate => 143,
+        }
+    }
+}
+
+/// The first signal received, if any.
+static REQUESTED: OnceLock<Signal> = OnceLock::new();
+
+/// Returned from the points where a run checks for a signal, so it unwinds like any other error:
+/// the open sink transaction is rolled back and buffered output is flushed.
+#[derive(Debug, thiserror::Error)]
+#[error("interrupted by {}", .0.as_str())]
+pub struct Interrupted(pub Signal);
+
+/// Listen for SIGINT and SIGTERM. The first signal is only recorded, for the run to stop at its
+/// next [`check`]; a second one exits straight away.
+pub fn install() -> std::io::Result<()> {
+    // The listeners run for the rest of the process, detached from their handles
+    drop(tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            record(Signal::Interrupt);
+        }
+    }));
+
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        drop(tokio::spawn(async move {
+            while terminate.recv().await.is_some() {
+                record(Signal::Terminate);
+            }
+        }));
+    }
+
+    Ok(())
+}
+
+fn record(signal: Signal) {
+    if REQUESTED.set(signal).is_err() {
+        tracing::error!("Received {} again, exiting immediately", signal.as_str());
+        std::process::exit(signal.exit_code());
+    }
+    tracing::warn!(
+        "Received {}, stopping at the next row or phase; send it again to exit immediately",
+        signal.as_str()
+    );
+}
+
+/// The signal received so far, if any.
+pub fn requested() -> Option<Signal> {
+    REQUESTED.get().copied()
+}
+
+/// Fails once a signal has been received.
+pub fn check() -> Result<(), Interrupted> {
+    match requested() {
+        Some(signal) => Err(Interrupted(signal)),
+        None => Ok(()),
+    }
+}
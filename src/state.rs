@@ -0,0 +1,290 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    product::{OutputAvailability, ProductId},
+    warehouse::{StockScope, Warehouse},
+};
+
+/// The rows emitted by previous runs, kept in the `--state-file` so a run only emits the rows
+/// whose values changed since, and the progress of the current run.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StateFile {
+    /// Last emitted row per stock scope (see [`scope_key`]) and product id
+    #[serde(default)]
+    rows: BTreeMap<String, BTreeMap<i32, StateRow>>,
+
+    /// Input checksum (see [`crate::checksum::input_checksum`]) of the last successful write per
+    /// stock scope
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    inputs: BTreeMap<String, String>,
+
+    /// Progress of a run that has not finished, cleared once it succeeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Checkpoint>,
+}
+
+/// How far a run got: the rows it committed, which `--resume` skips.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub run_id: String,
+
+    /// Products whose rows are written, per stock scope not completed yet
+    #[serde(default)]
+    committed: BTreeMap<String, BTreeSet<i32>>,
+
+    /// Stock scopes written completely
+    #[serde(default)]
+    completed: BTreeSet<String>,
+}
+
+impl Checkpoint {
+    pub fn new(run_id: String) -> Self {
+        Self {
+            run_id,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_completed(&self, scope: &str) -> bool {
+        self.completed.contains(scope)
+    }
+
+    pub fn is_committed(&self, scope: &str, product: ProductId) -> bool {
+        self.committed
+            .get(scope)
+            .is_some_and(|products| products.contains(&product.0))
+    }
+}
+
+/// The values of an emitted row: its availability, and its optional metrics by `jsonl` name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateRow {
+    #[serde(flatten)]
+    pub availability: OutputAvailability,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metrics: BTreeMap<String, String>,
+}
+
+impl StateFile {
+    /// Read the state file at `path`; a missing file is an empty state, as on a first run.
+    pub fn load(path: &Path) -> Result<Self, StateFileError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(source) => {
+                return Err(StateFileError::Read {
+                    path: path.to_path_buf(),
+                    source,
+                });
+            }
+        };
+        serde_json::from_str(&contents).map_err(|source| StateFileError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Write the state to `path` through a temporary file, so an interrupted write leaves the
+    /// previous state in place.
+    pub fn save(&self, path: &Path) -> Result<(), StateFileError> {
+        let write_error = |source| StateFileError::Write {
+            path: path.to_path_buf(),
+            source,
+        };
+        let contents = serde_json::to_vec(self).map_err(std::io::Error::other);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, contents.map_err(write_error)?).map_err(write_error)?;
+        std::fs::rename(&temporary, path).map_err(write_error)
+    }
+
+    /// The row last emitted for `product` in `scope`, if any.
+    pub fn previous(&self, scope: &str, product: ProductId) -> Option<&StateRow> {
+        self.rows.get(scope).and_then(|rows| rows.get(&product.0))
+    }
+
+    /// Whether `row` differs from the one last emitted for `product` in `scope`.
+    pub fn changed(&self, scope: &str, product: ProductId, row: &StateRow) -> bool {
+        self.previous(scope, product)
+            .is_none_or(|previous| previous != row)
+    }
+
+    /// Record `rows` as written, and as committed by the current run.
+    pub fn commit(&mut self, scope: &str, rows: impl IntoIterator<Item = (ProductId, StateRow)>) {
+        let recorded = self.rows.entry(scope.to_owned()).or_default();
+        let mut committed = BTreeSet::new();
+        for (product, row) in rows {
+            let _ = recorded.insert(product.0, row);
+            let _ = committed.insert(product.0);
+        }
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint
+                .committed
+                .entry(scope.to_owned())
+                .or_default()
+                .append(&mut committed);
+        }
+    }
+
+    /// Whether `scope` was last written successfully from inputs with this checksum.
+    pub fn inputs_unchanged(&self, scope: &str, checksum: &str) -> bool {
+        self.inputs
+            .get(scope)
+            .is_some_and(|recorded| recorded == checksum)
+    }
+
+    /// Record the checksum of the inputs `scope` was written from, kept once the run succeeds.
+    pub fn record_inputs(&mut self, scope: &str, checksum: String) {
+        let _ = self.inputs.insert(scope.to_owned(), checksum);
+    }
+
+    /// Record `scope` as written completely by the current run.
+    pub fn complete(&mut self, scope: &str) {
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            let _ = checkpoint.committed.remove(scope);
+            let _ = checkpoint.completed.insert(scope.to_owned());
+        }
+    }
+}
+
+/// The changed rows of one stock scope, recorded in the state file as they are written.
+#[derive(Debug)]
+pub struct ScopeProgress<'a> {
+    state: &'a mut StateFile,
+    path: &'a Path,
+    scope: String,
+    pending: HashMap<ProductId, StateRow>,
+}
+
+impl<'a> ScopeProgress<'a> {
+    pub fn new(
+        state: &'a mut StateFile,
+        path: &'a Path,
+        scope: String,
+        pending: HashMap<ProductId, StateRow>,
+    ) -> Self {
+        Self {
+            state,
+            path,
+            scope,
+            pending,
+        }
+    }
+
+    /// Record the rows of `products` as committed and save the state file.
+    pub fn commit(&mut self, products: &[ProductId]) -> Result<(), StateFileError> {
+        let rows = products
+            .iter()
+            .filter_map(|product| Some((*product, self.pending.remove(product)?)));
+        self.state.commit(&self.scope, rows.collect::<Vec<_>>());
+        self.state.save(self.path)
+    }
+
+    /// Record every remaining row and the scope as written, and save the state file.
+    pub fn complete(self) -> Result<(), StateFileError> {
+        self.state.commit(&self.scope, self.pending);
+        self.state.complete(&self.scope);
+        self.state.save(self.path)
+    }
+}
+
+/// Key of a stock scope in the state file: `warehouse/<id>` or `location/<id>`.
+pub fn scope_key(warehouse: &Warehouse) -> String {
+    stock_scope_key(match warehouse.location_id {
+        Some(location_id) => StockScope::Location(location_id),
+        None => StockScope::Warehouse(warehouse.id.0),
+    })
+}
+
+/// [`scope_key`] of a stock scope before its warehouse is read.
+pub fn stock_scope_key(scope: StockScope) -> String {
+    match scope {
+        StockScope::Location(location_id) => format!("location/{location_id}"),
+        StockScope::Warehouse(warehouse_id) => format!("warehouse/{warehouse_id}"),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StateFileError {
+    #[error("failed to read the state file {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("failed to parse the state file {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("failed to write the state file {}: {source}", .path.display())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rust_decimal::Decimal;
+
+    use super::{Checkpoint, StateFile, StateRow};
+    use crate::product::{OutputAvailability, ProductId};
+
+    fn row(quantity: i64) -> StateRow {
+        StateRow {
+            availability: OutputAvailability {
+                quantity: Decimal::from(quantity),
+                reserved: Decimal::ZERO,
+                incoming: Decimal::ZERO,
+                outgoing: Decimal::ZERO,
+                buildable: Decimal::ZERO,
+                free_immediately: Decimal::from(quantity),
+                virtual_available: Decimal::from(quantity),
+                net_on_order: Decimal::ZERO,
+            },
+            metrics: BTreeMap::from([("abc_class".to_owned(), "A".to_owned())]),
+        }
+    }
+
+    #[test]
+    fn only_rows_differing_from_the_recorded_ones_changed() {
+        let mut state = StateFile::default();
+        assert!(state.changed("warehouse/1", ProductId(7), &row(5)));
+
+        state.commit("warehouse/1", [(ProductId(7), row(5))]);
+        state.record_inputs("warehouse/1", "0123456789abcdef".to_owned());
+        let state: StateFile =
+            serde_json::from_str(&serde_json::to_string(&state).expect("state must serialize"))
+                .expect("state must deserialize");
+
+        assert!(!state.changed("warehouse/1", ProductId(7), &row(5)));
+        assert!(state.changed("warehouse/1", ProductId(7), &row(6)));
+        assert!(state.changed("warehouse/2", ProductId(7), &row(5)));
+        assert!(state.inputs_unchanged("warehouse/1", "0123456789abcdef"));
+        assert!(!state.inputs_unchanged("warehouse/1", "fedcba9876543210"));
+        assert!(!state.inputs_unchanged("warehouse/2", "0123456789abcdef"));
+    }
+
+    #[test]
+    fn checkpoints_track_committed_rows_until_their_scope_completes() {
+        let mut state = StateFile {
+            checkpoint: Some(Checkpoint::new("run".to_owned())),
+            ..StateFile::default()
+        };
+        state.commit("location/3", [(ProductId(7), row(5))]);
+        state.commit("location/4", [(ProductId(8), row(1))]);
+        state.complete("location/4");
+
+        let checkpoint = state.checkpoint.as_ref().expect("checkpoint must remain");
+        assert!(checkpoint.is_committed("location/3", ProductId(7)));
+        assert!(!checkpoint.is_committed("location/3", ProductId(8)));
+        assert!(!checkpoint.is_completed("location/3"));
+        assert!(checkpoint.is_completed("location/4"));
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+/// Fire-and-forget StatsD emitter over UDP: a lost or refused datagram never fails a run.
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl Statsd {
+    /// Resolve `address` (`host:port`) and bind a local socket of the same family to send to it.
+    pub fn connect(address: &str, prefix: &str) -> Result<Self, StatsdError> {
+        let remote = address
+            .to_socket_addrs()
+            .map_err(|source| StatsdError::Resolve {
+                address: address.to_owned(),
+                source,
+            })?
+            .next()
+            .ok_or_else(|| StatsdError::Resolve {
+                address: address.to_owned(),
+                source: std::io::Error::new(std::io::ErrorKind::NotFound, "no address found"),
+            })?;
+        let local = match remote {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local)
+            .and_then(|socket| socket.connect(remote).map(|()| socket))
+            .map_err(StatsdError::Socket)?;
+        Ok(Self {
+            socket,
+            prefix: prefix.trim_end_matches('.').to_owned(),
+        })
+    }
+
+    /// Send a timer in milliseconds.
+    pub fn timing(&self, name: &str, duration: Duration) {
+        self.send(&format!("{name}:{}|ms", duration.as_millis()));
+    }
+
+    /// Send a counter increment.
+    pub fn count(&self, name: &str, value: u64) {
+        self.send(&format!("{name}:{value}|c"));
+    }
+
+    fn send(&self, metric: &str) {
+        let line = metric_line(&self.prefix, metric);
+        if let Err(err) = self.socket.send(line.as_bytes()) {
+            tracing::debug!("Failed to send StatsD metric {line}: {err}");
+        }
+    }
+}
+
+fn metric_line(prefix: &str, metric: &str) -> String {
+    if prefix.is_empty() {
+        metric.to_owned()
+    } else {
+        format!("{prefix}.{metric}")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatsdError {
+    #[error("failed to resolve the StatsD address {address}: {source}")]
+    Resolve {
+        address: String,
+        source: std::io::Error,
+    },
+    #[error("failed to open the StatsD socket: {0}")]
+    Socket(#[source] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use super::Statsd;
+
+    #[test]
+    fn sends_prefixed_timers_and_counters() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("server socket must bind");
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("read timeout must be set");
+        let address = server.local_addr().expect("server must have an address");
+        let statsd =
+            Statsd::connect(&address.to_string(), "odoo_rapid_quant.").expect("must connect");
+
+        statsd.timing("collect.quants", Duration::from_millis(1250));
+        statsd.count("rows_written", 42);
+
+        let mut buffer = [0; 128];
+        for expected in [
+            "odoo_rapid_quant.collect.quants:1250|ms",
+            "odoo_rapid_quant.rows_written:42|c",
+        ] {
+            let read = server.recv(&mut buffer).expect("metric must arrive");
+            assert_eq!(std::str::from_utf8(&buffer[..read]), Ok(expected));
+        }
+    }
+}
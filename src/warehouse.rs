@@ -1,7 +1,13 @@
 use std::ops::Deref;
 
-#[derive(sqlx::Type, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Copy)]
+use serde::{Deserialize, Serialize};
+
+/// An Odoo `stock.warehouse` id, serialized as the bare integer.
+#[derive(
+    sqlx::Type, Serialize, Deserialize, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Copy,
+)]
 #[sqlx(transparent)]
+#[serde(transparent)]
 pub struct WarehouseId(pub i32);
 
 impl Deref for WarehouseId {
@@ -12,9 +18,116 @@ impl Deref for WarehouseId {
     }
 }
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+/// Serialized with its field names; `location_id` is omitted for warehouse scopes.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug, Clone)]
 pub struct Warehouse {
     pub id: WarehouseId,
     pub location_path: String,
     pub name: String,
+    pub company_id: i32,
+
+    /// The location availability is computed for with `--location`; `location_path` is then
+    /// that location's, while the other fields describe the warehouse it belongs to.
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_id: Option<i32>,
+
+    /// `parent_path` pattern of the warehouse's view location, the root Odoo's forecast counts
+    /// from; unset for location scopes.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub view_location_path: Option<String>,
+}
+
+impl Warehouse {
+    /// Name shown in human and html output, telling location scopes of a warehouse apart.
+    pub fn label(&self) -> String {
+        match self.location_id {
+            Some(location_id) => format!("{} (location {location_id})", self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    /// `parent_path` pattern of the locations stock is counted in: the view location when
+    /// mirroring Odoo's forecast, the stock location otherwise.
+    pub fn stock_location_path(&self, odoo_forecast: bool) -> &str {
+        match &self.view_location_path {
+            Some(path) if odoo_forecast => path,
+            _ => &self.location_path,
+        }
+    }
+}
+
+/// Pairs of scopes `(outer, inner)`, by index, where every location of `inner` is also one of
+/// `outer`. Location trees never partially overlap, so this lists every location counted twice;
+/// scopes over the same tree are paired once, the first as `outer`.
+pub fn nested_scopes(scopes: &[Warehouse]) -> Vec<(usize, usize)> {
+    let contains = |outer: &Warehouse, inner: &Warehouse| {
+        inner
+            .location_path
+            .starts_with(outer.location_path.trim_end_matches('%'))
+    };
+
+    let mut nested = Vec::new();
+    for (first, left) in scopes.iter().enumerate() {
+        for (second, right) in scopes.iter().enumerate().skip(first + 1) {
+            if contains(left, right) {
+                nested.push((first, second));
+            } else if contains(right, left) {
+                nested.push((second, first));
+            }
+        }
+    }
+    nested
+}
+
+/// Which locations count as a warehouse's stock, with `--warehouse-scope`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WarehouseScope {
+    /// Under its stock location (`lot_stock_id`), Odoo's on-hand quantity
+    #[default]
+    LotStock,
+    /// Under its view location, including the input, output and quality locations of multi-step
+    /// routes, which sit beside the stock location
+    View,
+    /// Under another location, e.g. a parent of several zones configured outside the stock
+    /// location
+    CustomLocation(i32),
+}
+
+/// Where availability is computed: everything under a warehouse's stock location, or under a
+/// single location (e.g. a zone) of a warehouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StockScope {
+    Warehouse(i32),
+    Location(i32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Warehouse, WarehouseId, nested_scopes};
+
+    fn scope(id: i32, location_path: &str) -> Warehouse {
+        Warehouse {
+            id: WarehouseId(id),
+            location_path: location_path.to_string(),
+            name: format!("WH{id}"),
+            company_id: 1,
+            location_id: None,
+            view_location_path: None,
+        }
+    }
+
+    #[test]
+    fn nested_scopes_pair_each_scope_with_the_one_enclosing_it() {
+        let scopes = [
+            scope(1, "1/7/12/%"),
+            scope(2, "1/7/%"),
+            scope(3, "1/70/%"),
+            scope(4, "1/7/%"),
+        ];
+        // "1/70/" shares a prefix with "1/7" but is a sibling tree
+        assert_eq!(nested_scopes(&scopes), vec![(1, 0), (3, 0), (1, 3)]);
+        assert!(nested_scopes(&scopes[2..3]).is_empty());
+    }
 }
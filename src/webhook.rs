@@ -0,0 +1,148 @@
+use std::{io::Write, path::PathBuf, process::Stdio, time::Duration};
+
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// How long a webhook may take to answer before the request is abandoned.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// POST `body` to `url` with `curl`, failing on a non-2xx answer. The body is passed on stdin so
+/// payloads of any size stay off the command line, and the URL in a config file so the secrets
+/// it often embeds stay out of the process list.
+pub async fn post(url: &str, content_type: &str, body: &[u8]) -> Result<(), WebhookError> {
+    let config = UrlConfig::create(url).map_err(WebhookError::Config)?;
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--request", "POST"])
+        .arg("--max-time")
+        .arg(TIMEOUT.as_secs().to_string())
+        .arg("--header")
+        .arg(format!("Content-Type: {content_type}"))
+        .args(["--data-binary", "@-", "--output", "/dev/null"])
+        .arg("--config")
+        .arg(&config.0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(WebhookError::Spawn)?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body).await.map_err(WebhookError::Spawn)?;
+        // Closing stdin ends the body
+        drop(stdin);
+    }
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(WebhookError::Spawn)?;
+    if !output.status.success() {
+        return Err(WebhookError::Request {
+            url: redact(url),
+            message: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// A curl config file setting the URL, only readable by the current user and removed once
+/// dropped.
+#[derive(Debug)]
+struct UrlConfig(PathBuf);
+
+impl UrlConfig {
+    fn create(url: &str) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "odoo-rapid-quant-{}.curlrc",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let mut options = std::fs::OpenOptions::new();
+        let _ = options.write(true).create_new(true);
+        #[cfg(unix)]
+        let _ = std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path)?;
+        let config = Self(path);
+        writeln!(file, "url = {}", config_string(url))?;
+        Ok(config)
+    }
+}
+
+impl Drop for UrlConfig {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Failed to remove {}: {err}", self.0.display());
+        }
+    }
+}
+
+/// `value` as a quoted curl config string.
+fn config_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for char in value.chars() {
+        match char {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            char => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// POST `payload` to `url` as JSON.
+pub async fn post_json<T: serde::Serialize>(url: &str, payload: &T) -> Result<(), WebhookError> {
+    let body = serde_json::to_vec(payload).map_err(WebhookError::Serialize)?;
+    post(url, "application/json", &body).await
+}
+
+/// `url` without its path and query, which often embed the webhook's secret token.
+fn redact(url: &str) -> String {
+    let authority_start = url.find("://").map_or(0, |index| index + 3);
+    match url[authority_start..].find(['/', '?']) {
+        Some(index) => format!("{}/…", &url[..authority_start + index]),
+        None => url.to_owned(),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("failed to serialize the webhook payload: {0}")]
+    Serialize(#[source] serde_json::Error),
+    #[error("failed to write the curl config of the webhook request: {0}")]
+    Config(#[source] std::io::Error),
+    #[error("failed to run curl, is it installed? {0}")]
+    Spawn(#[source] std::io::Error),
+    #[error("webhook request to {url} failed: {message}")]
+    Request { url: String, message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config_string, redact};
+
+    #[test]
+    fn redacts_the_path_and_query_of_urls() {
+        assert_eq!(
+            redact("https://hooks.slack.com/services/T000/B000/secret"),
+            "https://hooks.slack.com/…"
+        );
+        assert_eq!(
+            redact("http://localhost:9091?token=secret"),
+            "http://localhost:9091/…"
+        );
+        assert_eq!(redact("http://localhost:9091"), "http://localhost:9091");
+    }
+
+    #[test]
+    fn config_strings_are_quoted_and_escaped() {
+        assert_eq!(
+            config_string("https://hooks.example.com/a?b=c"),
+            r#""https://hooks.example.com/a?b=c""#
+        );
+        assert_eq!(config_string("a\"b\\c\nd"), r#""a\"b\\c\nd""#);
+    }
+}